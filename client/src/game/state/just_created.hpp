@@ -124,14 +124,14 @@ public:
     virtual void io_updates(TFQueue<Message>& read_message_queue, TFQueue<Message>& write_message_queue) {
         std::visit(overloaded {[&](JustCreated& just_created) {
             Message connection_requested_message(MessageType::ConnectionRequested);
-            connection_requested_message << client_user_number;
+            connection_requested_message << CLIENT_BUILD << PROTOCOL_VERSION;
             write_message_queue.enqueue(std::move(connection_requested_message));
             state = ConnectionRequested();
         },
                                [&](ConnectionRequested& connection_requested) {
             for(Message message : read_message_queue) {
                 switch(message.type()) {
-                case MessageType::ConnectionAccepted:
+                case MessageType::ConnectionAccepted: {
                     message >> this->server_user_number;
                     uint8_t user_states_len;
                     message >> user_states_len;
@@ -149,6 +149,16 @@ public:
                     state = ConnectionAccepted(std::move(user_to_state), Chat());
                     return;
                 }
+                case MessageType::ConnectionRejected: {
+                    uint8_t reason;
+                    uint16_t min_version;
+                    uint16_t max_version;
+                    message >> reason >> min_version >> max_version;
+                    std::cerr << "Connection rejected, reason " << static_cast<int>(reason) << ", server supports protocol "
+                              << min_version << ".." << max_version << ", we speak " << PROTOCOL_VERSION << std::endl;
+                    return;
+                }
+                }
             }
         },
                                [&](ConnectionAccepted& connection_accepted) {
@@ -185,6 +195,14 @@ public:
                     state = ReadyToStart(std::move(connection_accepted.user_to_state), std::move(connection_accepted.chat));
                     return;
                 }
+                case MessageType::Kicked: {
+                    uint8_t reason;
+                    std::string details;
+                    message >> reason >> details;
+                    std::cerr << "Kicked by the server, reason " << static_cast<int>(reason) << ": " << details
+                              << std::endl;
+                    return;
+                }
                 }
             }
 
@@ -3,21 +3,14 @@
 #include <boost/asio.hpp>
 #include <cstdint>
 #include <iostream>
+#include <string>
 #include <tuple>
 #include <vector>
 
-enum MessageType {
-    ConnectionRequested = 1,
-    ConnectionAccepted  = 2,
-    ConnectionRejected  = 3,
-    UserStatusUpdate    = 4,
-    ReadyToStartChanged = 5,
-    ReadyToStart        = 6,
-    StubMessage         = 7,
-    GameAboutToStart    = 8,
-    GameStarting        = 9,
-    ChatUpdate          = 10,
-};
+#include "protocol.hpp"
+
+constexpr uint16_t PROTOCOL_VERSION = 2;
+const std::string CLIENT_BUILD      = "spell-wars-client 0.0.1";
 
 class Message {
 private:
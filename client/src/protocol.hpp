@@ -0,0 +1,162 @@
+// Generated from server/src/message/schema.rs by `cargo run --bin protogen`.
+// Do not edit by hand.
+#pragma once
+
+#include <cstdint>
+
+enum MessageType {
+    ConnectionRequested = 1,
+    ConnectionAccepted  = 2,
+    ConnectionRejected  = 3,
+    UserStatusUpdate    = 4,
+    ReadyToStartChanged = 5,
+    ReadyToStart        = 6,
+    StubMessage         = 7,
+    GameAboutToStart    = 8,
+    GameStarting        = 9,
+    ChatUpdate          = 10,
+    ProtocolError       = 11,
+    Ping                = 12,
+    Pong                = 13,
+    ServerShutdown      = 14,
+    Reconnect           = 15,
+    Kicked              = 16,
+    CreateRoom          = 17,
+    ListRooms           = 18,
+    JoinRoom            = 19,
+    LeaveRoom           = 20,
+    RoomList            = 21,
+    RoomJoined          = 22,
+    RoomLeft            = 23,
+    RoomRequestFailed   = 24,
+    FindMatch           = 25,
+    CancelMatch         = 26,
+    MatchmakingStatus   = 27,
+    KickPlayer          = 28,
+    LockLobby           = 29,
+    TransferHost        = 30,
+    ForceStart          = 31,
+    ChangeLobbySettings = 32,
+    LobbyUpdate         = 33,
+    LobbyRequestFailed  = 34,
+};
+
+enum class RejectionReason : uint8_t {
+    UnsupportedVersion = 1,
+    UnknownSession     = 2,
+    GameInProgress     = 3,
+    Banned             = 4,
+    LobbyFull          = 5,
+};
+
+enum class UserStatus : uint8_t {
+    NotReady     = 0,
+    Ready        = 1,
+    Disconnected = 2,
+};
+
+enum class ProtocolErrorCode : uint8_t {
+    Io             = 0,
+    UnknownType    = 1,
+    UnexpectedType = 2,
+    Truncated      = 3,
+    BadUtf8        = 4,
+    InvalidValue   = 5,
+    Oversize       = 6,
+};
+
+enum class KickReason : uint8_t {
+    RateLimited      = 1,
+    HandshakeTimeout = 2,
+    ReadTimeout      = 3,
+    LobbyIdle        = 4,
+    ByHost           = 5,
+    IdleTimeout      = 6,
+};
+
+enum class RoomVisibility : uint8_t {
+    Public  = 0,
+    Private = 1,
+};
+
+enum class RoomState : uint8_t {
+    Open       = 0,
+    Starting   = 1,
+    InProgress = 2,
+    Locked     = 3,
+};
+
+enum class RoomRequestError : uint8_t {
+    UnknownRoom    = 1,
+    WrongPassword  = 2,
+    GameInProgress = 3,
+    AlreadyInRoom  = 4,
+    NotInRoom      = 5,
+    InvalidMatch   = 6,
+    NotQueued      = 7,
+    RoomLocked     = 8,
+    RoomFull       = 9,
+    NoRoomLeft     = 10,
+};
+
+enum class MatchmakingState : uint8_t {
+    Searching = 0,
+    Cancelled = 1,
+};
+
+enum class LobbyRequestError : uint8_t {
+    NotHost         = 1,
+    UnknownUser     = 2,
+    InvalidSettings = 3,
+};
+
+// Payload fields in the order they are read. In the stack layout (before protocol 3)
+// they are popped off the end of the payload, so the sender pushes them in reverse.
+// A list is its count (uint8_t before protocol 4, a varint since) followed by the items.
+// A field marked (since N) is left out of the payloads of protocols before N.
+// ConnectionRequested has the fields of the protocol it asks for, whatever was spoken before.
+//
+//   UserReady: ready uint8_t(bool), user int32_t, name string (since 9)
+//   UserStatusChange: status uint8_t(UserStatus), user int32_t, name string (since 9)
+//   ChatLine: user int32_t, text string, name string (since 9)
+//   RoomInfo: code string, players uint16_t, state uint8_t(RoomState), has_password uint8_t(bool)
+//
+// Client to server:
+//   ConnectionRequested: protocol_version uint16_t, client_build string, name string (since 9)
+//   ReadyToStartChanged: ready uint8_t(bool)
+//   StubMessage: strings list<string>
+//   ChatUpdate: messages list<string>
+//   Pong: nonce uint32_t
+//   Reconnect: protocol_version uint16_t, session string
+//   CreateRoom: visibility uint8_t(RoomVisibility), password string
+//   ListRooms: (empty)
+//   JoinRoom: code string, password string
+//   LeaveRoom: (empty)
+//   FindMatch: players uint8_t, rating uint16_t
+//   CancelMatch: (empty)
+//   KickPlayer: user int32_t
+//   LockLobby: locked uint8_t(bool)
+//   TransferHost: user int32_t
+//   ForceStart: (empty)
+//   ChangeLobbySettings: countdown uint8_t
+//
+// Server to client:
+//   ConnectionAccepted: you int32_t, users list<UserReady>, session string (since 6)
+//   ConnectionRejected: reason uint8_t(RejectionReason), min_version uint16_t, max_version uint16_t
+//   UserStatusUpdate: users list<UserStatusChange>
+//   ReadyToStart: (empty)
+//   StubMessage: strings list<string>
+//   GameAboutToStart: seconds_left uint8_t
+//   GameStarting: (empty)
+//   ChatUpdate: messages list<ChatLine>
+//   ProtocolError: rejected_type uint32_t, code uint8_t(ProtocolErrorCode), details string
+//   Ping: nonce uint32_t
+//   ServerShutdown: reason string
+//   Kicked: reason uint8_t(KickReason), details string
+//   RoomList: rooms list<RoomInfo>
+//   RoomJoined: code string
+//   RoomLeft: (empty)
+//   RoomRequestFailed: reason uint8_t(RoomRequestError)
+//   MatchmakingStatus: state uint8_t(MatchmakingState), waited_seconds uint16_t, tolerance uint16_t, queued uint16_t
+//   LobbyUpdate: host int32_t, locked uint8_t(bool), countdown uint8_t
+//   LobbyRequestFailed: reason uint8_t(LobbyRequestError)
@@ -0,0 +1,158 @@
+//! Renders the protocol schema into the client's `protocol.hpp`.
+//!
+//! `cargo run --bin protogen` rewrites the header, `--check` only compares it
+//! and fails if the checked-in copy is stale. `cargo test` does the same check.
+
+use std::{fmt::Write, process::ExitCode};
+
+use server::message::{
+    wire::{FieldSchema, PacketSchema},
+    ClientPacket, MessageType, ServerPacket, ENUMS, STRUCTS,
+};
+
+const PREAMBLE: &str = "\
+// Generated from server/src/message/schema.rs by `cargo run --bin protogen`.
+// Do not edit by hand.
+#pragma once
+
+#include <cstdint>
+
+";
+
+const LAYOUT_NOTE: &str = "\
+// Payload fields in the order they are read. In the stack layout (before protocol 3)
+// they are popped off the end of the payload, so the sender pushes them in reverse.
+// A list is its count (uint8_t before protocol 4, a varint since) followed by the items.
+// A field marked (since N) is left out of the payloads of protocols before N.
+// ConnectionRequested has the fields of the protocol it asks for, whatever was spoken before.
+";
+
+const HEADER_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../client/src/protocol.hpp");
+
+fn main() -> ExitCode {
+    let check = match std::env::args().nth(1).as_deref() {
+        None => false,
+        Some("--check") => true,
+        Some(arg) => {
+            eprintln!("unknown argument {arg}, usage: protogen [--check]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let header = render_header();
+
+    if check {
+        let current = std::fs::read_to_string(HEADER_PATH).unwrap_or_default();
+        if current != header {
+            eprintln!("{HEADER_PATH} is stale, run `cargo run --bin protogen`");
+            return ExitCode::FAILURE;
+        }
+        println!("{HEADER_PATH} is up to date");
+    } else {
+        std::fs::write(HEADER_PATH, header).unwrap();
+        println!("wrote {HEADER_PATH}");
+    }
+    ExitCode::SUCCESS
+}
+
+fn render_header() -> String {
+    let mut out = String::new();
+    out.push_str(PREAMBLE);
+
+    let message_types: Vec<_> = MessageType::ALL
+        .iter()
+        .map(|message_type| (message_type.name(), *message_type as u32))
+        .collect();
+    render_enum(&mut out, "enum MessageType", &message_types);
+
+    for schema in ENUMS {
+        let variants: Vec<_> = schema
+            .variants
+            .iter()
+            .map(|(name, value)| (*name, *value as u32))
+            .collect();
+        render_enum(
+            &mut out,
+            &format!("enum class {} : uint8_t", schema.name),
+            &variants,
+        );
+    }
+
+    out.push_str(LAYOUT_NOTE);
+    writeln!(out, "//").unwrap();
+    for schema in STRUCTS {
+        render_fields(&mut out, schema.name, schema.fields);
+    }
+    writeln!(out, "//").unwrap();
+    writeln!(out, "// Client to server:").unwrap();
+    render_packets(&mut out, ClientPacket::SCHEMA);
+    writeln!(out, "//").unwrap();
+    writeln!(out, "// Server to client:").unwrap();
+    render_packets(&mut out, ServerPacket::SCHEMA);
+    out
+}
+
+fn render_enum(out: &mut String, declaration: &str, variants: &[(&str, u32)]) {
+    let width = variants
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    writeln!(out, "{declaration} {{").unwrap();
+    for (name, value) in variants {
+        writeln!(out, "    {name:width$} = {value},").unwrap();
+    }
+    writeln!(out, "}};").unwrap();
+    writeln!(out).unwrap();
+}
+
+fn render_packets(out: &mut String, packets: &[PacketSchema]) {
+    for packet in packets {
+        render_fields(out, packet.message_type.name(), packet.fields);
+    }
+}
+
+fn render_fields(out: &mut String, name: &str, fields: &[FieldSchema]) {
+    let fields: Vec<_> = fields
+        .iter()
+        .map(|field| match field.since {
+            Some(since) => format!("{} {} (since {since})", field.name, cpp_type(field.ty)),
+            None => format!("{} {}", field.name, cpp_type(field.ty)),
+        })
+        .collect();
+    if fields.is_empty() {
+        writeln!(out, "//   {name}: (empty)").unwrap();
+    } else {
+        writeln!(out, "//   {name}: {}", fields.join(", ")).unwrap();
+    }
+}
+
+fn cpp_type(ty: &str) -> String {
+    if let Some(item) = ty.strip_prefix("Vec<").and_then(|ty| ty.strip_suffix('>')) {
+        return format!("list<{}>", cpp_type(item));
+    }
+    match ty {
+        "u8" => "uint8_t".to_string(),
+        "u16" => "uint16_t".to_string(),
+        "u32" => "uint32_t".to_string(),
+        "i32" | "UserId" => "int32_t".to_string(),
+        "bool" => "uint8_t(bool)".to_string(),
+        "String" => "string".to_string(),
+        _ if ENUMS.iter().any(|schema| schema.name == ty) => format!("uint8_t({ty})"),
+        _ => ty.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_client_header_is_up_to_date() {
+        let current = std::fs::read_to_string(HEADER_PATH).unwrap();
+        assert!(
+            current == render_header(),
+            "{HEADER_PATH} is stale, run `cargo run --bin protogen`"
+        );
+    }
+}
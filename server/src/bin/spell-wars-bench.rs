@@ -0,0 +1,186 @@
+//! Opens many idle connections against a running server and keeps them alive.
+//!
+//! `spell-wars-bench [connections] [host:port]` connects, completes the
+//! handshake on every connection, answers pings and reports every few seconds
+//! how many connections are still accepted by the server.
+//!
+//! The bench connects as a client that predates rooms, so every connection is
+//! put in a lobby, and the server holds no more of them than `max-rooms`
+//! lobbies of `room-size` each, 512 by default, nor more than `max-players`,
+//! 256 by default. Start it with e.g.
+//! `--max-players 100000 --max-rooms 1000 --room-size 100` to hold more
+//! connections than that.
+
+use std::{
+    collections::HashMap,
+    io::{Cursor, ErrorKind, Read, Write},
+    net::SocketAddr,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use mio::{net::TcpStream, Events, Interest, Poll, Token};
+use server::message::{
+    ClientPacket, DecodeError, FrameLimits, Message, ServerPacket, HEARTBEAT_PROTOCOL_VERSION,
+};
+
+const REPORT_EVERY: Duration = Duration::from_secs(5);
+
+const CONNECT_BATCH: usize = 50;
+
+struct Client {
+    stream: TcpStream,
+    inbound: Vec<u8>,
+    accepted: bool,
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let connections: usize = match args.next().map(|arg| arg.parse()) {
+        None => 2000,
+        Some(Ok(connections)) => connections,
+        Some(Err(_)) => {
+            eprintln!("usage: spell-wars-bench [connections] [host:port]");
+            return ExitCode::FAILURE;
+        }
+    };
+    let address: SocketAddr = args
+        .next()
+        .unwrap_or_else(|| "127.0.0.1:10101".to_string())
+        .parse()
+        .unwrap();
+
+    let mut poll = Poll::new().unwrap();
+    let mut clients = HashMap::new();
+    let hello = ClientPacket::ConnectionRequested {
+        protocol_version: HEARTBEAT_PROTOCOL_VERSION,
+        client_build: "spell-wars-bench".to_string(),
+        name: String::new(),
+    }
+    .encode(HEARTBEAT_PROTOCOL_VERSION);
+
+    // Every frame the server sends is read, however large the lobby gets.
+    let limits = FrameLimits::new(u32::MAX as usize);
+    let mut events = Events::with_capacity(1024);
+    let started = Instant::now();
+    let mut opened = 0;
+    let mut pongs = 0u64;
+    let mut last_report = Instant::now();
+    loop {
+        // Connect in small batches so that the first connections keep
+        // answering pings while the rest are being opened.
+        let batch_end = connections.min(opened + CONNECT_BATCH);
+        while opened < batch_end {
+            match connect(address, &hello) {
+                Ok(mut stream) => {
+                    poll.registry()
+                        .register(&mut stream, Token(opened), Interest::READABLE)
+                        .unwrap();
+                    clients.insert(
+                        Token(opened),
+                        Client {
+                            stream,
+                            inbound: Vec::new(),
+                            accepted: false,
+                        },
+                    );
+                    opened += 1;
+                }
+                Err(e) => {
+                    eprintln!("connection {opened} failed: {e}");
+                    opened = connections;
+                }
+            }
+            if opened == connections {
+                println!(
+                    "opened {} connections in {:?}",
+                    clients.len(),
+                    started.elapsed()
+                );
+            }
+        }
+
+        let timeout = if opened < connections {
+            Duration::ZERO
+        } else {
+            REPORT_EVERY
+        };
+        poll.poll(&mut events, Some(timeout)).unwrap();
+        for event in events.iter() {
+            let Some(client) = clients.get_mut(&event.token()) else {
+                continue;
+            };
+            if !client.read() {
+                clients.remove(&event.token());
+                continue;
+            }
+            pongs += client.answer(&limits);
+        }
+
+        if last_report.elapsed() >= REPORT_EVERY {
+            last_report = Instant::now();
+            let accepted = clients.values().filter(|client| client.accepted).count();
+            println!(
+                "{:>6.1}s: {} open, {accepted} accepted, {pongs} pings answered",
+                started.elapsed().as_secs_f64(),
+                clients.len()
+            );
+            if clients.is_empty() {
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+}
+
+/// A blocking connect keeps the listen backlog from overflowing.
+fn connect(address: SocketAddr, hello: &Message) -> std::io::Result<TcpStream> {
+    let stream = std::net::TcpStream::connect(address)?;
+    hello.write_to(&mut &stream)?;
+    stream.set_nonblocking(true)?;
+    Ok(TcpStream::from_std(stream))
+}
+
+impl Client {
+    /// Returns whether the connection is still open.
+    fn read(&mut self) -> bool {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return false,
+                Ok(read) => self.inbound.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Answers every complete ping and returns how many were answered.
+    fn answer(&mut self, limits: &FrameLimits) -> u64 {
+        let mut answered = 0;
+        loop {
+            let mut cursor = Cursor::new(&self.inbound[..]);
+            let message = match Message::next_message(&mut cursor, limits) {
+                Err(DecodeError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+                message => message,
+            };
+            let consumed = cursor.position() as usize;
+            self.inbound.drain(..consumed);
+
+            match message
+                .and_then(|message| ServerPacket::decode(message, HEARTBEAT_PROTOCOL_VERSION))
+            {
+                Ok(ServerPacket::ConnectionAccepted { .. }) => self.accepted = true,
+                Ok(ServerPacket::Ping { nonce }) => {
+                    let pong = ClientPacket::Pong { nonce }.encode(HEARTBEAT_PROTOCOL_VERSION);
+                    let mut frame = Vec::new();
+                    pong.write_to(&mut frame).unwrap();
+                    // A pong is tiny, a short write would mean the server is not reading at all.
+                    let _ = self.stream.write(&frame);
+                    answered += 1;
+                }
+                _ => {}
+            }
+        }
+        answered
+    }
+}
@@ -0,0 +1,214 @@
+//! Floods a running server with one type of message to see its rate limits at
+//! work.
+//!
+//! `spell-wars-flood [message] [per-second] [seconds] [host:port]` joins the
+//! lobby, sends `ChatUpdate` or `ReadyToStartChanged` frames at the given rate
+//! and prints what the server does about it: warnings in the chat, dropping
+//! the connection with `Kicked`, and whether the address is refused afterwards.
+//! It exits with failure if the server let the whole flood through.
+
+use std::{
+    io::{Cursor, ErrorKind, Read},
+    net::{SocketAddr, TcpStream},
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use server::{
+    message::{
+        ClientPacket, DecodeError, FrameLimits, Message, MessageType, ServerPacket,
+        SESSION_PROTOCOL_VERSION,
+    },
+    user_id::UserId,
+};
+
+const USAGE: &str =
+    "usage: spell-wars-flood [ChatUpdate|ReadyToStartChanged] [per-second] [seconds] [host:port]";
+
+struct Connection {
+    stream: TcpStream,
+    inbound: Vec<u8>,
+    limits: FrameLimits,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let message_type = match args.first().map(String::as_str) {
+        None | Some("ChatUpdate") => MessageType::ChatUpdate,
+        Some("ReadyToStartChanged") => MessageType::ReadyToStartChanged,
+        Some(_) => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let (Ok(per_second), Ok(seconds), Ok(address)) = (
+        args.get(1).map_or(Ok(100.0), |arg| arg.parse::<f64>()),
+        args.get(2).map_or(Ok(10.0), |arg| arg.parse::<f64>()),
+        args.get(3)
+            .map_or(Ok(SocketAddr::from(([127, 0, 0, 1], 10101))), |arg| {
+                arg.parse()
+            }),
+    ) else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+    if per_second <= 0.0 || seconds <= 0.0 {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut connection = match Connection::open(address) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("cannot connect to {address}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match connection.accepted() {
+        Ok(you) => println!("joined as {you}, sending {per_second} {message_type:?} per second"),
+        Err(e) => {
+            eprintln!("not accepted: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let interval = Duration::from_secs_f64(1.0 / per_second);
+    let started = Instant::now();
+    let mut next_send = started;
+    let mut sent = 0u64;
+    let mut warnings = 0u64;
+    let mut stopped = false;
+    while !stopped && started.elapsed().as_secs_f64() < seconds {
+        if Instant::now() >= next_send {
+            let packet = match message_type {
+                MessageType::ChatUpdate => ClientPacket::ChatUpdate {
+                    messages: vec![format!("flood {sent}")],
+                },
+                // Never ready, so that the lobby stays open for the next run.
+                _ => ClientPacket::ReadyToStartChanged { ready: false },
+            };
+            if connection.send(&packet).is_err() {
+                println!("the server closed the connection after {sent} frames");
+                break;
+            }
+            sent += 1;
+            next_send += interval;
+        }
+
+        let wait = next_send.saturating_duration_since(Instant::now());
+        let received = match connection.receive(wait) {
+            Ok(received) => received,
+            Err(e) => {
+                println!("the server closed the connection after {sent} frames: {e}");
+                break;
+            }
+        };
+        for packet in received {
+            match packet {
+                ServerPacket::ChatUpdate { messages } => {
+                    for line in messages.iter().filter(|line| line.user == UserId::SERVER) {
+                        println!(
+                            "{:>6.2}s: warned: {}",
+                            started.elapsed().as_secs_f64(),
+                            line.text
+                        );
+                        warnings += 1;
+                    }
+                }
+                ServerPacket::Ping { nonce } => {
+                    let _ = connection.send(&ClientPacket::Pong { nonce });
+                }
+                ServerPacket::Kicked { reason, details } => {
+                    println!(
+                        "{:>6.2}s: kicked after {sent} frames: {reason:?}, {details}",
+                        started.elapsed().as_secs_f64()
+                    );
+                    stopped = true;
+                }
+                _ => {}
+            }
+        }
+    }
+    println!("sent {sent} frames, warned {warnings} times");
+
+    match Connection::open(address).and_then(|mut connection| connection.accepted()) {
+        Ok(you) => println!("connecting again: accepted as {you}"),
+        Err(e) => println!("connecting again: {e}"),
+    }
+
+    if stopped || warnings > 0 {
+        ExitCode::SUCCESS
+    } else {
+        println!("the server let the whole flood through");
+        ExitCode::FAILURE
+    }
+}
+
+impl Connection {
+    fn open(address: SocketAddr) -> std::io::Result<Connection> {
+        let mut connection = Connection {
+            stream: TcpStream::connect(address)?,
+            inbound: Vec::new(),
+            limits: FrameLimits::new(u32::MAX as usize),
+        };
+        connection.send(&ClientPacket::ConnectionRequested {
+            protocol_version: SESSION_PROTOCOL_VERSION,
+            client_build: "spell-wars-flood".to_string(),
+            name: String::new(),
+        })?;
+        Ok(connection)
+    }
+
+    /// Waits for the answer to the handshake and returns the user's id.
+    fn accepted(&mut self) -> std::io::Result<UserId> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            for packet in self.receive(deadline.saturating_duration_since(Instant::now()))? {
+                match packet {
+                    ServerPacket::ConnectionAccepted { you, .. } => return Ok(you),
+                    ServerPacket::ConnectionRejected { reason, .. } => {
+                        return Err(std::io::Error::other(format!("rejected: {reason:?}")))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(ErrorKind::TimedOut.into())
+    }
+
+    fn send(&mut self, packet: &ClientPacket) -> std::io::Result<()> {
+        packet
+            .encode(SESSION_PROTOCOL_VERSION)
+            .write_to(&mut self.stream)
+    }
+
+    /// Waits up to `timeout` for data and returns the complete frames.
+    fn receive(&mut self, timeout: Duration) -> std::io::Result<Vec<ServerPacket>> {
+        let mut chunk = [0u8; 4096];
+        self.stream
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        match self.stream.read(&mut chunk) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(read) => self.inbound.extend_from_slice(&chunk[..read]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut packets = Vec::new();
+        loop {
+            let mut cursor = Cursor::new(&self.inbound[..]);
+            let message = match Message::next_message(&mut cursor, &self.limits) {
+                Err(DecodeError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+                message => message,
+            };
+            let consumed = cursor.position() as usize;
+            self.inbound.drain(..consumed);
+            if let Ok(packet) =
+                message.and_then(|message| ServerPacket::decode(message, SESSION_PROTOCOL_VERSION))
+            {
+                packets.push(packet);
+            }
+        }
+        Ok(packets)
+    }
+}
@@ -0,0 +1,271 @@
+//! Reads captures written by a server started with `SPELL_WARS_CAPTURE=<file>`.
+//!
+//! `spell-wars-replay print <capture>` prints every recorded frame, decoded.
+//! `spell-wars-replay replay <capture> [--user <id>] [--address <host:port>]`
+//! connects to a running server and sends it what the recorded clients sent,
+//! with the original timing, printing whatever the server answers.
+//!
+//! Frames larger than `--max-frame <bytes>`, by default
+//! [`MAX_CAPTURED_FRAME`], are taken for a corrupt capture.
+
+use std::{
+    collections::BTreeMap,
+    net::{Shutdown, TcpStream},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use server::{
+    capture::{CaptureEvent, CaptureReader, CaptureRecord, MAX_CAPTURED_FRAME},
+    message::{
+        ClientPacket, FrameLimits, Message, ServerPacket, HANDSHAKE_PROTOCOL_VERSION,
+        SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    user_id::UserId,
+};
+
+const USAGE: &str = "\
+usage: spell-wars-replay print <capture> [--max-frame <bytes>]
+       spell-wars-replay replay <capture> [--user <id>] [--address <host:port>] [--max-frame <bytes>]";
+
+struct Options {
+    user: Option<UserId>,
+    address: String,
+    max_frame: usize,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("print") if args.len() >= 2 => {
+            parse_options(&args[2..], false).and_then(|options| print(&args[1], &options))
+        }
+        Some("replay") if args.len() >= 2 => {
+            parse_options(&args[2..], true).and_then(|options| replay(&args[1], &options))
+        }
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `--user` and `--address` are only taken when `replaying`.
+fn parse_options(args: &[String], replaying: bool) -> Result<Options, String> {
+    let mut options = Options {
+        user: None,
+        address: "127.0.0.1:10101".to_string(),
+        max_frame: MAX_CAPTURED_FRAME,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(USAGE)?;
+        match arg.as_str() {
+            "--user" if replaying => {
+                options.user = Some(
+                    value
+                        .parse()
+                        .map(UserId::new)
+                        .map_err(|_| format!("bad user {value}"))?,
+                )
+            }
+            "--address" if replaying => options.address = value.clone(),
+            "--max-frame" => {
+                options.max_frame = value
+                    .parse()
+                    .map_err(|_| format!("bad frame size {value}"))?
+            }
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    Ok(options)
+}
+
+fn read_capture(path: &str, max_frame: usize) -> Result<Vec<CaptureRecord>, String> {
+    CaptureReader::open(path)
+        .map_err(|e| format!("cannot open {path}: {e}"))?
+        .with_frame_limit(max_frame)
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("cannot read {path}: {e}"))
+}
+
+fn print(path: &str, options: &Options) -> Result<(), String> {
+    for record in read_capture(path, options.max_frame)? {
+        let timestamp = format!(
+            "[{:>10.6}s] {:>4}",
+            record.elapsed.as_secs_f64(),
+            record.user
+        );
+        match record.event {
+            CaptureEvent::Connected => println!("{timestamp} connected"),
+            CaptureEvent::Disconnected => println!("{timestamp} disconnected"),
+            CaptureEvent::Inbound(message) => println!(
+                "{timestamp} -> {}",
+                describe_client(message, record.protocol_version)
+            ),
+            CaptureEvent::Outbound(message) => println!(
+                "{timestamp} <- {}",
+                describe_server(message, record.protocol_version)
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn describe_client(message: Message, protocol_version: u16) -> String {
+    let (message_type, len) = (message.message_type(), message.len());
+    match ClientPacket::decode(message, protocol_version) {
+        Ok(packet) => format!("{packet:?}"),
+        Err(e) => format!("{message_type:?} ({len} bytes, v{protocol_version}): {e}"),
+    }
+}
+
+fn describe_server(message: Message, protocol_version: u16) -> String {
+    let (message_type, len) = (message.message_type(), message.len());
+    match ServerPacket::decode(message, protocol_version) {
+        Ok(packet) => format!("{packet:?}"),
+        Err(e) => format!("{message_type:?} ({len} bytes, v{protocol_version}): {e}"),
+    }
+}
+
+/// Replays every recorded user on its own connection, so that interleavings
+/// between users are reproduced as closely as the timing allows.
+fn replay(path: &str, options: &Options) -> Result<(), String> {
+    let mut sessions: BTreeMap<UserId, Vec<CaptureRecord>> = BTreeMap::new();
+    for record in read_capture(path, options.max_frame)? {
+        if options.user.is_none_or(|user| user == record.user) {
+            sessions.entry(record.user).or_default().push(record);
+        }
+    }
+    if sessions.is_empty() {
+        return Err(format!("nothing to replay in {path}"));
+    }
+
+    // Start replaying at the first recorded event rather than at capture start.
+    let offset = sessions
+        .values()
+        .map(|records| records[0].elapsed)
+        .min()
+        .unwrap_or_default();
+    let started = Instant::now();
+    let handles: Vec<_> = sessions
+        .into_iter()
+        .map(|(user, records)| {
+            let address = options.address.clone();
+            let max_frame = options.max_frame;
+            thread::spawn(move || {
+                let replayed = replay_session(user, records, &address, max_frame, started, offset);
+                if let Err(e) = replayed {
+                    println!("{user}: {e}");
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.join();
+    }
+    Ok(())
+}
+
+fn replay_session(
+    user: UserId,
+    records: Vec<CaptureRecord>,
+    address: &str,
+    max_frame: usize,
+    started: Instant,
+    offset: Duration,
+) -> Result<(), String> {
+    let mut connection: Option<(TcpStream, JoinHandle<()>)> = None;
+    let protocol_version = Arc::new(AtomicU16::new(HANDSHAKE_PROTOCOL_VERSION));
+
+    for record in records {
+        if let Some(wait) = (record.elapsed - offset).checked_sub(started.elapsed()) {
+            thread::sleep(wait);
+        }
+        match record.event {
+            CaptureEvent::Connected => {
+                let connected = TcpStream::connect(address)
+                    .map_err(|e| format!("cannot connect to {address}: {e}"))?;
+                let printer = spawn_printer(
+                    user,
+                    connected.try_clone().unwrap(),
+                    protocol_version.clone(),
+                    max_frame,
+                    started,
+                );
+                connection = Some((connected, printer));
+            }
+            CaptureEvent::Inbound(message) => {
+                let Some((stream, _)) = connection.as_mut() else {
+                    continue;
+                };
+                if let Ok(ClientPacket::ConnectionRequested {
+                    protocol_version: requested,
+                    ..
+                }) = ClientPacket::decode(message.clone(), record.protocol_version)
+                {
+                    if SUPPORTED_PROTOCOL_VERSIONS.contains(&requested) {
+                        protocol_version.store(requested, Ordering::Release);
+                    }
+                }
+                println!(
+                    "[{:>10.6}s] {user:>4} -> {}",
+                    started.elapsed().as_secs_f64(),
+                    describe_client(message.clone(), record.protocol_version)
+                );
+                message
+                    .write_to(stream)
+                    .map_err(|e| format!("server closed the connection: {e}"))?;
+            }
+            CaptureEvent::Outbound(_) => {}
+            CaptureEvent::Disconnected => {
+                if let Some(connection) = connection.take() {
+                    hang_up(connection);
+                }
+            }
+        }
+    }
+    if let Some(connection) = connection.take() {
+        hang_up(connection);
+    }
+    Ok(())
+}
+
+/// Stops sending and waits for the server to close its side, so that its last
+/// answers are printed.
+fn hang_up((stream, printer): (TcpStream, JoinHandle<()>)) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = printer.join();
+}
+
+/// Prints what the server sends on a replayed connection until it is closed.
+fn spawn_printer(
+    user: UserId,
+    mut stream: TcpStream,
+    protocol_version: Arc<AtomicU16>,
+    max_frame: usize,
+    started: Instant,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let limits = FrameLimits::new(max_frame);
+        for message in Message::iter(&mut stream, &limits) {
+            let Ok(message) = message else {
+                break;
+            };
+            println!(
+                "[{:>10.6}s] {user:>4} <- {}",
+                started.elapsed().as_secs_f64(),
+                describe_server(message, protocol_version.load(Ordering::Acquire))
+            );
+        }
+    })
+}
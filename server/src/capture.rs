@@ -0,0 +1,320 @@
+use std::{
+    fs::File,
+    io::{BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    message::{DecodeError, FrameLimits, Message},
+    user_id::UserId,
+};
+
+const MAGIC: &[u8; 8] = b"SWCAP\0\0\x01";
+
+/// The largest frame [`CaptureReader`] reads unless told otherwise. The server
+/// never writes frames anywhere near this large, so a longer one means the
+/// file is corrupt.
+pub const MAX_CAPTURED_FRAME: usize = 16 * 1024 * 1024;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum EventKind {
+    Connected = 0,
+    Inbound = 1,
+    Outbound = 2,
+    Disconnected = 3,
+}
+
+impl EventKind {
+    fn from_u8(value: u8) -> Option<EventKind> {
+        match value {
+            0 => Some(EventKind::Connected),
+            1 => Some(EventKind::Inbound),
+            2 => Some(EventKind::Outbound),
+            3 => Some(EventKind::Disconnected),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CaptureEvent {
+    Connected,
+    /// A frame read from the user, before it was decoded. Frames dropped by the
+    /// reader itself (unknown or oversize) are not recorded.
+    Inbound(Message),
+    /// A frame written to the user.
+    Outbound(Message),
+    Disconnected,
+}
+
+/// One entry of a capture file.
+///
+/// `protocol_version` is the version the frame was decoded or encoded with. For
+/// the `ConnectionRequested` that negotiates a version it is still the handshake one.
+#[derive(Clone, Debug)]
+pub struct CaptureRecord {
+    pub elapsed: Duration,
+    pub user: UserId,
+    pub protocol_version: u16,
+    pub event: CaptureEvent,
+}
+
+/// Records every frame exchanged with every user into a file.
+///
+/// A record is the time since the capture started in microseconds (u64), the
+/// user (i32), the event kind (u8), the protocol version (u16) and, for
+/// inbound and outbound events, the frame exactly as it is on the wire.
+/// Everything is little endian.
+///
+/// Records are buffered; what is not written out by [`Capture::flush`] is
+/// written when the capture is dropped.
+pub struct Capture {
+    started: Instant,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Capture {
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Capture> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(MAGIC)?;
+        Ok(Capture {
+            started: Instant::now(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn flush(&self) {
+        if let Err(e) = self.file.lock().unwrap().flush() {
+            println!("failed to write the capture: {e}");
+        }
+    }
+
+    pub fn connected(&self, user: UserId) {
+        self.record(user, EventKind::Connected, 0, None);
+    }
+
+    pub fn inbound(&self, user: UserId, protocol_version: u16, message: &Message) {
+        self.record(user, EventKind::Inbound, protocol_version, Some(message));
+    }
+
+    pub fn outbound(&self, user: UserId, protocol_version: u16, message: &Message) {
+        self.record(user, EventKind::Outbound, protocol_version, Some(message));
+    }
+
+    pub fn disconnected(&self, user: UserId) {
+        self.record(user, EventKind::Disconnected, 0, None);
+    }
+
+    /// Failing to record is reported but never takes the connection down.
+    fn record(
+        &self,
+        user: UserId,
+        kind: EventKind,
+        protocol_version: u16,
+        message: Option<&Message>,
+    ) {
+        let elapsed = self.started.elapsed().as_micros() as u64;
+
+        let mut record = Vec::new();
+        record.extend_from_slice(&elapsed.to_le_bytes());
+        record.extend_from_slice(&user.get().to_le_bytes());
+        record.push(kind as u8);
+        record.extend_from_slice(&protocol_version.to_le_bytes());
+        if let Some(message) = message {
+            message.write_to(&mut record).unwrap();
+        }
+
+        if let Err(e) = self.file.lock().unwrap().write_all(&record) {
+            println!("failed to write capture record for {user}: {e}");
+        }
+    }
+}
+
+/// Reads the records of a file written by [`Capture`].
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    limits: FrameLimits,
+}
+
+impl CaptureReader<File> {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        CaptureReader::new(File::open(path)?)
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> std::io::Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "not a spell-wars capture",
+            ));
+        }
+        Ok(CaptureReader {
+            reader,
+            // Frames the server wrote are not bound by what it accepts.
+            limits: FrameLimits::new(MAX_CAPTURED_FRAME),
+        })
+    }
+
+    /// Reads frames of up to `limit` bytes instead of [`MAX_CAPTURED_FRAME`].
+    pub fn with_frame_limit(mut self, limit: usize) -> Self {
+        self.limits = FrameLimits::new(limit);
+        self
+    }
+
+    pub fn next_record(&mut self) -> Result<CaptureRecord, DecodeError> {
+        let mut header = [0u8; 8 + 4 + 1 + 2];
+        self.reader.read_exact(&mut header)?;
+
+        let elapsed = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let user = UserId::new(i32::from_le_bytes(header[8..12].try_into().unwrap()));
+        let kind = header[12];
+        let protocol_version = u16::from_le_bytes(header[13..15].try_into().unwrap());
+
+        let event = match EventKind::from_u8(kind) {
+            Some(EventKind::Connected) => CaptureEvent::Connected,
+            Some(EventKind::Inbound) => {
+                CaptureEvent::Inbound(Message::next_message(&mut self.reader, &self.limits)?)
+            }
+            Some(EventKind::Outbound) => {
+                CaptureEvent::Outbound(Message::next_message(&mut self.reader, &self.limits)?)
+            }
+            Some(EventKind::Disconnected) => CaptureEvent::Disconnected,
+            None => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown capture event kind {kind}"),
+                )
+                .into())
+            }
+        };
+
+        Ok(CaptureRecord {
+            elapsed: Duration::from_micros(elapsed),
+            user,
+            protocol_version,
+            event,
+        })
+    }
+}
+
+/// Ends at the last whole record, so that a capture cut short, e.g. by the
+/// server being killed, can still be read.
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<CaptureRecord, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_record() {
+            Ok(record) => Some(Ok(record)),
+            Err(DecodeError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{ClientPacket, ServerPacket, HANDSHAKE_PROTOCOL_VERSION};
+
+    const VERSION: u16 = HANDSHAKE_PROTOCOL_VERSION;
+
+    /// Records a short session and returns the file's bytes.
+    fn captured(name: &str) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!(
+            "spell-wars-capture-{}-{name}.bin",
+            std::process::id()
+        ));
+        let capture = Capture::create(&path).unwrap();
+        let user = UserId::new(4);
+        capture.connected(user);
+        capture.inbound(
+            user,
+            VERSION,
+            &ClientPacket::Pong { nonce: 1 }.encode(VERSION),
+        );
+        std::thread::sleep(Duration::from_millis(20));
+        capture.outbound(
+            user,
+            VERSION,
+            &ServerPacket::Ping { nonce: 2 }.encode(VERSION),
+        );
+        capture.disconnected(user);
+        drop(capture);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> Vec<CaptureRecord> {
+        CaptureReader::new(bytes)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn frames_are_read_back_as_they_were_recorded() {
+        let records = read(&captured("round-trip"));
+        assert_eq!(records.len(), 4);
+        assert!(records.iter().all(|record| record.user == UserId::new(4)));
+
+        assert!(matches!(records[0].event, CaptureEvent::Connected));
+        let CaptureEvent::Inbound(inbound) = &records[1].event else {
+            panic!("not inbound: {:?}", records[1]);
+        };
+        assert_eq!(records[1].protocol_version, VERSION);
+        assert_eq!(
+            ClientPacket::decode(inbound.clone(), VERSION).unwrap(),
+            ClientPacket::Pong { nonce: 1 }
+        );
+        let CaptureEvent::Outbound(outbound) = &records[2].event else {
+            panic!("not outbound: {:?}", records[2]);
+        };
+        assert_eq!(
+            ServerPacket::decode(outbound.clone(), VERSION).unwrap(),
+            ServerPacket::Ping { nonce: 2 }
+        );
+        assert!(matches!(records[3].event, CaptureEvent::Disconnected));
+
+        let elapsed: Vec<Duration> = records.iter().map(|record| record.elapsed).collect();
+        assert!(elapsed.is_sorted());
+        assert!(elapsed[2] - elapsed[1] >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn a_capture_cut_short_is_read_up_to_its_last_whole_record() {
+        let bytes = captured("truncated");
+        // The disconnect record is a header alone.
+        let without_last = bytes.len() - 15;
+        assert_eq!(read(&bytes[..without_last]).len(), 3);
+        // Cut in the middle of the outbound frame.
+        assert_eq!(read(&bytes[..without_last - 2]).len(), 2);
+        assert!(CaptureReader::new(&bytes[..MAGIC.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn other_files_are_not_taken_for_captures() {
+        let Err(e) = CaptureReader::new(&b"SWCAP\0\0\x02"[..]) else {
+            panic!("another version was read");
+        };
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_refused() {
+        let bytes = captured("limit");
+        let mut reader = CaptureReader::new(&bytes[..]).unwrap().with_frame_limit(0);
+        assert!(reader.next_record().is_ok());
+        assert!(matches!(
+            reader.next_record(),
+            Err(DecodeError::Oversize { limit: 0, .. })
+        ));
+    }
+}
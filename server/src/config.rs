@@ -0,0 +1,579 @@
+//! Everything that can be tuned without recompiling. Settings are taken from,
+//! in increasing priority, the defaults, a TOML file, `SPELL_WARS_*`
+//! environment variables and command line flags.
+//!
+//! ```toml
+//! address = "0.0.0.0:10101"
+//! max_players = 64
+//! room_size = 4
+//! malformed_frame_policy = "disconnect"
+//!
+//! [scheduler]
+//! tick_rate = 120
+//!
+//! [game]
+//! countdown = 3
+//! stub_interval = 0.5
+//!
+//! [matchmaking]
+//! tolerance = 200
+//! widen_every = 10
+//!
+//! [frame_limits.messages]
+//! ChatUpdate = 4096
+//!
+//! [rate_limit.messages.ChatUpdate]
+//! rate = 1
+//! burst = 3
+//! action = "kick"
+//! ```
+
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    game::GameConfig, heartbeat::HeartbeatConfig, matchmaker::MatchmakingConfig,
+    message::FrameLimits, rate_limit::RateLimitConfig, scheduler::SchedulerConfig, user_id::UserId,
+    MalformedFramePolicy,
+};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Where game clients connect.
+    pub address: SocketAddr,
+    /// Where JSON line clients connect, see [`crate::json`].
+    pub json_address: SocketAddr,
+    /// File every frame is recorded to, see [`crate::capture`].
+    pub capture: Option<PathBuf>,
+    /// File the games in progress are written to as JSON on shutdown.
+    pub snapshot: Option<PathBuf>,
+    /// How long a user whose connection dropped may come back as themselves.
+    #[serde(deserialize_with = "seconds")]
+    pub reconnect_grace: Duration,
+    /// Packets held for a user that is not reading before they are dropped,
+    /// merged or the user is disconnected, see [`crate::outbound`].
+    pub outbound_queue: usize,
+    /// How long shutting down waits for users to be told.
+    #[serde(deserialize_with = "seconds")]
+    pub shutdown_grace: Duration,
+    /// How long a connection has to send `ConnectionRequested` or `Reconnect`.
+    #[serde(deserialize_with = "seconds")]
+    pub handshake_timeout: Duration,
+    /// How long a frame may take to arrive once its first bytes have.
+    #[serde(deserialize_with = "seconds")]
+    pub read_timeout: Duration,
+    /// How long a client too old to answer pings may send nothing before its
+    /// connection is taken for dead, see [`crate::heartbeat`].
+    #[serde(deserialize_with = "seconds")]
+    pub idle_timeout: Duration,
+    /// Users on the server at once, across every room and counting those that
+    /// may reconnect. Anybody else is rejected with `LobbyFull`. This is what
+    /// the server can take; how many play together is `room_size`.
+    pub max_players: usize,
+    /// Users in one room at most, whether they joined it, were matched into
+    /// it or were put in the lobby. Matches cannot be asked for larger.
+    pub room_size: usize,
+    /// Rooms open at once, the lobby included. Once there are this many, new
+    /// rooms cannot be created or matched into, and clients that cannot pick a
+    /// room are rejected while the lobby does not take them.
+    pub max_rooms: usize,
+    /// Where user ids start, they are never reused, see [`crate::user_id`].
+    pub first_user_id: i32,
+    pub scheduler: SchedulerConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub game: GameConfig,
+    pub matchmaking: MatchmakingConfig,
+    pub rate_limit: RateLimitConfig,
+    /// The largest payload accepted for each message type, see [`FrameLimits`].
+    pub frame_limits: FrameLimits,
+    /// Whether a client that sent a frame we could not decode is told so or
+    /// disconnected, `"reject"` or `"disconnect"`.
+    pub malformed_frame_policy: MalformedFramePolicy,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: SocketAddr::from(([127, 0, 0, 1], 10101)),
+            json_address: SocketAddr::from(([127, 0, 0, 1], 10102)),
+            capture: None,
+            snapshot: None,
+            reconnect_grace: Duration::from_secs(30),
+            outbound_queue: 256,
+            shutdown_grace: Duration::from_secs(2),
+            handshake_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(600),
+            max_players: 256,
+            room_size: 8,
+            max_rooms: 64,
+            first_user_id: 1,
+            scheduler: SchedulerConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            game: GameConfig::default(),
+            matchmaking: MatchmakingConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            frame_limits: FrameLimits::default(),
+            malformed_frame_policy: MalformedFramePolicy::Reject,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A flag that does not exist or is missing its value.
+    Usage(String),
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A setting whose value cannot be parsed.
+    Value {
+        setting: String,
+        value: String,
+    },
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Usage(e) => write!(f, "{e}\n\n{}", usage()),
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {e}", path.display()),
+            ConfigError::Value { setting, value } => {
+                write!(f, "invalid value '{value}' for {setting}")
+            }
+            ConfigError::Invalid(e) => write!(f, "invalid configuration: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+type Setter = fn(&mut ServerConfig, &str) -> Option<()>;
+
+/// Settings that can be overridden with `--<name> <value>` or
+/// `SPELL_WARS_<NAME>=<value>`. Durations are given in seconds.
+const OVERRIDES: &[(&str, Setter)] = &[
+    ("address", |config, value| {
+        config.address = value.parse().ok()?;
+        Some(())
+    }),
+    ("json-address", |config, value| {
+        config.json_address = value.parse().ok()?;
+        Some(())
+    }),
+    ("capture", |config, value| {
+        config.capture = Some(value.into());
+        Some(())
+    }),
+    ("snapshot", |config, value| {
+        config.snapshot = Some(value.into());
+        Some(())
+    }),
+    ("reconnect-grace", |config, value| {
+        config.reconnect_grace = parse_seconds(value)?;
+        Some(())
+    }),
+    ("outbound-queue", |config, value| {
+        config.outbound_queue = value.parse().ok()?;
+        Some(())
+    }),
+    ("shutdown-grace", |config, value| {
+        config.shutdown_grace = parse_seconds(value)?;
+        Some(())
+    }),
+    ("handshake-timeout", |config, value| {
+        config.handshake_timeout = parse_seconds(value)?;
+        Some(())
+    }),
+    ("read-timeout", |config, value| {
+        config.read_timeout = parse_seconds(value)?;
+        Some(())
+    }),
+    ("idle-timeout", |config, value| {
+        config.idle_timeout = parse_seconds(value)?;
+        Some(())
+    }),
+    ("max-players", |config, value| {
+        config.max_players = value.parse().ok()?;
+        Some(())
+    }),
+    ("room-size", |config, value| {
+        config.room_size = value.parse().ok()?;
+        Some(())
+    }),
+    ("max-rooms", |config, value| {
+        config.max_rooms = value.parse().ok()?;
+        Some(())
+    }),
+    ("first-user-id", |config, value| {
+        config.first_user_id = value.parse().ok()?;
+        Some(())
+    }),
+    ("tick-rate", |config, value| {
+        config.scheduler.tick_rate = value.parse().ok()?;
+        Some(())
+    }),
+    ("send-rate", |config, value| {
+        config.scheduler.send_rate = value.parse().ok()?;
+        Some(())
+    }),
+    ("heartbeat-interval", |config, value| {
+        config.heartbeat.interval = parse_seconds(value)?;
+        Some(())
+    }),
+    ("heartbeat-max-missed", |config, value| {
+        config.heartbeat.max_missed = value.parse().ok()?;
+        Some(())
+    }),
+    ("countdown", |config, value| {
+        config.game.countdown = value.parse().ok()?;
+        Some(())
+    }),
+    ("stub-interval", |config, value| {
+        config.game.stub_interval = parse_seconds(value)?;
+        Some(())
+    }),
+    ("lobby-idle-timeout", |config, value| {
+        config.game.lobby_idle_timeout = parse_seconds(value)?;
+        Some(())
+    }),
+    ("match-tolerance", |config, value| {
+        config.matchmaking.tolerance = value.parse().ok()?;
+        Some(())
+    }),
+    ("match-widen-every", |config, value| {
+        config.matchmaking.widen_every = parse_seconds(value)?;
+        Some(())
+    }),
+    // Caps frames of every type, including those with a limit of their own.
+    ("frame-limit", |config, value| {
+        let limits = std::mem::take(&mut config.frame_limits);
+        config.frame_limits = limits.capped_at(value.parse().ok()?);
+        Some(())
+    }),
+    ("malformed-frame-policy", |config, value| {
+        config.malformed_frame_policy = match value {
+            "reject" => MalformedFramePolicy::Reject,
+            "disconnect" => MalformedFramePolicy::Disconnect,
+            _ => return None,
+        };
+        Some(())
+    }),
+    ("ban", |config, value| {
+        config.rate_limit.ban = parse_seconds(value)?;
+        Some(())
+    }),
+];
+
+pub fn usage() -> String {
+    let mut usage = "usage: server [--config <file>]".to_string();
+    for (name, _) in OVERRIDES {
+        usage += &format!(" [--{name} <value>]");
+    }
+    usage + "\n\nevery flag can also be set as SPELL_WARS_<FLAG>, e.g. SPELL_WARS_TICK_RATE"
+}
+
+impl ServerConfig {
+    /// Builds the configuration of this process from its arguments (without
+    /// the program name) and environment.
+    pub fn load<I: IntoIterator<Item = String>>(args: I) -> Result<ServerConfig, ConfigError> {
+        let mut flags = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(ConfigError::Usage(format!("unexpected argument '{arg}'")));
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| ConfigError::Usage(format!("--{name} needs a value")))?;
+            flags.push((name.to_string(), value));
+        }
+
+        let file = flags
+            .iter()
+            .rev()
+            .find(|(name, _)| name == "config")
+            .map(|(_, value)| PathBuf::from(value))
+            .or_else(|| std::env::var_os(env_name("config")).map(PathBuf::from));
+        let mut config = match file {
+            Some(path) => ServerConfig::read(&path)?,
+            None => ServerConfig::default(),
+        };
+
+        for (name, set) in OVERRIDES {
+            if let Ok(value) = std::env::var(env_name(name)) {
+                apply(&mut config, &env_name(name), *set, &value)?;
+            }
+        }
+        for (name, value) in flags.iter().filter(|(name, _)| name != "config") {
+            let Some((_, set)) = OVERRIDES.iter().find(|(known, _)| known == name) else {
+                return Err(ConfigError::Usage(format!("unknown flag --{name}")));
+            };
+            apply(&mut config, &format!("--{name}"), *set, value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn read(path: &Path) -> Result<ServerConfig, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |e: &str| Err(ConfigError::Invalid(e.to_string()));
+        if self.address == self.json_address {
+            return invalid("address and json_address must differ");
+        }
+        if self.first_user_id < 1 {
+            return invalid("first_user_id must be positive");
+        }
+        if self.scheduler.tick_rate == 0 || self.scheduler.send_rate == 0 {
+            return invalid("tick_rate and send_rate must be at least 1");
+        }
+        if self.scheduler.max_catch_up == 0 {
+            return invalid("max_catch_up must be at least 1");
+        }
+        if self.max_players == 0 {
+            return invalid("max_players must be at least 1");
+        }
+        if self.room_size < 2 {
+            return invalid("room_size must be at least 2");
+        }
+        if self.max_rooms == 0 {
+            return invalid("max_rooms must be at least 1");
+        }
+        if self.frame_limits.default_limit() == 0 {
+            return invalid("the default frame limit must be at least 1 byte");
+        }
+        if self.outbound_queue == 0 {
+            return invalid("outbound_queue must hold at least 1 packet");
+        }
+        if self.heartbeat.interval.is_zero() || self.heartbeat.max_missed == 0 {
+            return invalid("heartbeats need a positive interval and max_missed");
+        }
+        if self.handshake_timeout.is_zero()
+            || self.read_timeout.is_zero()
+            || self.idle_timeout.is_zero()
+            || self.game.lobby_idle_timeout.is_zero()
+        {
+            return invalid("timeouts must be positive");
+        }
+        if self.game.countdown == 0 {
+            return invalid("countdown must be at least 1 second");
+        }
+        if self.matchmaking.widen_every.is_zero() || self.matchmaking.status_interval.is_zero() {
+            return invalid("widen_every and status_interval must be positive");
+        }
+        if self.matchmaking.max_tolerance < self.matchmaking.tolerance {
+            return invalid("max_tolerance must be at least tolerance");
+        }
+        self.rate_limit
+            .validate()
+            .map_err(|e| ConfigError::Invalid(format!("rate_limit: {e}")))?;
+        Ok(())
+    }
+
+    pub fn first_user_id(&self) -> UserId {
+        UserId::new(self.first_user_id)
+    }
+}
+
+fn env_name(name: &str) -> String {
+    format!("SPELL_WARS_{}", name.to_uppercase().replace('-', "_"))
+}
+
+fn apply(
+    config: &mut ServerConfig,
+    setting: &str,
+    set: Setter,
+    value: &str,
+) -> Result<(), ConfigError> {
+    set(config, value).ok_or_else(|| ConfigError::Value {
+        setting: setting.to_string(),
+        value: value.to_string(),
+    })
+}
+
+fn parse_seconds(value: &str) -> Option<Duration> {
+    Duration::try_from_secs_f64(value.parse().ok()?).ok()
+}
+
+/// Durations are written as seconds in the config file, e.g. `interval = 0.5`.
+pub(crate) fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let seconds = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(seconds).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::message::MessageType;
+
+    /// Held by every test that reads the environment, which is shared by the
+    /// whole process.
+    static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+    fn load(args: &[&str]) -> Result<ServerConfig, ConfigError> {
+        ServerConfig::load(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// Writes `text` to a file of its own for the test called `name`.
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "spell-wars-config-{}-{name}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let _environment = ENVIRONMENT.lock().unwrap();
+        let file = config_file(
+            "precedence",
+            "room_size = 4\n[scheduler]\ntick_rate = 100\n[game]\ncountdown = 3\n",
+        );
+        std::env::set_var("SPELL_WARS_TICK_RATE", "90");
+        std::env::set_var("SPELL_WARS_COUNTDOWN", "4");
+        let config = load(&["--config", file.to_str().unwrap(), "--countdown", "5"]);
+        std::env::remove_var("SPELL_WARS_TICK_RATE");
+        std::env::remove_var("SPELL_WARS_COUNTDOWN");
+        std::fs::remove_file(file).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.room_size, 4);
+        assert_eq!(config.scheduler.tick_rate, 90);
+        assert_eq!(config.game.countdown, 5);
+        assert_eq!(config.max_rooms, ServerConfig::default().max_rooms);
+    }
+
+    #[test]
+    fn the_frame_limit_flag_caps_frames_of_every_type() {
+        let _environment = ENVIRONMENT.lock().unwrap();
+        let file = config_file(
+            "frame-limit",
+            "[frame_limits.messages]\nChatUpdate = 8192\nPong = 8\n",
+        );
+        let config = load(&["--config", file.to_str().unwrap(), "--frame-limit", "512"]);
+        std::fs::remove_file(file).unwrap();
+
+        let limits = config.unwrap().frame_limits;
+        assert_eq!(limits.default_limit(), 512);
+        assert_eq!(limits.limit_for(MessageType::ChatUpdate as u32), 512);
+        assert_eq!(limits.limit_for(MessageType::Pong as u32), 8);
+        assert_eq!(limits.limit_for(9999), 512);
+    }
+
+    #[test]
+    fn the_last_of_a_repeated_flag_wins() {
+        let _environment = ENVIRONMENT.lock().unwrap();
+        let config = load(&["--room-size", "3", "--room-size", "6"]).unwrap();
+        assert_eq!(config.room_size, 6);
+    }
+
+    #[test]
+    fn flags_that_do_not_exist_or_lack_a_value_are_refused() {
+        let _environment = ENVIRONMENT.lock().unwrap();
+        for args in [
+            &["--no-such-flag", "1"][..],
+            &["--room-size"],
+            &["room-size", "4"],
+        ] {
+            assert!(matches!(load(args), Err(ConfigError::Usage(_))), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn values_that_do_not_parse_are_refused() {
+        let _environment = ENVIRONMENT.lock().unwrap();
+        for (flag, value) in [
+            ("--room-size", "four"),
+            ("--address", "localhost"),
+            ("--tick-rate", "-1"),
+            ("--idle-timeout", "soon"),
+            ("--malformed-frame-policy", "ignore"),
+        ] {
+            let Err(ConfigError::Value { setting, .. }) = load(&[flag, value]) else {
+                panic!("{flag} {value} was taken");
+            };
+            assert_eq!(setting, flag);
+        }
+
+        std::env::set_var("SPELL_WARS_SEND_RATE", "fast");
+        let loaded = load(&[]);
+        std::env::remove_var("SPELL_WARS_SEND_RATE");
+        let Err(ConfigError::Value { setting, .. }) = loaded else {
+            panic!("SPELL_WARS_SEND_RATE=fast was taken");
+        };
+        assert_eq!(setting, "SPELL_WARS_SEND_RATE");
+    }
+
+    #[test]
+    fn files_that_cannot_be_read_or_parsed_are_refused() {
+        let missing = std::env::temp_dir().join("spell-wars-config-missing.toml");
+        assert!(matches!(
+            ServerConfig::read(&missing),
+            Err(ConfigError::Read(..))
+        ));
+        for (name, text) in [("malformed", "room_size = "), ("unknown", "rooms = 4\n")] {
+            let file = config_file(name, text);
+            let read = ServerConfig::read(&file);
+            std::fs::remove_file(file).unwrap();
+            assert!(matches!(read, Err(ConfigError::Parse(..))), "{name}");
+        }
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        ServerConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn settings_that_cannot_work_are_refused() {
+        let invalid: &[fn(&mut ServerConfig)] = &[
+            |config| config.json_address = config.address,
+            |config| config.first_user_id = 0,
+            |config| config.scheduler.tick_rate = 0,
+            |config| config.scheduler.send_rate = 0,
+            |config| config.scheduler.max_catch_up = 0,
+            |config| config.max_players = 0,
+            |config| config.room_size = 1,
+            |config| config.max_rooms = 0,
+            |config| config.frame_limits = FrameLimits::new(0),
+            |config| config.outbound_queue = 0,
+            |config| config.heartbeat.interval = Duration::ZERO,
+            |config| config.heartbeat.max_missed = 0,
+            |config| config.handshake_timeout = Duration::ZERO,
+            |config| config.read_timeout = Duration::ZERO,
+            |config| config.idle_timeout = Duration::ZERO,
+            |config| config.game.lobby_idle_timeout = Duration::ZERO,
+            |config| config.game.countdown = 0,
+            |config| config.matchmaking.widen_every = Duration::ZERO,
+            |config| config.matchmaking.status_interval = Duration::ZERO,
+            |config| config.matchmaking.max_tolerance = config.matchmaking.tolerance - 1,
+            |config| config.rate_limit.total.burst = 0.0,
+        ];
+        for (i, make_invalid) in invalid.iter().enumerate() {
+            let mut config = ServerConfig::default();
+            make_invalid(&mut config);
+            assert!(
+                matches!(config.validate(), Err(ConfigError::Invalid(_))),
+                "setting {i} was taken"
+            );
+        }
+    }
+}
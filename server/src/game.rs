@@ -1,23 +1,56 @@
 use std::{
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    sync::mpsc,
     time::Duration,
 };
 
-use state::GameState;
+use serde::Deserialize;
+use state::{Admission, GameState, Snapshot};
 
-use crate::Users;
+use crate::{message::ClientPacket, names::Names, user_id::UserId, Users};
 
 pub mod chat;
 pub mod state;
 
+/// How a game plays out once its users are ready.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    /// Seconds counted down between everyone being ready and the game starting.
+    pub countdown: u8,
+    /// How long a user's stub message is held before the next one is sent.
+    #[serde(deserialize_with = "crate::config::seconds")]
+    pub stub_interval: Duration,
+    /// How long a user may sit in the lobby not ready and silent before they
+    /// are kicked, so that the others can start.
+    #[serde(deserialize_with = "crate::config::seconds")]
+    pub lobby_idle_timeout: Duration,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            countdown: 10,
+            stub_interval: Duration::from_secs(2),
+            lobby_idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// One match, from its lobby until its last user is gone. Users are put in
+/// it by [`crate::room::RoomManager`].
 pub struct Game {
-    users: Arc<Mutex<Users>>,
     game_state: Box<dyn GameState>,
+    /// Everybody routed here, connected or away.
+    users: HashSet<UserId>,
 }
 
 impl Game {
-    pub fn new(users: Arc<Mutex<Users>>, game_state: Box<dyn GameState>) -> Game {
-        Game { users, game_state }
+    pub fn new(game_state: Box<dyn GameState>) -> Game {
+        Game {
+            game_state,
+            users: HashSet::new(),
+        }
     }
 
     pub fn elapsed(&mut self, elapsed: Duration) {
@@ -26,12 +59,41 @@ impl Game {
         }
     }
 
-    pub fn io_updates(&mut self) {
-        let locked_users = self.users.lock().unwrap();
-        self.game_state.io_updates(
-            &locked_users.user_to_write_sender,
-            &locked_users.user_to_read_receiver,
-            &locked_users.users,
-        );
+    /// Only this game's users that are connected are shown to its state, which
+    /// reads what they send from `receivers`.
+    pub fn io_updates(
+        &mut self,
+        users: &Users,
+        receivers: &HashMap<UserId, mpsc::Receiver<ClientPacket>>,
+        names: &Names,
+    ) {
+        let connected = self
+            .users
+            .iter()
+            .filter(|user| users.users.contains(user))
+            .copied()
+            .collect();
+        self.game_state
+            .io_updates(&users.user_to_write_sender, receivers, &connected, names);
+    }
+
+    pub fn admission(&self) -> Admission {
+        self.game_state.admission()
+    }
+
+    pub fn users(&self) -> &HashSet<UserId> {
+        &self.users
+    }
+
+    pub fn join(&mut self, user: UserId) {
+        self.users.insert(user);
+    }
+
+    pub fn leave(&mut self, user: &UserId) {
+        self.users.remove(user);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.game_state.snapshot()
     }
 }
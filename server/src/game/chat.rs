@@ -1,8 +1,11 @@
-use crate::message::{Message, MessageType};
+use crate::{
+    message::{ChatLine, ServerPacket},
+    user_id::UserId,
+};
 
 pub struct Chat {
-    messages: Vec<(i32, String)>,
-    new_messages: Vec<(i32, String)>,
+    messages: Vec<ChatLine>,
+    new_messages: Vec<ChatLine>,
 }
 
 impl Chat {
@@ -13,41 +16,45 @@ impl Chat {
         }
     }
 
-    pub fn append(&mut self, user: i32, mut message: Message) {
-        let number_of_messages: u8 = message.pop().unwrap();
-        for _ in 0..number_of_messages {
-            let chat_message = message.pop_string().unwrap();
-            self.new_messages.push((user, chat_message));
+    /// Lines keep the name their author had when they were written.
+    pub fn append(&mut self, user: UserId, name: String, messages: Vec<String>) {
+        for text in messages {
+            self.new_messages.push(ChatLine {
+                user,
+                text,
+                name: name.clone(),
+            });
         }
     }
 
-    pub fn whole_chat_state(&self) -> Message {
-        let mut chat_update_message = Message::new(MessageType::ChatUpdate);
-        for (user, message) in self.messages.iter().rev() {
-            chat_update_message.push_string(message);
-            chat_update_message.push(user);
+    pub fn whole_chat_state(&self) -> ServerPacket {
+        ServerPacket::ChatUpdate {
+            messages: self.messages.clone(),
         }
-        chat_update_message.push(&(self.messages.len() as u8));
-        chat_update_message
     }
 
-    pub fn commit(&mut self) -> Option<Message> {
-        if self.new_messages.len() > 0 {
-            let mut chat_update_message = Message::new(MessageType::ChatUpdate);
-            for (user, message) in self.new_messages.iter().rev() {
-                chat_update_message.push_string(message);
-                chat_update_message.push(user);
-            }
-            chat_update_message.push(&(self.new_messages.len() as u8));
-
-            self.messages.reserve(self.new_messages.len());
-            let messages = std::mem::replace(&mut self.new_messages, Vec::new());
-            for ele in messages {
-                self.messages.push(ele);
-            }
-
-            return Some(chat_update_message);
+    /// Every line, including those not sent to the users yet.
+    pub fn lines(&self) -> Vec<ChatLine> {
+        self.messages
+            .iter()
+            .chain(self.new_messages.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn commit(&mut self) -> Option<ServerPacket> {
+        if !self.new_messages.is_empty() {
+            let messages = std::mem::take(&mut self.new_messages);
+            self.messages.extend(messages.iter().cloned());
+
+            return Some(ServerPacket::ChatUpdate { messages });
         }
         None
     }
 }
+
+impl Default for Chat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
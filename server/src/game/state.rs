@@ -4,20 +4,54 @@ use std::{
     time::Duration,
 };
 
-use crate::message::Message;
+use serde::Serialize;
+
+use crate::{
+    message::{ChatLine, ClientPacket},
+    names::Names,
+    outbound::OutboundSender,
+    user_id::UserId,
+};
 
 pub mod just_created;
 pub mod reaction;
 pub mod ready_to_start;
 pub mod running;
 
+/// What a game is in the middle of, written out when the server shuts down.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub state: &'static str,
+    pub users: Vec<UserId>,
+    pub chat: Vec<ChatLine>,
+}
+
+/// Whether a game takes the users that are not in any game yet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Open,
+    /// New users wait until admission is open or closed.
+    Paused,
+    /// Only users already in are let back in, until the host opens it again.
+    Locked,
+    /// New users go to another game.
+    Closed,
+}
+
 pub trait GameState {
     fn elapsed(&mut self, elapsed: Duration) -> Option<Box<dyn GameState>>;
 
     fn io_updates(
         &mut self,
-        user_to_sender: &HashMap<i32, mpsc::Sender<Message>>,
-        user_to_receiver: &HashMap<i32, mpsc::Receiver<Message>>,
-        users: &HashSet<i32>,
+        user_to_sender: &HashMap<UserId, OutboundSender>,
+        user_to_receiver: &HashMap<UserId, mpsc::Receiver<ClientPacket>>,
+        users: &HashSet<UserId>,
+        names: &Names,
     );
+
+    fn snapshot(&self) -> Snapshot;
+
+    fn admission(&self) -> Admission {
+        Admission::Closed
+    }
 }
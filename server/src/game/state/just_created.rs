@@ -1,36 +1,26 @@
 use std::{
     collections::{HashMap, HashSet},
-    sync::{
-        mpsc::{self, TryIter},
-        Arc, Mutex,
-    },
+    sync::mpsc::{self, TryIter},
     time::Duration,
 };
 
 use crate::{
-    game::chat::Chat,
-    message::{Message, MessageType},
+    game::{chat::Chat, GameConfig},
+    message::{
+        ClientPacket, KickReason, LobbyRequestError, RejectionReason, ServerPacket, UserReady,
+        UserStatus, UserStatusChange, SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    names::Names,
+    outbound::OutboundSender,
+    user_id::UserId,
 };
 
 use super::{reaction::Reaction, ready_to_start::ReadyToStartGame};
 
-use super::GameState;
+use super::{Admission, GameState, Snapshot};
 
-enum UserUpdateStatus {
-    Ready,
-    NotReady,
-    Disconnected,
-}
-
-impl UserUpdateStatus {
-    pub fn value(&self) -> u8 {
-        match self {
-            UserUpdateStatus::Ready => 1,
-            UserUpdateStatus::NotReady => 0,
-            UserUpdateStatus::Disconnected => 2,
-        }
-    }
-}
+/// The longest countdown the host may set.
+const MAX_COUNTDOWN: u8 = 60;
 
 #[derive(Debug)]
 enum FinalCall {
@@ -41,226 +31,453 @@ enum FinalCall {
 
 #[derive(Debug)]
 enum OverallState {
-    AcceptingUsers(HashMap<i32, AcceptingUserState>, FinalCall),
-    AllReady(HashSet<i32>, bool),
+    AcceptingUsers(HashMap<UserId, AcceptingUserState>, FinalCall),
+    AllReady(HashSet<UserId>, bool),
 }
 
 #[derive(Debug)]
 enum AcceptingUserState {
     Connected,
+    Rejected,
     AboutToAccept,
-    ConnectionAccepted(Reaction, bool),
+    /// Whether the user is ready, and how long they have been neither ready
+    /// nor sent anything for.
+    ConnectionAccepted(Reaction, bool, Duration),
+}
+
+/// What the host of the lobby controls.
+#[derive(Debug, Default)]
+struct Lobby {
+    /// Users in the order they were accepted. The first one is the host, so
+    /// when the host leaves whoever came next takes over.
+    accepted: Vec<UserId>,
+    locked: bool,
+    force_start: bool,
+    /// The last `LobbyUpdate` sent.
+    announced: Option<ServerPacket>,
 }
 
 pub struct JustCreatedGame {
     state: OverallState,
-    pause_accepting_users: Arc<Mutex<bool>>,
-    stop_accepting_users: Arc<Mutex<bool>>,
+    /// Rejected and kicked users whose connection is not closed yet, so that
+    /// they are not taken in again in the meantime.
+    removed: HashSet<UserId>,
+    admission: Admission,
+    lobby: Lobby,
     chat: Chat,
+    config: GameConfig,
 }
 
 impl JustCreatedGame {
-    pub fn new(
-        pause_accepting_users: Arc<Mutex<bool>>,
-        stop_accepting_users: Arc<Mutex<bool>>,
-    ) -> Self {
+    pub fn new(config: GameConfig) -> Self {
         JustCreatedGame {
             state: OverallState::AcceptingUsers(HashMap::new(), FinalCall::NotYet),
-            pause_accepting_users,
-            stop_accepting_users,
+            removed: HashSet::new(),
+            admission: Admission::Open,
+            lobby: Lobby::default(),
             chat: Chat::new(),
+            config,
         }
     }
 }
 
 impl GameState for JustCreatedGame {
-    fn elapsed(&mut self, _: Duration) -> Option<Box<dyn GameState>> {
+    fn elapsed(&mut self, elapsed: Duration) -> Option<Box<dyn GameState>> {
         match &mut self.state {
             OverallState::AcceptingUsers(users, final_call) => {
                 for (user, state) in users.iter_mut() {
                     match state {
-                        AcceptingUserState::ConnectionAccepted(reaction, _) => {
+                        AcceptingUserState::ConnectionAccepted(reaction, ready, idle) => {
                             reaction.react_once(|| {
                                 println!("message from {user}: ConnectionRequested");
                             });
+                            if !*ready {
+                                *idle += elapsed;
+                            }
                         }
                         _ => continue,
                     }
                 }
-                if users.len() > 0
+                let all_ready = !users.is_empty()
                     && users.iter().all(|(_, state)| {
-                        matches!(state, AcceptingUserState::ConnectionAccepted(_, true))
-                    })
-                {
+                        matches!(state, AcceptingUserState::ConnectionAccepted(_, true, _))
+                    });
+                // A forced start waits for whoever has not been accepted yet.
+                let pending = users.values().any(|state| {
+                    matches!(
+                        state,
+                        AcceptingUserState::Connected | AcceptingUserState::AboutToAccept
+                    )
+                });
+                if all_ready || (self.lobby.force_start && !pending) {
                     match final_call {
                         FinalCall::NotYet => {
-                            *self.pause_accepting_users.lock().unwrap() = true;
                             *final_call = FinalCall::AllReady;
+                            self.admission = Admission::Paused;
                         }
                         FinalCall::AllReady => {}
                         FinalCall::Processed => {
-                            *self.stop_accepting_users.lock().unwrap() = true;
-                            self.state = OverallState::AllReady(
-                                users.iter().map(|(user, _)| *user).collect(),
-                                false,
-                            );
+                            let accepted = users.keys().copied().collect();
+                            self.state = OverallState::AllReady(accepted, false);
+                            self.admission = Admission::Closed;
                         }
                     }
                 } else {
-                    *self.pause_accepting_users.lock().unwrap() = false;
                     *final_call = FinalCall::NotYet;
+                    self.admission = Admission::Open;
                 }
             }
             OverallState::AllReady(users, ready_sent) => {
                 if *ready_sent {
                     println!("moving to ReadyToStartGame");
                     return Some(Box::new(ReadyToStartGame::new(
-                        std::mem::replace(users, HashSet::new()),
-                        std::mem::replace(&mut self.chat, Chat::new()),
+                        std::mem::take(users),
+                        std::mem::take(&mut self.chat),
+                        self.config.clone(),
                     )));
                 }
             }
         }
-        return None;
+        None
     }
 
     fn io_updates(
         &mut self,
-        user_to_sender: &HashMap<i32, mpsc::Sender<Message>>,
-        user_to_receiver: &HashMap<i32, mpsc::Receiver<Message>>,
-        users: &HashSet<i32>,
+        user_to_sender: &HashMap<UserId, OutboundSender>,
+        user_to_receiver: &HashMap<UserId, mpsc::Receiver<ClientPacket>>,
+        users: &HashSet<UserId>,
+        names: &Names,
     ) {
         match &mut self.state {
             OverallState::AcceptingUsers(current_users, final_call) => {
                 let mut updated_users = Vec::new();
+                let mut host_requests = Vec::new();
 
-                for user in users.iter() {
+                self.removed.retain(|user| users.contains(user));
+                for user in users.iter().filter(|user| !self.removed.contains(user)) {
                     current_users
                         .entry(*user)
                         .or_insert(AcceptingUserState::Connected);
                 }
 
-                let disconnected_users: Vec<i32> = current_users
-                    .iter()
-                    .map(|(user, _)| *user)
-                    .filter(|u| !users.contains(&u))
+                let disconnected_users: Vec<UserId> = current_users
+                    .keys()
+                    .copied()
+                    .filter(|u| !users.contains(u))
                     .collect();
 
                 for user in disconnected_users {
                     current_users.remove(&user);
-                    updated_users.push((user, UserUpdateStatus::Disconnected));
+                    updated_users.push(UserStatusChange {
+                        status: UserStatus::Disconnected,
+                        user,
+                        name: names.of(&user),
+                    });
                 }
 
                 for (user, user_state) in current_users.iter_mut() {
                     match user_state {
                         AcceptingUserState::Connected => {
-                            for message in receiver(&user_to_receiver, user) {
-                                match message.message_type() {
-                                    MessageType::ConnectionRequested => {
+                            for packet in receiver(user_to_receiver, user) {
+                                // The network thread has already checked the
+                                // version and session of a reconnecting user.
+                                if let ClientPacket::Reconnect { .. } = packet {
+                                    println!("{user} is back");
+                                    *user_state = AcceptingUserState::AboutToAccept;
+                                    break;
+                                }
+                                if let ClientPacket::ConnectionRequested {
+                                    protocol_version,
+                                    client_build,
+                                    ..
+                                } = packet
+                                {
+                                    if SUPPORTED_PROTOCOL_VERSIONS.contains(&protocol_version) {
                                         *user_state = AcceptingUserState::AboutToAccept;
-                                        break;
+                                    } else {
+                                        println!(
+                                            "rejecting {user}: protocol {protocol_version} of '{client_build}' is not supported"
+                                        );
+                                        reject_connection(
+                                            user,
+                                            RejectionReason::UnsupportedVersion,
+                                            user_to_sender,
+                                        );
+                                        *user_state = AcceptingUserState::Rejected;
                                     }
-                                    _ => continue,
+                                    break;
                                 }
                             }
                         }
-                        AcceptingUserState::ConnectionAccepted(_, user_ready) => {
+                        AcceptingUserState::ConnectionAccepted(_, user_ready, idle) => {
                             let mut was_changed = false;
-                            for mut message in receiver(&user_to_receiver, user) {
-                                match message.message_type() {
-                                    MessageType::ReadyToStartChanged => {
-                                        let is_ready: u8 = message.pop().unwrap_or(0);
-                                        *user_ready = is_ready != 0;
+                            for packet in receiver(user_to_receiver, user) {
+                                match packet {
+                                    ClientPacket::ReadyToStartChanged { ready } => {
+                                        *user_ready = ready;
                                         was_changed = true;
                                     }
-                                    MessageType::ChatUpdate => {
-                                        self.chat.append(*user, message);
+                                    ClientPacket::ChatUpdate { messages } => {
+                                        self.chat.append(*user, names.of(user), messages);
+                                    }
+                                    ClientPacket::KickPlayer { .. }
+                                    | ClientPacket::LockLobby { .. }
+                                    | ClientPacket::TransferHost { .. }
+                                    | ClientPacket::ForceStart
+                                    | ClientPacket::ChangeLobbySettings { .. } => {
+                                        host_requests.push((*user, packet));
                                     }
                                     _ => continue,
                                 }
+                                *idle = Duration::ZERO;
+                            }
+                            if *idle >= self.config.lobby_idle_timeout {
+                                let details = format!(
+                                    "was not ready for {:?}",
+                                    self.config.lobby_idle_timeout
+                                );
+                                println!("kicking {user}: {details}");
+                                if let Some(sender) = user_to_sender.get(user) {
+                                    sender.send(ServerPacket::Kicked {
+                                        reason: KickReason::LobbyIdle,
+                                        details,
+                                    });
+                                }
+                                *user_state = AcceptingUserState::Rejected;
+                                continue;
                             }
                             if was_changed {
-                                updated_users.push((
-                                    *user,
-                                    if *user_ready {
-                                        UserUpdateStatus::Ready
+                                updated_users.push(UserStatusChange {
+                                    status: if *user_ready {
+                                        UserStatus::Ready
                                     } else {
-                                        UserUpdateStatus::NotReady
+                                        UserStatus::NotReady
                                     },
-                                ));
+                                    user: *user,
+                                    name: names.of(user),
+                                });
                             }
                         }
-                        AcceptingUserState::AboutToAccept => {}
+                        AcceptingUserState::AboutToAccept | AcceptingUserState::Rejected => {}
+                    }
+                }
+
+                for (user, request) in host_requests {
+                    let handled = self.lobby.handle(
+                        user,
+                        request,
+                        current_users,
+                        user_to_sender,
+                        &mut self.config,
+                    );
+                    if let (Err(reason), Some(sender)) = (handled, user_to_sender.get(&user)) {
+                        sender.send(ServerPacket::LobbyRequestFailed { reason });
                     }
                 }
 
+                let rejected_users: Vec<UserId> = current_users
+                    .iter()
+                    .filter(|(_, state)| matches!(state, AcceptingUserState::Rejected))
+                    .map(|(user, _)| *user)
+                    .collect();
+
+                for user in rejected_users {
+                    current_users.remove(&user);
+                    self.removed.insert(user);
+                    updated_users.push(UserStatusChange {
+                        status: UserStatus::Disconnected,
+                        user,
+                        name: names.of(&user),
+                    });
+                }
+
                 let new_users = move_about_to_start_users_to_connetion_accepted(current_users);
+                self.lobby.accepted.extend(new_users.iter().copied());
+                self.lobby.accepted.retain(|user| {
+                    matches!(
+                        current_users.get(user),
+                        Some(AcceptingUserState::ConnectionAccepted(..))
+                    )
+                });
 
                 if !new_users.is_empty() {
                     send_connection_accepted(
                         &new_users,
-                        collect_user_state(current_users),
+                        collect_user_state(current_users, names),
                         user_to_sender,
                     );
 
                     send_chat_state(&new_users, user_to_sender, &self.chat);
                 }
 
-                for user in new_users {
-                    updated_users.push((user, UserUpdateStatus::NotReady));
+                for user in new_users.iter() {
+                    updated_users.push(UserStatusChange {
+                        status: UserStatus::NotReady,
+                        user: *user,
+                        name: names.of(user),
+                    });
                 }
 
                 if !updated_users.is_empty() {
-                    let mut update_message = Message::new(MessageType::UserStatusUpdate);
-                    for (user_to_update, update_status) in updated_users.iter() {
-                        update_message.push(user_to_update);
-                        update_message.push(&update_status.value());
+                    let update_packet = ServerPacket::UserStatusUpdate {
+                        users: updated_users,
+                    };
+                    packet_to_accepted_users(current_users, user_to_sender, update_packet);
+                }
+
+                let update = self.lobby.update(self.config.countdown);
+                if update != self.lobby.announced {
+                    if let Some(update) = &update {
+                        packet_to_accepted_users(current_users, user_to_sender, update.clone());
+                    }
+                    self.lobby.announced = update;
+                } else if let Some(update) = update {
+                    for user in new_users.iter() {
+                        if let Some(sender) = user_to_sender.get(user) {
+                            sender.send(update.clone());
+                        }
                     }
-                    update_message.push(&(updated_users.len() as u8));
-                    message_to_accepted_users(current_users, user_to_sender, update_message);
                 }
 
-                self.chat.commit().map(|message| {
-                    message_to_accepted_users(current_users, user_to_sender, message);
-                });
+                if let Some(packet) = self.chat.commit() {
+                    packet_to_accepted_users(current_users, user_to_sender, packet);
+                }
 
                 *final_call = FinalCall::Processed;
             }
             OverallState::AllReady(users, ready_sent) => {
                 if !*ready_sent {
                     for user in users.iter() {
-                        let ready_to_start = Message::new(MessageType::ReadyToStart);
-                        user_to_sender.get(user).map(|sender| {
-                            sender.send(ready_to_start).unwrap();
-                        });
+                        if let Some(sender) = user_to_sender.get(user) {
+                            sender.send(ServerPacket::ReadyToStart);
+                        }
                     }
                     *ready_sent = true;
                 }
             }
         }
     }
+
+    fn snapshot(&self) -> Snapshot {
+        let users = match &self.state {
+            OverallState::AcceptingUsers(users, _) => users.keys().copied().collect(),
+            OverallState::AllReady(users, _) => users.iter().copied().collect(),
+        };
+        Snapshot {
+            state: "JustCreated",
+            users,
+            chat: self.chat.lines(),
+        }
+    }
+
+    fn admission(&self) -> Admission {
+        if self.lobby.locked && self.admission == Admission::Open {
+            Admission::Locked
+        } else {
+            self.admission
+        }
+    }
+}
+
+impl Lobby {
+    fn host(&self) -> Option<UserId> {
+        self.accepted.first().copied()
+    }
+
+    fn update(&self, countdown: u8) -> Option<ServerPacket> {
+        Some(ServerPacket::LobbyUpdate {
+            host: self.host()?,
+            locked: self.locked,
+            countdown,
+        })
+    }
+
+    /// Carries out a request only the host may make.
+    fn handle(
+        &mut self,
+        user: UserId,
+        request: ClientPacket,
+        current_users: &mut HashMap<UserId, AcceptingUserState>,
+        user_to_sender: &HashMap<UserId, OutboundSender>,
+        config: &mut GameConfig,
+    ) -> Result<(), LobbyRequestError> {
+        if self.host() != Some(user) {
+            return Err(LobbyRequestError::NotHost);
+        }
+        match request {
+            ClientPacket::KickPlayer { user: target } => {
+                let state = current_users
+                    .get_mut(&target)
+                    .filter(|state| matches!(state, AcceptingUserState::ConnectionAccepted(..)))
+                    .filter(|_| target != user)
+                    .ok_or(LobbyRequestError::UnknownUser)?;
+                println!("{user} kicked {target}");
+                if let Some(sender) = user_to_sender.get(&target) {
+                    sender.send(ServerPacket::Kicked {
+                        reason: KickReason::ByHost,
+                        details: format!("kicked by {user}"),
+                    });
+                }
+                *state = AcceptingUserState::Rejected;
+            }
+            ClientPacket::LockLobby { locked } => self.locked = locked,
+            ClientPacket::TransferHost { user: target } => {
+                if !self.accepted.contains(&target) {
+                    return Err(LobbyRequestError::UnknownUser);
+                }
+                self.accepted.retain(|accepted| *accepted != target);
+                self.accepted.insert(0, target);
+            }
+            ClientPacket::ForceStart => {
+                println!("{user} forced the game to start");
+                self.force_start = true;
+            }
+            ClientPacket::ChangeLobbySettings { countdown } => {
+                if !(1..=MAX_COUNTDOWN).contains(&countdown) {
+                    return Err(LobbyRequestError::InvalidSettings);
+                }
+                config.countdown = countdown;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
 }
 
-fn message_to_accepted_users(
-    current_users: &HashMap<i32, AcceptingUserState>,
-    user_to_sender: &HashMap<i32, mpsc::Sender<Message>>,
-    message: Message,
+fn packet_to_accepted_users(
+    current_users: &HashMap<UserId, AcceptingUserState>,
+    user_to_sender: &HashMap<UserId, OutboundSender>,
+    packet: ServerPacket,
 ) {
     for (user, user_state) in current_users.iter() {
         match user_state {
-            AcceptingUserState::ConnectionAccepted(_, _) => {
-                user_to_sender.get(user).map(|sender| {
-                    sender.send(message.clone()).unwrap();
-                });
+            AcceptingUserState::ConnectionAccepted(..) => {
+                if let Some(sender) = user_to_sender.get(user) {
+                    sender.send(packet.clone());
+                }
             }
             _ => continue,
         }
     }
 }
 
+fn reject_connection(
+    user: &UserId,
+    reason: RejectionReason,
+    user_to_sender: &HashMap<UserId, OutboundSender>,
+) {
+    if let Some(sender) = user_to_sender.get(user) {
+        sender.send(ServerPacket::ConnectionRejected {
+            reason,
+            min_version: *SUPPORTED_PROTOCOL_VERSIONS.start(),
+            max_version: *SUPPORTED_PROTOCOL_VERSIONS.end(),
+        });
+    }
+}
+
 pub fn receiver<'a>(
-    users: &'a HashMap<i32, mpsc::Receiver<Message>>,
-    user: &i32,
+    users: &'a HashMap<UserId, mpsc::Receiver<ClientPacket>>,
+    user: &UserId,
 ) -> OptTryIterator<'a> {
     OptTryIterator {
         iter: users.get(user).map(|receiver| receiver.try_iter()),
@@ -268,13 +485,14 @@ pub fn receiver<'a>(
 }
 
 fn move_about_to_start_users_to_connetion_accepted(
-    users: &mut HashMap<i32, AcceptingUserState>,
-) -> Vec<i32> {
+    users: &mut HashMap<UserId, AcceptingUserState>,
+) -> Vec<UserId> {
     users
         .iter_mut()
         .filter_map(|(user, user_state)| {
             if let AcceptingUserState::AboutToAccept = user_state {
-                *user_state = AcceptingUserState::ConnectionAccepted(Reaction::new(), false);
+                *user_state =
+                    AcceptingUserState::ConnectionAccepted(Reaction::new(), false, Duration::ZERO);
                 Some(*user)
             } else {
                 None
@@ -283,12 +501,19 @@ fn move_about_to_start_users_to_connetion_accepted(
         .collect()
 }
 
-fn collect_user_state(users: &HashMap<i32, AcceptingUserState>) -> Vec<(i32, bool)> {
+fn collect_user_state(
+    users: &HashMap<UserId, AcceptingUserState>,
+    names: &Names,
+) -> Vec<UserReady> {
     users
         .iter()
         .filter_map(|(user, state)| {
-            if let AcceptingUserState::ConnectionAccepted(_, is_ready) = state {
-                Some((*user, *is_ready))
+            if let AcceptingUserState::ConnectionAccepted(_, is_ready, _) = state {
+                Some(UserReady {
+                    ready: *is_ready,
+                    user: *user,
+                    name: names.of(user),
+                })
             } else {
                 None
             }
@@ -297,48 +522,289 @@ fn collect_user_state(users: &HashMap<i32, AcceptingUserState>) -> Vec<(i32, boo
 }
 
 fn send_chat_state(
-    users_need_to_send_connection_accepted: &Vec<i32>,
-    user_to_sender: &HashMap<i32, mpsc::Sender<Message>>,
+    users_need_to_send_connection_accepted: &[UserId],
+    user_to_sender: &HashMap<UserId, OutboundSender>,
     chat: &Chat,
 ) {
     let chat_state = chat.whole_chat_state();
     for user in users_need_to_send_connection_accepted.iter() {
-        user_to_sender.get(user).map(|sender| {
-            sender.send(chat_state.clone()).unwrap();
-        });
+        if let Some(sender) = user_to_sender.get(user) {
+            sender.send(chat_state.clone());
+        }
     }
 }
 
 fn send_connection_accepted(
-    users_need_to_send_connection_accepted: &Vec<i32>,
-    users_state: Vec<(i32, bool)>,
-    user_to_sender: &HashMap<i32, mpsc::Sender<Message>>,
+    users_need_to_send_connection_accepted: &[UserId],
+    users_state: Vec<UserReady>,
+    user_to_sender: &HashMap<UserId, OutboundSender>,
 ) {
     for user in users_need_to_send_connection_accepted.iter() {
-        let mut accepted_message = Message::new(MessageType::ConnectionAccepted);
-
-        for (user, is_ready) in users_state.iter() {
-            accepted_message.push(user);
-            accepted_message.push(&(*is_ready as u8));
+        let accepted_packet = ServerPacket::ConnectionAccepted {
+            you: *user,
+            users: users_state.clone(),
+            session: String::new(),
+        };
+
+        if let Some(sender) = user_to_sender.get(user) {
+            sender.send(accepted_packet);
         }
-
-        accepted_message.push(&(users_state.len() as u8));
-        accepted_message.push(user);
-
-        user_to_sender
-            .get(&user)
-            .map(|sender| sender.send(accepted_message).unwrap());
     }
 }
 
 pub struct OptTryIterator<'a> {
-    iter: Option<TryIter<'a, Message>>,
+    iter: Option<TryIter<'a, ClientPacket>>,
 }
 
-impl<'a> Iterator for OptTryIterator<'a> {
-    type Item = Message;
+impl Iterator for OptTryIterator<'_> {
+    type Item = ClientPacket;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.as_mut()?.next()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        message::HANDSHAKE_PROTOCOL_VERSION,
+        outbound::{outbound_queue, OutboundReceiver},
+    };
+
+    use super::*;
+
+    /// The sides of the channels the room manager holds for a user, and what
+    /// the network thread would write to them.
+    struct Client {
+        packets: mpsc::Sender<ClientPacket>,
+        receiver: Option<mpsc::Receiver<ClientPacket>>,
+        sender: OutboundSender,
+        outbound: OutboundReceiver,
+    }
+
+    impl Client {
+        /// A client that has not said what protocol it speaks yet.
+        fn silent() -> Self {
+            let (packets, receiver) = mpsc::channel();
+            let (sender, outbound) = outbound_queue(64);
+            Client {
+                packets,
+                receiver: Some(receiver),
+                sender,
+                outbound,
+            }
+        }
+
+        fn send(&self, packet: ClientPacket) {
+            self.packets.send(packet).unwrap();
+        }
+
+        fn greet(&self) {
+            self.send(ClientPacket::ConnectionRequested {
+                protocol_version: HANDSHAKE_PROTOCOL_VERSION,
+                client_build: String::new(),
+                name: String::new(),
+            });
+        }
+
+        fn received(&self) -> Vec<ServerPacket> {
+            std::iter::from_fn(|| self.outbound.try_recv()).collect()
+        }
+    }
+
+    fn client() -> Client {
+        let client = Client::silent();
+        client.greet();
+        client
+    }
+
+    /// Runs one tick of the room manager for `clients`, all still connected.
+    fn tick(
+        game: &mut JustCreatedGame,
+        clients: &mut HashMap<UserId, Client>,
+    ) -> Option<Box<dyn GameState>> {
+        let user_to_sender = clients
+            .iter()
+            .map(|(user, client)| (*user, client.sender.clone()))
+            .collect();
+        let user_to_receiver = clients
+            .iter_mut()
+            .filter_map(|(user, client)| Some((*user, client.receiver.take()?)))
+            .collect();
+        let users = clients.keys().copied().collect();
+        game.io_updates(&user_to_sender, &user_to_receiver, &users, &Names::new());
+        for (user, receiver) in user_to_receiver {
+            clients.get_mut(&user).unwrap().receiver = Some(receiver);
+        }
+        game.elapsed(Duration::from_millis(10))
+    }
+
+    const HOST: UserId = UserId::new(1);
+    const GUEST: UserId = UserId::new(2);
+
+    /// A lobby the host was accepted in first, with what was sent so far
+    /// taken out of the queues.
+    fn lobby() -> (JustCreatedGame, HashMap<UserId, Client>) {
+        let mut game = JustCreatedGame::new(GameConfig::default());
+        let mut clients = HashMap::from([(HOST, client())]);
+        assert!(tick(&mut game, &mut clients).is_none());
+        clients.insert(GUEST, client());
+        assert!(tick(&mut game, &mut clients).is_none());
+        assert_eq!(game.lobby.accepted, [HOST, GUEST]);
+        for client in clients.values() {
+            client.received();
+        }
+        (game, clients)
+    }
+
+    fn lobby_update(host: UserId, locked: bool, countdown: u8) -> ServerPacket {
+        ServerPacket::LobbyUpdate {
+            host,
+            locked,
+            countdown,
+        }
+    }
+
+    fn request_failed(reason: LobbyRequestError) -> ServerPacket {
+        ServerPacket::LobbyRequestFailed { reason }
+    }
+
+    fn start(
+        game: &mut JustCreatedGame,
+        clients: &mut HashMap<UserId, Client>,
+    ) -> Box<dyn GameState> {
+        (0..3)
+            .find_map(|_| tick(game, clients))
+            .expect("the game did not start")
+    }
+
+    fn sorted(mut users: Vec<UserId>) -> Vec<UserId> {
+        users.sort_unstable();
+        users
+    }
+
+    #[test]
+    fn a_kicked_user_still_connected_does_not_hold_up_the_start() {
+        let (mut game, mut clients) = lobby();
+        clients[&HOST].send(ClientPacket::KickPlayer { user: GUEST });
+        clients[&HOST].send(ClientPacket::ReadyToStartChanged { ready: true });
+        assert!(tick(&mut game, &mut clients).is_none());
+        assert_eq!(game.lobby.accepted, [HOST]);
+        assert_eq!(game.snapshot().users, [HOST]);
+        assert!(matches!(
+            clients[&GUEST].received()[..],
+            [ServerPacket::Kicked {
+                reason: KickReason::ByHost,
+                ..
+            }]
+        ));
+
+        assert_eq!(start(&mut game, &mut clients).snapshot().users, [HOST]);
+    }
+
+    #[test]
+    fn a_forced_start_waits_for_users_not_accepted_yet() {
+        let mut game = JustCreatedGame::new(GameConfig::default());
+        let mut clients = HashMap::from([(HOST, client()), (GUEST, Client::silent())]);
+        clients[&HOST].send(ClientPacket::ForceStart);
+        for _ in 0..3 {
+            assert!(tick(&mut game, &mut clients).is_none());
+        }
+        assert_eq!(game.admission(), Admission::Open);
+
+        clients[&GUEST].greet();
+        let started = start(&mut game, &mut clients);
+        assert_eq!(sorted(started.snapshot().users), [HOST, GUEST]);
+    }
+
+    #[test]
+    fn the_host_can_lock_the_lobby() {
+        let (mut game, mut clients) = lobby();
+        clients[&HOST].send(ClientPacket::LockLobby { locked: true });
+        assert!(tick(&mut game, &mut clients).is_none());
+        assert_eq!(game.admission(), Admission::Locked);
+        for client in clients.values() {
+            assert_eq!(client.received(), [lobby_update(HOST, true, 10)]);
+        }
+
+        clients[&HOST].send(ClientPacket::LockLobby { locked: false });
+        assert!(tick(&mut game, &mut clients).is_none());
+        assert_eq!(game.admission(), Admission::Open);
+    }
+
+    #[test]
+    fn the_host_can_hand_over_to_someone_in_the_lobby() {
+        let (mut game, mut clients) = lobby();
+        clients[&HOST].send(ClientPacket::TransferHost {
+            user: UserId::new(3),
+        });
+        assert!(tick(&mut game, &mut clients).is_none());
+        assert_eq!(
+            clients[&HOST].received(),
+            [request_failed(LobbyRequestError::UnknownUser)]
+        );
+
+        clients[&HOST].send(ClientPacket::TransferHost { user: GUEST });
+        assert!(tick(&mut game, &mut clients).is_none());
+        assert_eq!(game.lobby.accepted, [GUEST, HOST]);
+        for client in clients.values() {
+            assert_eq!(client.received(), [lobby_update(GUEST, false, 10)]);
+        }
+    }
+
+    #[test]
+    fn the_countdown_can_only_be_set_within_bounds() {
+        let (mut game, mut clients) = lobby();
+        for countdown in [0, MAX_COUNTDOWN + 1] {
+            clients[&HOST].send(ClientPacket::ChangeLobbySettings { countdown });
+            assert!(tick(&mut game, &mut clients).is_none());
+            assert_eq!(
+                clients[&HOST].received(),
+                [request_failed(LobbyRequestError::InvalidSettings)]
+            );
+        }
+        assert_eq!(game.config.countdown, 10);
+
+        clients[&HOST].send(ClientPacket::ChangeLobbySettings { countdown: 30 });
+        assert!(tick(&mut game, &mut clients).is_none());
+        assert_eq!(game.config.countdown, 30);
+        for client in clients.values() {
+            assert_eq!(client.received(), [lobby_update(HOST, false, 30)]);
+        }
+    }
+
+    #[test]
+    fn whoever_came_next_takes_over_when_the_host_leaves() {
+        let (mut game, mut clients) = lobby();
+        clients.remove(&HOST);
+        assert!(tick(&mut game, &mut clients).is_none());
+        assert_eq!(game.lobby.accepted, [GUEST]);
+        let received = clients[&GUEST].received();
+        assert!(received.contains(&lobby_update(GUEST, false, 10)));
+    }
+
+    #[test]
+    fn only_the_host_may_run_the_lobby() {
+        let (mut game, mut clients) = lobby();
+        let requests = [
+            ClientPacket::KickPlayer { user: HOST },
+            ClientPacket::LockLobby { locked: true },
+            ClientPacket::TransferHost { user: GUEST },
+            ClientPacket::ForceStart,
+            ClientPacket::ChangeLobbySettings { countdown: 30 },
+        ];
+        for request in requests {
+            clients[&GUEST].send(request);
+        }
+        for _ in 0..3 {
+            assert!(tick(&mut game, &mut clients).is_none());
+        }
+        assert_eq!(
+            clients[&GUEST].received(),
+            vec![request_failed(LobbyRequestError::NotHost); 5]
+        );
+        assert_eq!(game.lobby.accepted, [HOST, GUEST]);
+        assert_eq!(game.admission(), Admission::Open);
+        assert_eq!(game.config.countdown, 10);
+    }
+}
@@ -10,10 +10,16 @@ impl Reaction {
         Reaction(reacted)
     }
 
-    pub fn react_once<F: FnOnce() -> ()>(&mut self, f: F) {
+    pub fn react_once<F: FnOnce()>(&mut self, f: F) {
         if !self.0 {
             f();
             self.0 = true;
         }
     }
 }
+
+impl Default for Reaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -3,29 +3,46 @@ use std::sync::mpsc;
 use std::{collections::HashSet, time::Duration};
 
 use crate::game::chat::Chat;
-use crate::message::{Message, MessageType};
+use crate::game::GameConfig;
+use crate::message::{ClientPacket, ServerPacket};
+use crate::names::Names;
+use crate::outbound::OutboundSender;
+use crate::user_id::UserId;
 
 use super::running::RunningGame;
 
-use super::GameState;
+use super::{GameState, Snapshot};
 
 enum OverallState {
     SecondsLeft(u8, Duration, bool),
     Starting(bool),
 }
 
+impl OverallState {
+    fn packet(&self) -> ServerPacket {
+        match self {
+            OverallState::SecondsLeft(seconds_left, _, _) => ServerPacket::GameAboutToStart {
+                seconds_left: *seconds_left,
+            },
+            OverallState::Starting(_) => ServerPacket::GameStarting,
+        }
+    }
+}
+
 pub struct ReadyToStartGame {
     state: OverallState,
-    users: HashSet<i32>,
+    users: HashSet<UserId>,
     chat: Chat,
+    config: GameConfig,
 }
 
 impl ReadyToStartGame {
-    pub fn new(users: HashSet<i32>, chat: Chat) -> Self {
+    pub fn new(users: HashSet<UserId>, chat: Chat, config: GameConfig) -> Self {
         ReadyToStartGame {
-            state: OverallState::SecondsLeft(10, Duration::ZERO, false),
+            state: OverallState::SecondsLeft(config.countdown, Duration::ZERO, false),
             users,
             chat,
+            config,
         }
     }
 }
@@ -46,8 +63,9 @@ impl GameState for ReadyToStartGame {
                 if *sent {
                     println!("moving to RunningGame");
                     return Some(Box::new(RunningGame::new(
-                        std::mem::replace(&mut self.users, HashSet::new()),
-                        std::mem::replace(&mut self.chat, Chat::new()),
+                        std::mem::take(&mut self.users),
+                        std::mem::take(&mut self.chat),
+                        &self.config,
                     )));
                 }
             }
@@ -57,20 +75,20 @@ impl GameState for ReadyToStartGame {
 
     fn io_updates(
         &mut self,
-        user_to_sender: &HashMap<i32, mpsc::Sender<Message>>,
-        user_to_receiver: &HashMap<i32, mpsc::Receiver<Message>>,
-        _: &HashSet<i32>,
+        user_to_sender: &HashMap<UserId, OutboundSender>,
+        user_to_receiver: &HashMap<UserId, mpsc::Receiver<ClientPacket>>,
+        _: &HashSet<UserId>,
+        _: &Names,
     ) {
         match &mut self.state {
             OverallState::SecondsLeft(seconds_left, _, sent) => {
                 if !*sent {
                     for user in self.users.iter() {
-                        user_to_sender.get(user).map(|sender| {
-                            let mut game_about_to_start =
-                                Message::new(MessageType::GameAboutToStart);
-                            game_about_to_start.push(seconds_left);
-                            sender.send(game_about_to_start).unwrap();
-                        });
+                        if let Some(sender) = user_to_sender.get(user) {
+                            sender.send(ServerPacket::GameAboutToStart {
+                                seconds_left: *seconds_left,
+                            });
+                        }
                     }
                     *sent = true;
                     if *seconds_left == 0 {
@@ -81,21 +99,34 @@ impl GameState for ReadyToStartGame {
             OverallState::Starting(sent) => {
                 if !*sent {
                     for user in self.users.iter() {
-                        user_to_sender.get(user).map(|sender| {
-                            let game_starting = Message::new(MessageType::GameStarting);
-                            sender.send(game_starting).unwrap();
-                        });
+                        if let Some(sender) = user_to_sender.get(user) {
+                            sender.send(ServerPacket::GameStarting);
+                        }
                     }
                     *sent = true;
                 }
             }
         }
         for user in self.users.iter() {
-            user_to_receiver.get(user).map(|receiver| {
-                for _ in receiver.try_iter() {
-                    // don't care about messages here
+            if let Some(receiver) = user_to_receiver.get(user) {
+                for packet in receiver.try_iter() {
+                    // Only a user coming back needs to be told where we are.
+                    if let ClientPacket::Reconnect { .. } = packet {
+                        println!("{user} is back");
+                        if let Some(sender) = user_to_sender.get(user) {
+                            sender.send(self.state.packet());
+                        }
+                    }
                 }
-            });
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: "ReadyToStart",
+            users: self.users.iter().copied().collect(),
+            chat: self.chat.lines(),
         }
     }
 }
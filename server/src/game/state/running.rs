@@ -5,11 +5,14 @@ use std::{
 };
 
 use crate::{
-    game::chat::Chat,
-    message::{Message, MessageType},
+    game::{chat::Chat, GameConfig},
+    message::{ClientPacket, ServerPacket},
+    names::Names,
+    outbound::OutboundSender,
+    user_id::UserId,
 };
 
-use super::{reaction::Reaction, GameState};
+use super::{reaction::Reaction, GameState, Snapshot};
 
 enum UserState {
     WaitingForStub,
@@ -17,12 +20,13 @@ enum UserState {
 }
 
 pub struct RunningGame {
-    user_to_user_state: HashMap<i32, UserState>,
+    user_to_user_state: HashMap<UserId, UserState>,
     chat: Chat,
+    stub_interval: Duration,
 }
 
 impl RunningGame {
-    pub fn new(users: HashSet<i32>, chat: Chat) -> Self {
+    pub fn new(users: HashSet<UserId>, chat: Chat, config: &GameConfig) -> Self {
         RunningGame {
             user_to_user_state: users
                 .iter()
@@ -38,15 +42,17 @@ impl RunningGame {
                 })
                 .collect(),
             chat,
+            stub_interval: config.stub_interval,
         }
     }
 
-    fn create_stub_message() -> Message {
-        let mut stub_message = Message::new(MessageType::StubMessage);
-        stub_message.push_string("Hello from the other siiiiiiiiiide!");
-        stub_message.push_string("At least I can say that I've triiiiiiiiiiied!");
-        stub_message.push(&(2 as u8));
-        stub_message
+    fn create_stub_packet() -> ServerPacket {
+        ServerPacket::StubMessage {
+            strings: vec![
+                "At least I can say that I've triiiiiiiiiiied!".to_string(),
+                "Hello from the other siiiiiiiiiide!".to_string(),
+            ],
+        }
     }
 }
 
@@ -70,61 +76,88 @@ impl GameState for RunningGame {
 
     fn io_updates(
         &mut self,
-        user_to_sender: &HashMap<i32, mpsc::Sender<Message>>,
-        user_to_receiver: &HashMap<i32, mpsc::Receiver<Message>>,
-        _: &HashSet<i32>,
+        user_to_sender: &HashMap<UserId, OutboundSender>,
+        user_to_receiver: &HashMap<UserId, mpsc::Receiver<ClientPacket>>,
+        _: &HashSet<UserId>,
+        names: &Names,
     ) {
         for (user, state) in self.user_to_user_state.iter_mut() {
             match state {
                 UserState::WaitingForStub => {
-                    user_to_receiver.get(user).map(|receiver| {
-                        for mut message in receiver.try_iter() {
-                            match message.message_type() {
-                                MessageType::StubMessage => {
-                                    let number_of_strings: u8 = message.pop().unwrap();
-                                    let strings: Vec<String> = (0..number_of_strings)
-                                        .map(|_| message.pop_string().unwrap())
-                                        .collect();
-
+                    if let Some(receiver) = user_to_receiver.get(user) {
+                        for packet in receiver.try_iter() {
+                            match packet {
+                                ClientPacket::StubMessage { strings } => {
                                     *state = UserState::StubAccepted(
                                         strings,
                                         Duration::ZERO,
                                         Reaction::new(),
                                     );
                                 }
-                                MessageType::ChatUpdate => {
-                                    self.chat.append(*user, message);
+                                ClientPacket::ChatUpdate { messages } => {
+                                    self.chat.append(*user, names.of(user), messages);
+                                }
+                                ClientPacket::Reconnect { .. } => {
+                                    welcome_back(user, user_to_sender, &self.chat);
+                                    // The stub we were waiting for may have been lost.
+                                    *state = UserState::StubAccepted(
+                                        Vec::new(),
+                                        Duration::ZERO,
+                                        Reaction::new_reacted(true),
+                                    );
                                 }
                                 _ => continue,
                             }
                         }
-                    });
+                    }
                 }
                 UserState::StubAccepted(_, duration, _) => {
-                    user_to_receiver.get(user).map(|receiver| {
-                        for message in receiver.try_iter() {
-                            match message.message_type() {
-                                MessageType::ChatUpdate => {
-                                    self.chat.append(*user, message);
+                    if let Some(receiver) = user_to_receiver.get(user) {
+                        for packet in receiver.try_iter() {
+                            match packet {
+                                ClientPacket::ChatUpdate { messages } => {
+                                    self.chat.append(*user, names.of(user), messages);
+                                }
+                                ClientPacket::Reconnect { .. } => {
+                                    welcome_back(user, user_to_sender, &self.chat);
                                 }
                                 _ => continue,
                             }
                         }
-                    });
-                    if *duration > Duration::from_secs(2) {
-                        user_to_sender.get(user).map(|sender| {
-                            sender.send(Self::create_stub_message()).unwrap();
-                        });
+                    }
+                    if *duration > self.stub_interval {
+                        if let Some(sender) = user_to_sender.get(user) {
+                            sender.send(Self::create_stub_packet());
+                        }
                         *state = UserState::WaitingForStub
                     }
                 }
             }
         }
 
-        self.chat.commit().map(|message| {
-            for (_, sender) in user_to_sender.iter() {
-                sender.send(message.clone()).unwrap();
+        if let Some(packet) = self.chat.commit() {
+            for user in self.user_to_user_state.keys() {
+                if let Some(sender) = user_to_sender.get(user) {
+                    sender.send(packet.clone());
+                }
             }
-        });
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: "Running",
+            users: self.user_to_user_state.keys().copied().collect(),
+            chat: self.chat.lines(),
+        }
+    }
+}
+
+/// The user's slot was kept while they were away, they only need to catch up.
+fn welcome_back(user: &UserId, user_to_sender: &HashMap<UserId, OutboundSender>, chat: &Chat) {
+    println!("{user} is back");
+    if let Some(sender) = user_to_sender.get(user) {
+        sender.send(ServerPacket::GameStarting);
+        sender.send(chat.whole_chat_state());
     }
 }
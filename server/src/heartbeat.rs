@@ -0,0 +1,167 @@
+//! Pings that tell a connection that died without being closed from a quiet
+//! user. Only clients since protocol 5 answer them; older ones are dropped by
+//! the network thread once they send nothing for
+//! [`crate::config::ServerConfig::idle_timeout`].
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    #[serde(deserialize_with = "crate::config::seconds")]
+    pub interval: Duration,
+    /// Consecutive unanswered pings after which the user is disconnected.
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: Duration::from_secs(2),
+            max_missed: 3,
+        }
+    }
+}
+
+pub enum HeartbeatAction {
+    Wait,
+    Ping(u32),
+    Expired,
+}
+
+/// Ping/pong bookkeeping for one connection.
+#[derive(Debug)]
+pub struct Heartbeat {
+    next_nonce: u32,
+    outstanding: Option<(u32, Instant)>,
+    last_sent: Option<Instant>,
+    missed: u32,
+    rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {
+            next_nonce: 1,
+            outstanding: None,
+            last_sent: None,
+            missed: 0,
+            rtt: None,
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant, config: &HeartbeatConfig) -> HeartbeatAction {
+        if let Some(last_sent) = self.last_sent {
+            if now.duration_since(last_sent) < config.interval {
+                return HeartbeatAction::Wait;
+            }
+        }
+        if self.outstanding.is_some() {
+            self.missed += 1;
+            if self.missed >= config.max_missed {
+                return HeartbeatAction::Expired;
+            }
+        }
+
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        self.outstanding = Some((nonce, now));
+        self.last_sent = Some(now);
+        HeartbeatAction::Ping(nonce)
+    }
+
+    /// Pongs for anything but the latest ping are ignored, those pings were
+    /// already counted as missed.
+    pub fn pong(&mut self, nonce: u32, now: Instant) {
+        if let Some((outstanding_nonce, sent)) = self.outstanding {
+            if outstanding_nonce == nonce {
+                self.rtt = Some(now.duration_since(sent));
+                self.outstanding = None;
+                self.missed = 0;
+            }
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval: Duration::from_secs(2),
+            max_missed: 3,
+        }
+    }
+
+    #[test]
+    fn a_ping_is_sent_every_interval() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new();
+        assert!(matches!(
+            heartbeat.poll(start, &config()),
+            HeartbeatAction::Ping(1)
+        ));
+        heartbeat.pong(1, start);
+        assert!(matches!(
+            heartbeat.poll(start + Duration::from_secs(1), &config()),
+            HeartbeatAction::Wait
+        ));
+        assert!(matches!(
+            heartbeat.poll(start + Duration::from_secs(2), &config()),
+            HeartbeatAction::Ping(2)
+        ));
+    }
+
+    #[test]
+    fn a_pong_gives_the_round_trip_time_and_clears_missed_pings() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new();
+        heartbeat.poll(start, &config());
+        heartbeat.poll(start + Duration::from_secs(2), &config());
+        assert_eq!(heartbeat.missed(), 1);
+
+        // The answer to the ping already counted as missed changes nothing.
+        heartbeat.pong(1, start + Duration::from_secs(3));
+        assert_eq!(heartbeat.missed(), 1);
+        assert_eq!(heartbeat.rtt(), None);
+
+        heartbeat.pong(2, start + Duration::from_millis(2050));
+        assert_eq!(heartbeat.missed(), 0);
+        assert_eq!(heartbeat.rtt(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn a_peer_that_misses_max_missed_pings_has_expired() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new();
+        for interval in 0..3 {
+            let now = start + config().interval * interval;
+            assert!(matches!(
+                heartbeat.poll(now, &config()),
+                HeartbeatAction::Ping(_)
+            ));
+        }
+        assert!(matches!(
+            heartbeat.poll(start + config().interval * 3, &config()),
+            HeartbeatAction::Expired
+        ));
+        assert_eq!(heartbeat.missed(), 3);
+    }
+}
@@ -0,0 +1,168 @@
+//! One JSON object per line, for driving the server by hand, e.g. with `nc`.
+//!
+//! A packet is an object whose `type` is its message type and whose other keys
+//! are its fields: `{"type":"ChatUpdate","messages":["hello"]}`.
+
+use std::io::{BufRead, ErrorKind, Read, Write};
+
+use crate::message::{ClientPacket, DecodeError, ServerPacket};
+
+pub fn write_packet<W: Write>(writer: &mut W, packet: &ServerPacket) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(packet).map_err(std::io::Error::from)?;
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+/// Reads one packet per line. Blank lines are skipped.
+pub struct JsonPacketIterator<R: BufRead> {
+    reader: R,
+    limit: usize,
+    line: Vec<u8>,
+}
+
+impl<R: BufRead> JsonPacketIterator<R> {
+    /// Lines longer than `limit` bytes are rejected as [`DecodeError::Oversize`].
+    pub fn new(reader: R, limit: usize) -> Self {
+        JsonPacketIterator {
+            reader,
+            limit,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for JsonPacketIterator<R> {
+    type Item = Result<ClientPacket, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+            let read = (&mut self.reader)
+                .take(self.limit as u64 + 1)
+                .read_until(b'\n', &mut self.line);
+            match read {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return None,
+                Err(e) => return Some(Err(e.into())),
+            }
+
+            let length = self.line.strip_suffix(b"\n").unwrap_or(&self.line).len();
+            if length > self.limit {
+                return Some(Err(DecodeError::Oversize {
+                    message_type: 0,
+                    length,
+                    limit: self.limit,
+                }));
+            }
+
+            if self.line.trim_ascii().is_empty() {
+                continue;
+            }
+
+            return Some(serde_json::from_slice(&self.line).map_err(DecodeError::Json));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{ChatLine, RoomVisibility},
+        user_id::UserId,
+    };
+
+    fn read(lines: &str) -> Vec<Result<ClientPacket, DecodeError>> {
+        JsonPacketIterator::new(lines.as_bytes(), 1024).collect()
+    }
+
+    fn error(line: &str) -> String {
+        match &read(line)[..] {
+            [Err(DecodeError::Json(e))] => e.to_string(),
+            decoded => panic!("{line} was read as {decoded:?}"),
+        }
+    }
+
+    #[test]
+    fn client_packets_are_read_one_per_line() {
+        let packets = [
+            ClientPacket::ChatUpdate {
+                messages: vec!["hello".to_string()],
+            },
+            ClientPacket::CreateRoom {
+                visibility: RoomVisibility::Private,
+                password: "secret".to_string(),
+            },
+            ClientPacket::KickPlayer {
+                user: UserId::new(7),
+            },
+            ClientPacket::ListRooms,
+        ];
+        let mut lines = String::new();
+        for packet in packets.iter() {
+            lines += &serde_json::to_string(packet).unwrap();
+            lines += "\n\n";
+        }
+        let read: Vec<ClientPacket> = read(&lines).into_iter().map(Result::unwrap).collect();
+        assert_eq!(read, packets);
+    }
+
+    #[test]
+    fn packets_are_written_as_the_objects_they_are_read_from() {
+        assert!(matches!(
+            &read(r#"{"type":"ChatUpdate","messages":["hello"]}"#)[..],
+            [Ok(ClientPacket::ChatUpdate { messages })] if messages == &["hello"]
+        ));
+
+        let packet = ServerPacket::ChatUpdate {
+            messages: vec![ChatLine {
+                user: UserId::new(3),
+                text: "hi".to_string(),
+                name: "ann".to_string(),
+            }],
+        };
+        let mut written = Vec::new();
+        write_packet(&mut written, &packet).unwrap();
+        assert_eq!(
+            String::from_utf8(written.clone()).unwrap(),
+            "{\"type\":\"ChatUpdate\",\"messages\":[{\"user\":3,\"text\":\"hi\",\"name\":\"ann\"}]}\n"
+        );
+        let read: ServerPacket = serde_json::from_slice(&written).unwrap();
+        assert_eq!(read, packet);
+    }
+
+    #[test]
+    fn fields_newer_than_old_clients_may_be_left_out() {
+        assert!(matches!(
+            &read(r#"{"type":"ConnectionRequested","protocol_version":2,"client_build":"nc"}"#)[..],
+            [Ok(ClientPacket::ConnectionRequested { name, .. })] if name.is_empty()
+        ));
+    }
+
+    #[test]
+    fn unknown_types_and_missing_fields_are_errors() {
+        assert!(error(r#"{"type":"Dance"}"#).contains("unknown variant `Dance`"));
+        assert!(error(r#"{"type":"Pong"}"#).contains("missing field `nonce`"));
+        assert!(error(r#"{"nonce":1}"#).contains("missing field `type`"));
+        // The lines after a bad one are still read.
+        let read = read("{\"type\":\"Pong\"}\n{\"type\":\"Pong\",\"nonce\":1}\n");
+        assert!(matches!(
+            read[..],
+            [Err(_), Ok(ClientPacket::Pong { nonce: 1 })]
+        ));
+    }
+
+    #[test]
+    fn lines_over_the_limit_are_oversize() {
+        let line = format!(
+            "{{\"type\":\"ChatUpdate\",\"messages\":[\"{}\"]}}\n",
+            "a".repeat(64)
+        );
+        let read: Vec<_> = JsonPacketIterator::new(line.as_bytes(), 32).collect();
+        assert!(matches!(
+            read[..],
+            [Err(DecodeError::Oversize { limit: 32, .. }), ..]
+        ));
+    }
+}
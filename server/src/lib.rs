@@ -0,0 +1,191 @@
+use capture::Capture;
+use config::ServerConfig;
+use heartbeat::Heartbeat;
+use message::{ClientPacket, FrameLimits, FrameStats};
+use network::NetworkHandle;
+use outbound::{OutboundSender, OutboundStats};
+use rate_limit::RateLimitConfig;
+use serde::Deserialize;
+use user_id::{UserId, UserIdAllocator};
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc,
+    },
+    time::Duration,
+};
+
+pub mod capture;
+pub mod config;
+pub mod game;
+pub mod heartbeat;
+pub mod json;
+pub mod matchmaker;
+pub mod message;
+pub mod names;
+pub mod network;
+pub mod outbound;
+pub mod rate_limit;
+pub mod room;
+pub mod scheduler;
+pub mod session;
+pub mod user_id;
+
+pub struct Users {
+    user_to_write_sender: HashMap<UserId, OutboundSender>,
+    user_to_read_receiver: HashMap<UserId, mpsc::Receiver<ClientPacket>>,
+    user_to_connection: HashMap<UserId, Connection>,
+    users: HashSet<UserId>,
+    user_ids: UserIdAllocator,
+    /// Users whose connection dropped but who may still come back, see
+    /// [`session`].
+    away: HashSet<UserId>,
+    network: Option<NetworkHandle>,
+}
+
+impl Users {
+    pub fn new() -> Self {
+        Self::with_first_user_id(UserId::new(1))
+    }
+
+    pub fn with_first_user_id(first: UserId) -> Self {
+        Users {
+            user_to_write_sender: HashMap::new(),
+            user_to_read_receiver: HashMap::new(),
+            user_to_connection: HashMap::new(),
+            users: HashSet::new(),
+            user_ids: UserIdAllocator::starting_at(first),
+            away: HashSet::new(),
+            network: None,
+        }
+    }
+
+    pub fn is_away(&self, user: &UserId) -> bool {
+        self.away.contains(user)
+    }
+
+    /// How far behind each user is in reading what is sent to them.
+    pub fn outbound_stats(&self) -> Vec<(UserId, OutboundStats)> {
+        self.user_to_write_sender
+            .iter()
+            .map(|(user, sender)| (*user, sender.stats()))
+            .collect()
+    }
+
+    pub fn rtt(&self, user: &UserId) -> Option<Duration> {
+        self.user_to_connection
+            .get(user)
+            .and_then(|connection| connection.heartbeat.rtt())
+    }
+
+    /// Closes the user's socket. The user is removed once the network thread has closed it.
+    pub fn disconnect(&self, user: &UserId) {
+        if let Some(connection) = self.user_to_connection.get(user) {
+            connection.disconnect();
+        }
+    }
+
+    /// Gets what was sent to the users' channels written out.
+    pub fn flush(&self) {
+        if let Some(network) = &self.network {
+            network.wake();
+        }
+    }
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the game loop needs to know about a user's socket besides its channels.
+pub struct Connection {
+    user: UserId,
+    protocol_version: Arc<AtomicU16>,
+    heartbeat: Heartbeat,
+    network: NetworkHandle,
+}
+
+impl Connection {
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version.load(Ordering::Acquire)
+    }
+
+    pub fn disconnect(&self) {
+        self.network.disconnect(self.user);
+    }
+}
+
+/// How packets are put on the wire for the connections of one listener.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Length-prefixed binary frames, what the game client speaks.
+    Binary,
+    /// One JSON object per line, see [`json`].
+    Json,
+}
+
+/// What to do with a client that sent a frame we could not decode.
+/// Errors that leave the stream misaligned always disconnect.
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MalformedFramePolicy {
+    Reject,
+    Disconnect,
+}
+
+/// The part of [`ServerConfig`] the network thread works with.
+#[derive(Clone, Debug)]
+pub struct ProtocolConfig {
+    pub malformed_frame_policy: MalformedFramePolicy,
+    pub frame_limits: FrameLimits,
+    /// How long a user whose connection dropped may come back as themselves.
+    pub reconnect_grace: Duration,
+    /// Packets held for a user that is not reading, see [`outbound`].
+    pub outbound_queue: usize,
+    /// How much each user may send, see [`rate_limit`].
+    pub rate_limit: RateLimitConfig,
+    /// Users on the server at once, counting those that may reconnect.
+    pub max_players: usize,
+    /// How long a connection has to send `ConnectionRequested` or `Reconnect`.
+    pub handshake_timeout: Duration,
+    /// How long a frame may take to arrive once its first bytes have.
+    pub read_timeout: Duration,
+    /// How long a client that is not sent pings may send nothing.
+    pub idle_timeout: Duration,
+}
+
+impl From<&ServerConfig> for ProtocolConfig {
+    fn from(config: &ServerConfig) -> Self {
+        ProtocolConfig {
+            malformed_frame_policy: config.malformed_frame_policy,
+            frame_limits: config.frame_limits.clone(),
+            reconnect_grace: config.reconnect_grace,
+            outbound_queue: config.outbound_queue,
+            rate_limit: config.rate_limit.clone(),
+            max_players: config.max_players,
+            handshake_timeout: config.handshake_timeout,
+            read_timeout: config.read_timeout,
+            idle_timeout: config.idle_timeout,
+        }
+    }
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        ProtocolConfig::from(&ServerConfig::default())
+    }
+}
+
+/// Protocol settings and counters shared by all connections.
+#[derive(Default)]
+pub struct Protocol {
+    pub config: ProtocolConfig,
+    pub frame_stats: FrameStats,
+    /// Where every frame is recorded, if anywhere.
+    pub capture: Option<Capture>,
+}
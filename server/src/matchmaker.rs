@@ -0,0 +1,345 @@
+//! Puts users that asked for a match of some size together with others of a
+//! similar rating.
+//!
+//! Every user in the queue accepts players whose rating is within their
+//! tolerance of their own. The tolerance starts at
+//! [`MatchmakingConfig::tolerance`] and widens the longer they wait, so that
+//! nobody waits forever for a perfect match. A match is only made of users that
+//! all accept each other, and whoever has waited longest is matched first.
+//!
+//! The clock is whatever `now` the caller passes, so the queue can be run
+//! against a simulated one.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::user_id::UserId;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchmakingConfig {
+    /// How far apart in rating two users may be when they have just queued.
+    pub tolerance: u16,
+    /// Added to a user's tolerance every `widen_every` they wait.
+    pub widen_by: u16,
+    #[serde(deserialize_with = "crate::config::seconds")]
+    pub widen_every: Duration,
+    /// The tolerance stops widening here.
+    pub max_tolerance: u16,
+    /// How often users in the queue are told how it is going.
+    #[serde(deserialize_with = "crate::config::seconds")]
+    pub status_interval: Duration,
+}
+
+impl Default for MatchmakingConfig {
+    fn default() -> Self {
+        MatchmakingConfig {
+            tolerance: 100,
+            widen_by: 50,
+            widen_every: Duration::from_secs(5),
+            max_tolerance: 1000,
+            status_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Ticket {
+    user: UserId,
+    players: u8,
+    rating: u16,
+    since: Instant,
+    last_status: Instant,
+}
+
+/// How the search of a user in the queue is going.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct QueueStatus {
+    pub waited: Duration,
+    pub tolerance: u16,
+    /// Users in the queue for a match of the same size, this one included.
+    pub queued: usize,
+}
+
+#[derive(Debug)]
+pub struct Matchmaker {
+    config: MatchmakingConfig,
+    /// Oldest first.
+    queue: Vec<Ticket>,
+}
+
+impl Matchmaker {
+    pub fn new(config: MatchmakingConfig) -> Self {
+        Matchmaker {
+            config,
+            queue: Vec::new(),
+        }
+    }
+
+    /// Queues the user for a match of `players`, in place of whatever they
+    /// were queued for before.
+    pub fn enqueue(&mut self, user: UserId, players: u8, rating: u16, now: Instant) {
+        self.cancel(&user);
+        self.queue.push(Ticket {
+            user,
+            players,
+            rating,
+            since: now,
+            last_status: now,
+        });
+    }
+
+    /// Returns whether the user was in the queue.
+    pub fn cancel(&mut self, user: &UserId) -> bool {
+        let before = self.queue.len();
+        self.queue.retain(|ticket| ticket.user != *user);
+        self.queue.len() != before
+    }
+
+    pub fn queued(&self) -> impl Iterator<Item = UserId> + '_ {
+        self.queue.iter().map(|ticket| ticket.user)
+    }
+
+    pub fn status(&self, user: &UserId, now: Instant) -> Option<QueueStatus> {
+        let ticket = self.queue.iter().find(|ticket| ticket.user == *user)?;
+        Some(self.status_of(ticket, now))
+    }
+
+    /// Takes every match that can be made now out of the queue. The users of
+    /// each match are in the order they queued.
+    pub fn find_matches(&mut self, now: Instant) -> Vec<Vec<UserId>> {
+        let mut matches = Vec::new();
+        let mut anchor = 0;
+        while anchor < self.queue.len() {
+            match self.match_for(anchor, now) {
+                Some(mut members) => {
+                    members.sort_unstable();
+                    matches.push(members.iter().map(|&i| self.queue[i].user).collect());
+                    for i in members.into_iter().rev() {
+                        self.queue.remove(i);
+                    }
+                }
+                None => anchor += 1,
+            }
+        }
+        matches
+    }
+
+    /// Users that are due to be told how their search is going.
+    pub fn statuses_due(&mut self, now: Instant) -> Vec<(UserId, QueueStatus)> {
+        let due: Vec<usize> = (0..self.queue.len())
+            .filter(|&i| {
+                now.duration_since(self.queue[i].last_status) >= self.config.status_interval
+            })
+            .collect();
+        due.into_iter()
+            .map(|i| {
+                self.queue[i].last_status = now;
+                let ticket = &self.queue[i];
+                (ticket.user, self.status_of(ticket, now))
+            })
+            .collect()
+    }
+
+    /// The users, by their place in the queue, of a match for the one at
+    /// `anchor`. Closest ratings are tried first and ties go to whoever
+    /// queued first.
+    fn match_for(&self, anchor: usize, now: Instant) -> Option<Vec<usize>> {
+        let wanted = self.queue[anchor].players;
+        let rating = self.queue[anchor].rating;
+        let mut candidates: Vec<usize> = (anchor + 1..self.queue.len())
+            .filter(|&i| self.queue[i].players == wanted)
+            .collect();
+        candidates.sort_by_key(|&i| self.queue[i].rating.abs_diff(rating));
+
+        let mut members = vec![anchor];
+        for candidate in candidates {
+            if members.len() == usize::from(wanted) {
+                break;
+            }
+            if members
+                .iter()
+                .all(|&member| self.accept_each_other(member, candidate, now))
+            {
+                members.push(candidate);
+            }
+        }
+        (members.len() == usize::from(wanted)).then_some(members)
+    }
+
+    fn accept_each_other(&self, a: usize, b: usize, now: Instant) -> bool {
+        let (a, b) = (&self.queue[a], &self.queue[b]);
+        let difference = a.rating.abs_diff(b.rating);
+        difference <= self.tolerance(a, now) && difference <= self.tolerance(b, now)
+    }
+
+    fn tolerance(&self, ticket: &Ticket, now: Instant) -> u16 {
+        let steps = now
+            .duration_since(ticket.since)
+            .as_secs_f64()
+            .div_euclid(self.config.widen_every.as_secs_f64());
+        let widened = f64::from(self.config.tolerance) + steps * f64::from(self.config.widen_by);
+        widened.min(f64::from(self.config.max_tolerance)) as u16
+    }
+
+    fn status_of(&self, ticket: &Ticket, now: Instant) -> QueueStatus {
+        QueueStatus {
+            waited: now.duration_since(ticket.since),
+            tolerance: self.tolerance(ticket, now),
+            queued: self
+                .queue
+                .iter()
+                .filter(|other| other.players == ticket.players)
+                .count(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A queue and the clock it is run against.
+    struct Simulation {
+        matchmaker: Matchmaker,
+        start: Instant,
+        now: Instant,
+    }
+
+    impl Simulation {
+        fn new() -> Self {
+            let start = Instant::now();
+            Simulation {
+                matchmaker: Matchmaker::new(MatchmakingConfig::default()),
+                start,
+                now: start,
+            }
+        }
+
+        fn at(&mut self, seconds: u64) -> &mut Self {
+            self.now = self.start + Duration::from_secs(seconds);
+            self
+        }
+
+        fn enqueue(&mut self, user: i32, players: u8, rating: u16) -> &mut Self {
+            self.matchmaker
+                .enqueue(UserId::new(user), players, rating, self.now);
+            self
+        }
+
+        fn cancel(&mut self, user: i32) -> &mut Self {
+            self.matchmaker.cancel(&UserId::new(user));
+            self
+        }
+
+        fn matches(&mut self) -> Vec<Vec<i32>> {
+            self.matchmaker
+                .find_matches(self.now)
+                .into_iter()
+                .map(|users| users.into_iter().map(UserId::get).collect())
+                .collect()
+        }
+    }
+
+    const NONE: &[&[i32]] = &[];
+
+    #[test]
+    fn close_ratings_are_matched_right_away() {
+        let mut sim = Simulation::new();
+        sim.at(0).enqueue(1, 2, 1000).enqueue(2, 2, 1080);
+        assert_eq!(sim.matches(), [[1, 2]]);
+    }
+
+    #[test]
+    fn distant_ratings_wait_until_both_tolerances_have_widened() {
+        let mut sim = Simulation::new();
+        // 300 apart needs 100 + 4 * 50, which takes 20s.
+        sim.at(0).enqueue(1, 2, 1000).enqueue(2, 2, 1300);
+        assert_eq!(sim.matches(), NONE);
+        assert_eq!(sim.at(19).matches(), NONE);
+        assert_eq!(sim.at(20).matches(), [[1, 2]]);
+    }
+
+    #[test]
+    fn a_newcomer_is_only_matched_once_they_accept_the_others_too() {
+        let mut sim = Simulation::new();
+        sim.at(0).enqueue(1, 2, 1000);
+        sim.at(30).enqueue(2, 2, 1300);
+        // 1 accepts 400 by now, but 2 has only just queued.
+        assert_eq!(sim.matches(), NONE);
+        assert_eq!(sim.at(50).matches(), [[1, 2]]);
+    }
+
+    #[test]
+    fn a_match_waits_until_it_is_full() {
+        let mut sim = Simulation::new();
+        sim.at(0)
+            .enqueue(1, 4, 1000)
+            .enqueue(2, 4, 1010)
+            .enqueue(3, 4, 990);
+        assert_eq!(sim.matches(), NONE);
+        sim.at(1).enqueue(4, 4, 1020);
+        assert_eq!(sim.matches(), [[1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn sizes_are_never_mixed() {
+        let mut sim = Simulation::new();
+        sim.at(0)
+            .enqueue(1, 2, 1000)
+            .enqueue(2, 3, 1000)
+            .enqueue(3, 3, 1000);
+        assert_eq!(sim.matches(), NONE);
+        assert_eq!(sim.at(600).matches(), NONE);
+    }
+
+    #[test]
+    fn whoever_waited_longest_is_matched_first() {
+        let mut sim = Simulation::new();
+        sim.at(0).enqueue(1, 2, 1000);
+        sim.at(1).enqueue(2, 2, 1000);
+        sim.at(2).enqueue(3, 2, 1000);
+        assert_eq!(sim.matches(), [[1, 2]]);
+        sim.at(3).enqueue(4, 2, 1000);
+        assert_eq!(sim.matches(), [[3, 4]]);
+    }
+
+    #[test]
+    fn the_closest_rating_is_picked_over_the_earliest() {
+        let mut sim = Simulation::new();
+        sim.at(0)
+            .enqueue(1, 2, 1000)
+            .enqueue(2, 2, 1090)
+            .enqueue(3, 2, 1010);
+        assert_eq!(sim.matches(), [[1, 3]]);
+    }
+
+    #[test]
+    fn several_matches_are_made_at_once() {
+        let mut sim = Simulation::new();
+        sim.at(0)
+            .enqueue(1, 2, 1000)
+            .enqueue(2, 2, 2000)
+            .enqueue(3, 2, 2050)
+            .enqueue(4, 2, 1020);
+        assert_eq!(sim.matches(), [[1, 4], [2, 3]]);
+    }
+
+    #[test]
+    fn the_tolerance_stops_widening() {
+        let mut sim = Simulation::new();
+        sim.at(0).enqueue(1, 2, 0).enqueue(2, 2, 1001);
+        assert_eq!(sim.at(3600).matches(), NONE);
+    }
+
+    #[test]
+    fn cancelled_and_requeued_users_start_over() {
+        let mut sim = Simulation::new();
+        sim.at(0).enqueue(1, 2, 1000).enqueue(2, 2, 1300).cancel(2);
+        assert_eq!(sim.at(20).matches(), NONE);
+        sim.enqueue(2, 2, 1300);
+        assert_eq!(sim.matches(), NONE);
+        assert_eq!(sim.at(40).matches(), [[1, 2]]);
+    }
+}
@@ -1,40 +1,256 @@
-use core::str;
-use std::io::{ErrorKind, Read, Write};
-
-#[repr(u32)] // Ensure that the enum is represented as an u32
-#[derive(Copy, Clone)]
-pub enum MessageType {
-    ConnectionRequested = 1,
-    ConnectionAccepted = 2,
-    ConnectionRejected = 3,
-    UserStatusUpdate = 4,
-    ReadyToStartChanged = 5,
-    ReadyToStart = 6,
-    StubMessage = 7,
-    GameAboutToStart = 8,
-    GameStarting = 9,
-    ChatUpdate = 10,
-}
-
-impl From<u32> for MessageType {
-    fn from(value: u32) -> MessageType {
-        match value {
-            1 => MessageType::ConnectionRequested,
-            2 => MessageType::ConnectionAccepted,
-            3 => MessageType::ConnectionRejected,
-            4 => MessageType::UserStatusUpdate,
-            5 => MessageType::ReadyToStartChanged,
-            6 => MessageType::ReadyToStart,
-            7 => MessageType::StubMessage,
-            8 => MessageType::GameAboutToStart,
-            9 => MessageType::GameStarting,
-            10 => MessageType::ChatUpdate,
-            _ => panic!("Unknown MessageType value: {value}!"),
-        }
-    }
-}
-
-#[derive(Clone)]
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{ErrorKind, Read, Write},
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::{Deserialize, Deserializer};
+
+#[macro_use]
+pub mod wire;
+mod schema;
+
+pub use schema::{
+    ChatLine, ClientPacket, KickReason, LobbyRequestError, MatchmakingState, MessageType,
+    ProtocolErrorCode, RejectionReason, RoomInfo, RoomRequestError, RoomState, RoomVisibility,
+    ServerPacket, UserReady, UserStatus, UserStatusChange, ENUMS, STRUCTS,
+};
+
+/// Maximum payload size per message type. A frame whose length prefix is above
+/// the limit for its type is rejected before anything is allocated for it.
+///
+/// In the config file the limits of [`FrameLimits::default`] can be changed
+/// one message type at a time, by name:
+///
+/// ```toml
+/// [frame_limits]
+/// default = 32768
+///
+/// [frame_limits.messages]
+/// ChatUpdate = 4096
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrameLimits {
+    /// For every message type without a limit of its own, unknown ones included.
+    #[serde(rename = "default")]
+    default_limit: usize,
+    #[serde(rename = "messages", deserialize_with = "limits_by_name")]
+    limits: HashMap<MessageType, usize>,
+}
+
+impl FrameLimits {
+    pub const DEFAULT_LIMIT: usize = 64 * 1024;
+
+    /// Limits where every message type, including unknown ones, uses `default_limit`.
+    pub fn new(default_limit: usize) -> Self {
+        FrameLimits {
+            default_limit,
+            limits: HashMap::new(),
+        }
+    }
+
+    pub fn with_limit(mut self, message_type: MessageType, limit: usize) -> Self {
+        self.limits.insert(message_type, limit);
+        self
+    }
+
+    pub fn with_default_limit(mut self, default_limit: usize) -> Self {
+        self.default_limit = default_limit;
+        self
+    }
+
+    /// Makes `limit` the largest frame of any type: the default, and every
+    /// limit of a message type that was larger.
+    pub fn capped_at(mut self, limit: usize) -> Self {
+        self.default_limit = limit;
+        for message_limit in self.limits.values_mut() {
+            *message_limit = (*message_limit).min(limit);
+        }
+        self
+    }
+
+    pub fn default_limit(&self) -> usize {
+        self.default_limit
+    }
+
+    pub fn limit_for(&self, raw_message_type: u32) -> usize {
+        MessageType::try_from(raw_message_type)
+            .ok()
+            .and_then(|message_type| self.limits.get(&message_type).copied())
+            .unwrap_or(self.default_limit)
+    }
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits::new(Self::DEFAULT_LIMIT)
+            .with_limit(MessageType::ConnectionRequested, 1024)
+            .with_limit(MessageType::ReadyToStartChanged, 16)
+            .with_limit(MessageType::StubMessage, 16 * 1024)
+            .with_limit(MessageType::ChatUpdate, 16 * 1024)
+            .with_limit(MessageType::Pong, 16)
+            .with_limit(MessageType::CreateRoom, 256)
+            .with_limit(MessageType::ListRooms, 16)
+            .with_limit(MessageType::JoinRoom, 256)
+            .with_limit(MessageType::LeaveRoom, 16)
+            .with_limit(MessageType::FindMatch, 16)
+            .with_limit(MessageType::CancelMatch, 16)
+            .with_limit(MessageType::KickPlayer, 16)
+            .with_limit(MessageType::LockLobby, 16)
+            .with_limit(MessageType::TransferHost, 16)
+            .with_limit(MessageType::ForceStart, 16)
+            .with_limit(MessageType::ChangeLobbySettings, 16)
+    }
+}
+
+/// The limits named in the config file, on top of the default ones.
+fn limits_by_name<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<MessageType, usize>, D::Error> {
+    let mut limits = FrameLimits::default().limits;
+    for (name, limit) in BTreeMap::<String, usize>::deserialize(deserializer)? {
+        let message_type = MessageType::from_name(&name)
+            .ok_or_else(|| serde::de::Error::custom(format!("no message type is called {name}")))?;
+        limits.insert(message_type, limit);
+    }
+    Ok(limits)
+}
+
+/// Counters of frames dropped for exceeding their [`FrameLimits`].
+#[derive(Debug, Default)]
+pub struct FrameStats {
+    oversize_total: AtomicU64,
+    oversize_by_type: Mutex<BTreeMap<u32, u64>>,
+}
+
+impl FrameStats {
+    pub fn new() -> Self {
+        FrameStats::default()
+    }
+
+    pub fn record_oversize(&self, raw_message_type: u32) {
+        *self
+            .oversize_by_type
+            .lock()
+            .unwrap()
+            .entry(raw_message_type)
+            .or_insert(0) += 1;
+        self.oversize_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn oversize_total(&self) -> u64 {
+        self.oversize_total.load(Ordering::Relaxed)
+    }
+
+    pub fn oversize_by_type(&self) -> BTreeMap<u32, u64> {
+        self.oversize_by_type.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(std::io::Error),
+    UnknownType(u32),
+    UnexpectedType(MessageType),
+    Truncated(MessageType),
+    BadUtf8(MessageType),
+    InvalidValue(MessageType),
+    /// A line of the JSON codec that is not a packet, see [`crate::json`].
+    Json(serde_json::Error),
+    Oversize {
+        message_type: u32,
+        length: usize,
+        limit: usize,
+    },
+}
+
+impl DecodeError {
+    /// Whether the reader is still aligned on a frame boundary after this error.
+    /// If it is not, the only safe thing to do is to drop the connection.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, DecodeError::Io(_) | DecodeError::Oversize { .. })
+    }
+
+    pub fn rejected_type(&self) -> u32 {
+        match self {
+            DecodeError::Io(_) | DecodeError::Json(_) => 0,
+            DecodeError::UnknownType(message_type) => *message_type,
+            DecodeError::UnexpectedType(message_type)
+            | DecodeError::Truncated(message_type)
+            | DecodeError::BadUtf8(message_type)
+            | DecodeError::InvalidValue(message_type) => *message_type as u32,
+            DecodeError::Oversize { message_type, .. } => *message_type,
+        }
+    }
+
+    pub fn code(&self) -> ProtocolErrorCode {
+        match self {
+            DecodeError::Io(_) => ProtocolErrorCode::Io,
+            DecodeError::UnknownType(_) => ProtocolErrorCode::UnknownType,
+            DecodeError::UnexpectedType(_) => ProtocolErrorCode::UnexpectedType,
+            DecodeError::Truncated(_) => ProtocolErrorCode::Truncated,
+            DecodeError::BadUtf8(_) => ProtocolErrorCode::BadUtf8,
+            DecodeError::InvalidValue(_) | DecodeError::Json(_) => ProtocolErrorCode::InvalidValue,
+            DecodeError::Oversize { .. } => ProtocolErrorCode::Oversize,
+        }
+    }
+
+    /// The packet sent back to the client whose frame was rejected.
+    pub fn to_packet(&self) -> ServerPacket {
+        ServerPacket::ProtocolError {
+            rejected_type: self.rejected_type(),
+            code: self.code(),
+            details: self.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "io error: {e}"),
+            DecodeError::UnknownType(message_type) => {
+                write!(f, "unknown message type {message_type}")
+            }
+            DecodeError::UnexpectedType(message_type) => {
+                write!(f, "unexpected message type {message_type:?}")
+            }
+            DecodeError::Truncated(message_type) => {
+                write!(f, "truncated {message_type:?} payload")
+            }
+            DecodeError::BadUtf8(message_type) => {
+                write!(f, "invalid utf-8 in {message_type:?} payload")
+            }
+            DecodeError::InvalidValue(message_type) => {
+                write!(f, "invalid value in {message_type:?} payload")
+            }
+            DecodeError::Json(e) => write!(f, "invalid json packet: {e}"),
+            DecodeError::Oversize {
+                message_type,
+                length,
+                limit,
+            } => write!(
+                f,
+                "payload of {length} bytes for message type {message_type} exceeds limit of {limit}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<std::io::Error> for DecodeError {
+    fn from(e: std::io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Message {
     message_type: MessageType,
     data: Vec<u8>,
@@ -52,6 +268,14 @@ impl Message {
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
     pub fn push<T: bytemuck::Pod>(&mut self, data: &T) -> &mut Self {
         let bytes = bytemuck::bytes_of(data);
         self.data.extend_from_slice(bytes);
@@ -64,26 +288,28 @@ impl Message {
         self.push(&(bytes.len() as u32))
     }
 
-    pub fn pop<T: bytemuck::Pod>(&mut self) -> Option<T> {
+    pub fn pop<T: bytemuck::Pod>(&mut self) -> Result<T, DecodeError> {
         let size = std::mem::size_of::<T>();
 
         if self.data.len() < size {
-            return None;
+            return Err(DecodeError::Truncated(self.message_type));
         }
 
         let start_index = self.data.len() - size;
         let bytes = self.data.split_off(start_index);
 
-        bytemuck::try_from_bytes(&bytes).ok().cloned()
+        Ok(bytemuck::pod_read_unaligned(&bytes))
     }
 
-    pub fn pop_string(&mut self) -> Option<String> {
+    pub fn pop_string(&mut self) -> Result<String, DecodeError> {
         let string_size: u32 = self.pop()?;
-        Some(
-            str::from_utf8(&self.data.split_off(self.data.len() - string_size as usize))
-                .ok()?
-                .to_string(),
-        )
+        let start_index = self
+            .data
+            .len()
+            .checked_sub(string_size as usize)
+            .ok_or(DecodeError::Truncated(self.message_type))?;
+        String::from_utf8(self.data.split_off(start_index))
+            .map_err(|_| DecodeError::BadUtf8(self.message_type))
     }
 
     pub fn write_to<T: Write>(&self, writer: &mut T) -> std::io::Result<()> {
@@ -92,41 +318,1262 @@ impl Message {
         writer.write_all(&self.data)
     }
 
-    pub fn next_message<R: Read>(reader: &mut R) -> std::io::Result<Message> {
+    /// Reads the next frame. Frames of an unknown type are skipped so that the
+    /// reader stays aligned, and reported as [`DecodeError::UnknownType`].
+    pub fn next_message<R: Read>(
+        reader: &mut R,
+        limits: &FrameLimits,
+    ) -> Result<Message, DecodeError> {
         let mut message_type_buf = [0u8; std::mem::size_of::<MessageType>()];
         reader.read_exact(&mut message_type_buf)?;
-        let message_type = MessageType::from(u32::from_le_bytes(message_type_buf));
+        let raw_message_type = u32::from_le_bytes(message_type_buf);
 
         let mut length_buf = [0u8; std::mem::size_of::<u32>()];
         reader.read_exact(&mut length_buf)?;
         let message_length = u32::from_le_bytes(length_buf) as usize;
 
+        let limit = limits.limit_for(raw_message_type);
+        if message_length > limit {
+            return Err(DecodeError::Oversize {
+                message_type: raw_message_type,
+                length: message_length,
+                limit,
+            });
+        }
+
+        let message_type = match MessageType::try_from(raw_message_type) {
+            Ok(message_type) => message_type,
+            Err(e) => {
+                let skipped = std::io::copy(
+                    &mut reader.take(message_length as u64),
+                    &mut std::io::sink(),
+                )?;
+                if skipped < message_length as u64 {
+                    return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+                }
+                return Err(e);
+            }
+        };
+
         let mut data = vec![0u8; message_length];
         reader.read_exact(&mut data)?;
 
         Ok(Message { message_type, data })
     }
 
-    pub fn iter<'a, R: std::io::Read>(reader: &'a mut R) -> MessageIterator<'a, R> {
-        MessageIterator(reader)
+    pub fn iter<'a, R: std::io::Read>(
+        reader: &'a mut R,
+        limits: &'a FrameLimits,
+    ) -> MessageIterator<'a, R> {
+        MessageIterator(reader, limits)
     }
 }
 
-pub struct MessageIterator<'a, R: Read>(&'a mut R);
+/// Ends where the stream does, if that is between two frames. A stream that
+/// ends in the middle of a frame is an [`ErrorKind::UnexpectedEof`].
+pub struct MessageIterator<'a, R: Read>(&'a mut R, &'a FrameLimits);
 
-impl<'a, R: Read> Iterator for MessageIterator<'a, R> {
-    type Item = std::io::Result<Message>;
+impl<R: Read> Iterator for MessageIterator<'_, R> {
+    type Item = Result<Message, DecodeError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match Message::next_message(self.0) {
-            Ok(message) => Some(Ok(message)),
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    None
-                } else {
-                    Some(Err(e))
+        let mut first = [0u8; 1];
+        loop {
+            match self.0.read(&mut first) {
+                Ok(0) => return None,
+                Ok(_) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        Some(Message::next_message(
+            &mut (&first[..]).chain(&mut *self.0),
+            self.1,
+        ))
+    }
+}
+
+/// The version sent by clients that predate the handshake: their
+/// `ConnectionRequested` carries nothing but a 4-byte client-side number.
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+/// Protocol versions this server can talk.
+pub const SUPPORTED_PROTOCOL_VERSIONS: RangeInclusive<u16> = 2..=9;
+
+/// The version assumed for frames exchanged before the handshake completes.
+pub const HANDSHAKE_PROTOCOL_VERSION: u16 = 2;
+
+/// First version whose payloads are read front to back, see [`Layout`].
+pub const FORWARD_LAYOUT_PROTOCOL_VERSION: u16 = 3;
+
+/// First version whose collection counts are varints, see [`CountEncoding`].
+pub const VARINT_COUNTS_PROTOCOL_VERSION: u16 = 4;
+
+/// First version whose clients answer [`ServerPacket::Ping`].
+pub const HEARTBEAT_PROTOCOL_VERSION: u16 = 5;
+
+/// First version whose clients are given a session in
+/// [`ServerPacket::ConnectionAccepted`] and may send [`ClientPacket::Reconnect`].
+pub const SESSION_PROTOCOL_VERSION: u16 = 6;
+
+/// First version whose clients pick a room themselves, see [`crate::room`].
+pub const ROOMS_PROTOCOL_VERSION: u16 = 7;
+
+/// First version whose lobbies have a host, see [`ServerPacket::LobbyUpdate`].
+pub const HOST_PROTOCOL_VERSION: u16 = 8;
+
+/// First version that asks for a display name and is told everybody's, see
+/// [`crate::names`].
+pub const NAMES_PROTOCOL_VERSION: u16 = 9;
+
+/// How fields are laid out in a payload. Both layouts carry the same fields in
+/// the same order; they differ in which end of the payload the reader starts from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Fields are popped off the end of the payload, see [`Message::pop`].
+    Stack,
+    /// Fields are read front to back, see [`MessageReader`].
+    Forward,
+}
+
+impl Layout {
+    pub fn for_version(protocol_version: u16) -> Layout {
+        if protocol_version >= FORWARD_LAYOUT_PROTOCOL_VERSION {
+            Layout::Forward
+        } else {
+            Layout::Stack
+        }
+    }
+}
+
+/// How collection counts are written in the [`Layout::Forward`] layout.
+/// The [`Layout::Stack`] layout always uses [`CountEncoding::U8`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CountEncoding {
+    U8,
+    /// Unsigned LEB128, at most 5 bytes.
+    Varint,
+}
+
+impl CountEncoding {
+    pub fn for_version(protocol_version: u16) -> CountEncoding {
+        if protocol_version >= VARINT_COUNTS_PROTOCOL_VERSION {
+            CountEncoding::Varint
+        } else {
+            CountEncoding::U8
+        }
+    }
+
+    pub fn max_len(&self) -> usize {
+        match self {
+            CountEncoding::U8 => u8::MAX as usize,
+            CountEncoding::Varint => u32::MAX as usize,
+        }
+    }
+}
+
+pub trait PayloadWriter {
+    /// The version of the client the payload is for, see [`wire`].
+    fn protocol_version(&self) -> u16;
+
+    fn write<T: bytemuck::Pod>(&mut self, data: &T);
+
+    fn write_string(&mut self, data: &str);
+
+    fn write_len(&mut self, len: usize);
+
+    fn max_len(&self) -> usize;
+
+    /// Writes the count followed by the items. If the count does not fit the
+    /// count encoding, only the last `max_len` items are written, so that a
+    /// client on an older version keeps the most recent entries and stays in sync.
+    fn write_collection<T, F: FnMut(&mut Self, &T)>(&mut self, items: &[T], mut write_item: F)
+    where
+        Self: Sized,
+    {
+        let items = &items[items.len().saturating_sub(self.max_len())..];
+        self.write_len(items.len());
+        for item in items {
+            write_item(self, item);
+        }
+    }
+}
+
+pub trait PayloadReader {
+    fn message_type(&self) -> MessageType;
+
+    /// The version of the client the payload is from, see [`wire`].
+    fn protocol_version(&self) -> u16;
+
+    fn read<T: bytemuck::Pod>(&mut self) -> Result<T, DecodeError>;
+
+    fn read_string(&mut self) -> Result<String, DecodeError>;
+
+    fn read_len(&mut self) -> Result<usize, DecodeError>;
+
+    fn read_collection<T, F: FnMut(&mut Self) -> Result<T, DecodeError>>(
+        &mut self,
+        mut read_item: F,
+    ) -> Result<Vec<T>, DecodeError>
+    where
+        Self: Sized,
+    {
+        let len = self.read_len()?;
+        (0..len).map(|_| read_item(self)).collect()
+    }
+}
+
+/// Reads a payload in the [`Layout::Stack`] layout, popping fields off the
+/// end of the message.
+struct StackReader<'a> {
+    message: &'a mut Message,
+    protocol_version: u16,
+}
+
+impl<'a> StackReader<'a> {
+    fn new(message: &'a mut Message, protocol_version: u16) -> Self {
+        StackReader {
+            message,
+            protocol_version,
+        }
+    }
+}
+
+impl PayloadReader for StackReader<'_> {
+    fn message_type(&self) -> MessageType {
+        self.message.message_type
+    }
+
+    fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    fn read<T: bytemuck::Pod>(&mut self) -> Result<T, DecodeError> {
+        self.message.pop()
+    }
+
+    fn read_string(&mut self) -> Result<String, DecodeError> {
+        self.message.pop_string()
+    }
+
+    fn read_len(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.message.pop::<u8>()? as usize)
+    }
+}
+
+/// Builds a payload in the [`Layout::Stack`] layout: fields are written in the
+/// order they will be popped, and laid out back to front.
+struct StackWriter {
+    message_type: MessageType,
+    protocol_version: u16,
+    fields: Vec<Vec<u8>>,
+}
+
+impl StackWriter {
+    fn new(message_type: MessageType, protocol_version: u16) -> Self {
+        StackWriter {
+            message_type,
+            protocol_version,
+            fields: Vec::new(),
+        }
+    }
+
+    fn finish(self) -> Message {
+        Message {
+            message_type: self.message_type,
+            data: self.fields.into_iter().rev().flatten().collect(),
+        }
+    }
+}
+
+impl PayloadWriter for StackWriter {
+    fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    fn write<T: bytemuck::Pod>(&mut self, data: &T) {
+        self.fields.push(bytemuck::bytes_of(data).to_vec());
+    }
+
+    fn write_string(&mut self, data: &str) {
+        let mut field = data.as_bytes().to_vec();
+        field.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        self.fields.push(field);
+    }
+
+    fn write_len(&mut self, len: usize) {
+        self.write(&(len as u8));
+    }
+
+    fn max_len(&self) -> usize {
+        CountEncoding::U8.max_len()
+    }
+}
+
+/// Builds a payload in the [`Layout::Forward`] layout.
+pub struct MessageWriter {
+    message: Message,
+    protocol_version: u16,
+    counts: CountEncoding,
+}
+
+impl MessageWriter {
+    pub fn new(message_type: MessageType, protocol_version: u16) -> Self {
+        MessageWriter {
+            message: Message::new(message_type),
+            protocol_version,
+            counts: CountEncoding::for_version(protocol_version),
+        }
+    }
+
+    pub fn finish(self) -> Message {
+        self.message
+    }
+}
+
+impl PayloadWriter for MessageWriter {
+    fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    fn write<T: bytemuck::Pod>(&mut self, data: &T) {
+        self.message.push(data);
+    }
+
+    fn write_string(&mut self, data: &str) {
+        self.write(&(data.len() as u32));
+        self.message.data.extend_from_slice(data.as_bytes());
+    }
+
+    fn write_len(&mut self, len: usize) {
+        match self.counts {
+            CountEncoding::U8 => self.write(&(len as u8)),
+            CountEncoding::Varint => {
+                let mut value = len as u32;
+                while value >= 0x80 {
+                    self.write(&((value as u8 & 0x7f) | 0x80));
+                    value >>= 7;
+                }
+                self.write(&(value as u8));
+            }
+        }
+    }
+
+    fn max_len(&self) -> usize {
+        self.counts.max_len()
+    }
+}
+
+/// Reads a payload in the [`Layout::Forward`] layout, front to back.
+pub struct MessageReader<'a> {
+    message_type: MessageType,
+    data: &'a [u8],
+    protocol_version: u16,
+    counts: CountEncoding,
+}
+
+impl<'a> MessageReader<'a> {
+    pub fn new(message: &'a Message, protocol_version: u16) -> Self {
+        MessageReader {
+            message_type: message.message_type,
+            data: &message.data,
+            protocol_version,
+            counts: CountEncoding::for_version(protocol_version),
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn take(&mut self, size: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < size {
+            return Err(DecodeError::Truncated(self.message_type));
+        }
+        let (taken, rest) = self.data.split_at(size);
+        self.data = rest;
+        Ok(taken)
+    }
+}
+
+impl PayloadReader for MessageReader<'_> {
+    fn message_type(&self) -> MessageType {
+        self.message_type
+    }
+
+    fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    fn read<T: bytemuck::Pod>(&mut self) -> Result<T, DecodeError> {
+        let bytes = self.take(std::mem::size_of::<T>())?;
+        Ok(bytemuck::pod_read_unaligned(bytes))
+    }
+
+    fn read_string(&mut self) -> Result<String, DecodeError> {
+        let string_size: u32 = self.read()?;
+        let bytes = self.take(string_size as usize)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::BadUtf8(self.message_type))
+    }
+
+    fn read_len(&mut self) -> Result<usize, DecodeError> {
+        match self.counts {
+            CountEncoding::U8 => Ok(self.read::<u8>()? as usize),
+            CountEncoding::Varint => {
+                let mut value: u32 = 0;
+                for shift in (0..32).step_by(7) {
+                    let byte: u8 = self.read()?;
+                    let bits = (byte & 0x7f) as u32;
+                    if bits << shift >> shift != bits {
+                        return Err(DecodeError::InvalidValue(self.message_type));
+                    }
+                    value |= bits << shift;
+                    if byte & 0x80 == 0 {
+                        return Ok(value as usize);
+                    }
                 }
+                Err(DecodeError::InvalidValue(self.message_type))
+            }
+        }
+    }
+}
+
+impl ClientPacket {
+    /// `ConnectionRequested` and `Reconnect` are the frames that negotiate the
+    /// version, so they are always in the stack layout. `ConnectionRequested`
+    /// carries the fields of the version it asks for.
+    pub fn encode(&self, protocol_version: u16) -> Message {
+        if let ClientPacket::ConnectionRequested {
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            ..
+        } = self
+        {
+            let mut message = Message::new(self.message_type());
+            message.push(&0i32);
+            return message;
+        }
+        let protocol_version = match self {
+            ClientPacket::ConnectionRequested {
+                protocol_version: requested,
+                ..
+            } => *requested,
+            _ => protocol_version,
+        };
+        match self.layout(protocol_version) {
+            Layout::Stack => {
+                let mut writer = StackWriter::new(self.message_type(), protocol_version);
+                self.write_payload(&mut writer);
+                writer.finish()
+            }
+            Layout::Forward => {
+                let mut writer = MessageWriter::new(self.message_type(), protocol_version);
+                self.write_payload(&mut writer);
+                writer.finish()
+            }
+        }
+    }
+
+    pub fn decode(
+        mut message: Message,
+        protocol_version: u16,
+    ) -> Result<ClientPacket, DecodeError> {
+        let message_type = message.message_type();
+        if message_type == MessageType::ConnectionRequested {
+            if message.len() == std::mem::size_of::<i32>() {
+                return Ok(ClientPacket::ConnectionRequested {
+                    protocol_version: LEGACY_PROTOCOL_VERSION,
+                    client_build: String::new(),
+                    name: String::new(),
+                });
+            }
+            // The requested version is the last field, so the first popped.
+            let requested = message.clone().pop::<u16>()?;
+            return Self::read_payload(
+                message_type,
+                &mut StackReader::new(&mut message, requested),
+            );
+        }
+        if message_type == MessageType::Reconnect {
+            return Self::read_payload(
+                message_type,
+                &mut StackReader::new(&mut message, protocol_version),
+            );
+        }
+        match Layout::for_version(protocol_version) {
+            Layout::Stack => Self::read_payload(
+                message_type,
+                &mut StackReader::new(&mut message, protocol_version),
+            ),
+            Layout::Forward => Self::read_payload(
+                message_type,
+                &mut MessageReader::new(&message, protocol_version),
+            ),
+        }
+    }
+
+    fn layout(&self, protocol_version: u16) -> Layout {
+        match self {
+            ClientPacket::ConnectionRequested { .. } | ClientPacket::Reconnect { .. } => {
+                Layout::Stack
+            }
+            _ => Layout::for_version(protocol_version),
+        }
+    }
+}
+
+impl ServerPacket {
+    /// `ConnectionRejected` answers a client whose version we may not speak,
+    /// so it is always in the stack layout.
+    pub fn encode(&self, protocol_version: u16) -> Message {
+        match self.layout(protocol_version) {
+            Layout::Stack => {
+                let mut writer = StackWriter::new(self.message_type(), protocol_version);
+                self.write_payload(&mut writer);
+                writer.finish()
+            }
+            Layout::Forward => {
+                let mut writer = MessageWriter::new(self.message_type(), protocol_version);
+                self.write_payload(&mut writer);
+                writer.finish()
+            }
+        }
+    }
+
+    pub fn decode(
+        mut message: Message,
+        protocol_version: u16,
+    ) -> Result<ServerPacket, DecodeError> {
+        let message_type = message.message_type();
+        let layout = if message_type == MessageType::ConnectionRejected {
+            Layout::Stack
+        } else {
+            Layout::for_version(protocol_version)
+        };
+        match layout {
+            Layout::Stack => Self::read_payload(
+                message_type,
+                &mut StackReader::new(&mut message, protocol_version),
+            ),
+            Layout::Forward => Self::read_payload(
+                message_type,
+                &mut MessageReader::new(&message, protocol_version),
+            ),
+        }
+    }
+
+    /// The oldest protocol whose clients know this packet. Older ones are not
+    /// sent it.
+    pub fn first_version(&self) -> u16 {
+        match self {
+            ServerPacket::RoomList { .. }
+            | ServerPacket::RoomJoined { .. }
+            | ServerPacket::RoomLeft
+            | ServerPacket::RoomRequestFailed { .. }
+            | ServerPacket::MatchmakingStatus { .. } => ROOMS_PROTOCOL_VERSION,
+            ServerPacket::LobbyUpdate { .. } | ServerPacket::LobbyRequestFailed { .. } => {
+                HOST_PROTOCOL_VERSION
             }
+            _ => HANDSHAKE_PROTOCOL_VERSION,
+        }
+    }
+
+    fn layout(&self, protocol_version: u16) -> Layout {
+        match self {
+            ServerPacket::ConnectionRejected { .. } => Layout::Stack,
+            _ => Layout::for_version(protocol_version),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_id::UserId;
+
+    /// Builds a frame the way `Message` in client/src/message.hpp does: every
+    /// value is appended as is and a string is its bytes followed by its
+    /// length. Values are pushed in the reverse of the order they are read.
+    #[derive(Default)]
+    struct Cpp(Vec<u8>);
+
+    impl Cpp {
+        fn u8(mut self, value: u8) -> Self {
+            self.0.push(value);
+            self
+        }
+
+        fn u16(mut self, value: u16) -> Self {
+            self.0.extend_from_slice(&value.to_le_bytes());
+            self
+        }
+
+        fn u32(mut self, value: u32) -> Self {
+            self.0.extend_from_slice(&value.to_le_bytes());
+            self
+        }
+
+        fn i32(mut self, value: i32) -> Self {
+            self.0.extend_from_slice(&value.to_le_bytes());
+            self
+        }
+
+        fn string(mut self, value: &str) -> Self {
+            self.0.extend_from_slice(value.as_bytes());
+            self.u32(value.len() as u32)
+        }
+
+        fn frame(self, message_type: MessageType) -> Vec<u8> {
+            let mut frame = Vec::new();
+            frame.extend_from_slice(&(message_type as u32).to_le_bytes());
+            frame.extend_from_slice(&(self.0.len() as u32).to_le_bytes());
+            frame.extend_from_slice(&self.0);
+            frame
+        }
+    }
+
+    fn bytes(message: &Message) -> Vec<u8> {
+        let mut frame = Vec::new();
+        message.write_to(&mut frame).unwrap();
+        frame
+    }
+
+    fn read_back(frame: &[u8]) -> Message {
+        Message::next_message(&mut &frame[..], &FrameLimits::default()).unwrap()
+    }
+
+    fn client_packets() -> Vec<(ClientPacket, Vec<u8>)> {
+        use ClientPacket::*;
+        vec![
+            (
+                ConnectionRequested {
+                    protocol_version: 2,
+                    client_build: "spell-wars-client 0.0.1".to_string(),
+                    name: String::new(),
+                },
+                Cpp::default()
+                    .string("spell-wars-client 0.0.1")
+                    .u16(2)
+                    .frame(MessageType::ConnectionRequested),
+            ),
+            (
+                ReadyToStartChanged { ready: true },
+                Cpp::default().u8(1).frame(MessageType::ReadyToStartChanged),
+            ),
+            (
+                StubMessage {
+                    strings: vec!["a".to_string(), "bc".to_string()],
+                },
+                Cpp::default()
+                    .string("bc")
+                    .string("a")
+                    .u8(2)
+                    .frame(MessageType::StubMessage),
+            ),
+            (
+                ChatUpdate {
+                    messages: vec!["hi".to_string(), "there".to_string()],
+                },
+                Cpp::default()
+                    .string("there")
+                    .string("hi")
+                    .u8(2)
+                    .frame(MessageType::ChatUpdate),
+            ),
+            (
+                Pong { nonce: 7 },
+                Cpp::default().u32(7).frame(MessageType::Pong),
+            ),
+            (
+                Reconnect {
+                    protocol_version: 6,
+                    session: "abc".to_string(),
+                },
+                Cpp::default()
+                    .string("abc")
+                    .u16(6)
+                    .frame(MessageType::Reconnect),
+            ),
+            (
+                CreateRoom {
+                    visibility: RoomVisibility::Private,
+                    password: "pw".to_string(),
+                },
+                Cpp::default()
+                    .string("pw")
+                    .u8(1)
+                    .frame(MessageType::CreateRoom),
+            ),
+            (ListRooms, Cpp::default().frame(MessageType::ListRooms)),
+            (
+                JoinRoom {
+                    code: "ABC234".to_string(),
+                    password: String::new(),
+                },
+                Cpp::default()
+                    .string("")
+                    .string("ABC234")
+                    .frame(MessageType::JoinRoom),
+            ),
+            (LeaveRoom, Cpp::default().frame(MessageType::LeaveRoom)),
+            (
+                FindMatch {
+                    players: 4,
+                    rating: 1200,
+                },
+                Cpp::default().u16(1200).u8(4).frame(MessageType::FindMatch),
+            ),
+            (CancelMatch, Cpp::default().frame(MessageType::CancelMatch)),
+            (
+                KickPlayer {
+                    user: UserId::new(3),
+                },
+                Cpp::default().i32(3).frame(MessageType::KickPlayer),
+            ),
+            (
+                LockLobby { locked: true },
+                Cpp::default().u8(1).frame(MessageType::LockLobby),
+            ),
+            (
+                TransferHost {
+                    user: UserId::new(4),
+                },
+                Cpp::default().i32(4).frame(MessageType::TransferHost),
+            ),
+            (ForceStart, Cpp::default().frame(MessageType::ForceStart)),
+            (
+                ChangeLobbySettings { countdown: 5 },
+                Cpp::default().u8(5).frame(MessageType::ChangeLobbySettings),
+            ),
+        ]
+    }
+
+    fn server_packets() -> Vec<(ServerPacket, Vec<u8>)> {
+        use ServerPacket::*;
+        vec![
+            (
+                ConnectionAccepted {
+                    you: UserId::new(2),
+                    users: vec![
+                        UserReady {
+                            ready: true,
+                            user: UserId::new(1),
+                            name: String::new(),
+                        },
+                        UserReady {
+                            ready: false,
+                            user: UserId::new(2),
+                            name: String::new(),
+                        },
+                    ],
+                    session: String::new(),
+                },
+                Cpp::default()
+                    .i32(2)
+                    .u8(0)
+                    .i32(1)
+                    .u8(1)
+                    .u8(2)
+                    .i32(2)
+                    .frame(MessageType::ConnectionAccepted),
+            ),
+            (
+                ConnectionRejected {
+                    reason: RejectionReason::LobbyFull,
+                    min_version: 2,
+                    max_version: 9,
+                },
+                Cpp::default()
+                    .u16(9)
+                    .u16(2)
+                    .u8(5)
+                    .frame(MessageType::ConnectionRejected),
+            ),
+            (
+                UserStatusUpdate {
+                    users: vec![UserStatusChange {
+                        status: UserStatus::Disconnected,
+                        user: UserId::new(5),
+                        name: String::new(),
+                    }],
+                },
+                Cpp::default()
+                    .i32(5)
+                    .u8(2)
+                    .u8(1)
+                    .frame(MessageType::UserStatusUpdate),
+            ),
+            (
+                ReadyToStart,
+                Cpp::default().frame(MessageType::ReadyToStart),
+            ),
+            (
+                StubMessage {
+                    strings: vec!["x".to_string()],
+                },
+                Cpp::default()
+                    .string("x")
+                    .u8(1)
+                    .frame(MessageType::StubMessage),
+            ),
+            (
+                GameAboutToStart { seconds_left: 3 },
+                Cpp::default().u8(3).frame(MessageType::GameAboutToStart),
+            ),
+            (
+                GameStarting,
+                Cpp::default().frame(MessageType::GameStarting),
+            ),
+            (
+                ChatUpdate {
+                    messages: vec![
+                        ChatLine {
+                            user: UserId::new(1),
+                            text: "hi".to_string(),
+                            name: String::new(),
+                        },
+                        ChatLine {
+                            user: UserId::SERVER,
+                            text: "slow down".to_string(),
+                            name: String::new(),
+                        },
+                    ],
+                },
+                Cpp::default()
+                    .string("slow down")
+                    .i32(UserId::SERVER.get())
+                    .string("hi")
+                    .i32(1)
+                    .u8(2)
+                    .frame(MessageType::ChatUpdate),
+            ),
+            (
+                ProtocolError {
+                    rejected_type: 99,
+                    code: ProtocolErrorCode::UnknownType,
+                    details: "unknown".to_string(),
+                },
+                Cpp::default()
+                    .string("unknown")
+                    .u8(1)
+                    .u32(99)
+                    .frame(MessageType::ProtocolError),
+            ),
+            (
+                Ping { nonce: 11 },
+                Cpp::default().u32(11).frame(MessageType::Ping),
+            ),
+            (
+                ServerShutdown {
+                    reason: "bye".to_string(),
+                },
+                Cpp::default()
+                    .string("bye")
+                    .frame(MessageType::ServerShutdown),
+            ),
+            (
+                Kicked {
+                    reason: KickReason::ByHost,
+                    details: "kicked".to_string(),
+                },
+                Cpp::default()
+                    .string("kicked")
+                    .u8(5)
+                    .frame(MessageType::Kicked),
+            ),
+            (
+                RoomList {
+                    rooms: vec![RoomInfo {
+                        code: "ABC234".to_string(),
+                        players: 3,
+                        state: RoomState::Locked,
+                        has_password: true,
+                    }],
+                },
+                Cpp::default()
+                    .u8(1)
+                    .u8(3)
+                    .u16(3)
+                    .string("ABC234")
+                    .u8(1)
+                    .frame(MessageType::RoomList),
+            ),
+            (
+                RoomJoined {
+                    code: "ABC234".to_string(),
+                },
+                Cpp::default()
+                    .string("ABC234")
+                    .frame(MessageType::RoomJoined),
+            ),
+            (RoomLeft, Cpp::default().frame(MessageType::RoomLeft)),
+            (
+                RoomRequestFailed {
+                    reason: RoomRequestError::WrongPassword,
+                },
+                Cpp::default().u8(2).frame(MessageType::RoomRequestFailed),
+            ),
+            (
+                MatchmakingStatus {
+                    state: MatchmakingState::Searching,
+                    waited_seconds: 12,
+                    tolerance: 150,
+                    queued: 4,
+                },
+                Cpp::default()
+                    .u16(4)
+                    .u16(150)
+                    .u16(12)
+                    .u8(0)
+                    .frame(MessageType::MatchmakingStatus),
+            ),
+            (
+                LobbyUpdate {
+                    host: UserId::new(1),
+                    locked: false,
+                    countdown: 10,
+                },
+                Cpp::default()
+                    .u8(10)
+                    .u8(0)
+                    .i32(1)
+                    .frame(MessageType::LobbyUpdate),
+            ),
+            (
+                LobbyRequestFailed {
+                    reason: LobbyRequestError::NotHost,
+                },
+                Cpp::default().u8(1).frame(MessageType::LobbyRequestFailed),
+            ),
+        ]
+    }
+
+    #[test]
+    fn every_packet_is_covered() {
+        let mut covered: Vec<MessageType> = client_packets()
+            .iter()
+            .map(|(packet, _)| packet.message_type())
+            .chain(
+                server_packets()
+                    .iter()
+                    .map(|(packet, _)| packet.message_type()),
+            )
+            .collect();
+        covered.sort_by_key(|message_type| *message_type as u32);
+        covered.dedup();
+        assert_eq!(covered, MessageType::ALL);
+        assert_eq!(client_packets().len(), ClientPacket::SCHEMA.len());
+        assert_eq!(server_packets().len(), ServerPacket::SCHEMA.len());
+    }
+
+    #[test]
+    fn client_packets_match_the_cpp_client_in_the_stack_layout() {
+        for (packet, frame) in client_packets() {
+            let encoded = packet.encode(HANDSHAKE_PROTOCOL_VERSION);
+            assert_eq!(bytes(&encoded), frame, "{packet:?}");
+            let decoded = ClientPacket::decode(read_back(&frame), HANDSHAKE_PROTOCOL_VERSION);
+            assert_eq!(decoded.unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn server_packets_match_the_cpp_client_in_the_stack_layout() {
+        for (packet, frame) in server_packets() {
+            let encoded = packet.encode(HANDSHAKE_PROTOCOL_VERSION);
+            assert_eq!(bytes(&encoded), frame, "{packet:?}");
+            let decoded = ServerPacket::decode(read_back(&frame), HANDSHAKE_PROTOCOL_VERSION);
+            assert_eq!(decoded.unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn the_legacy_connection_request_is_a_bare_number() {
+        let frame = Cpp::default()
+            .i32(42)
+            .frame(MessageType::ConnectionRequested);
+        let packet = ClientPacket::decode(read_back(&frame), HANDSHAKE_PROTOCOL_VERSION);
+        assert_eq!(
+            packet.unwrap(),
+            ClientPacket::ConnectionRequested {
+                protocol_version: LEGACY_PROTOCOL_VERSION,
+                client_build: String::new(),
+                name: String::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn every_packet_round_trips_in_every_version() {
+        for version in SUPPORTED_PROTOCOL_VERSIONS {
+            for (packet, _) in client_packets() {
+                let decoded = ClientPacket::decode(packet.encode(version), version);
+                assert_eq!(decoded.unwrap(), packet, "protocol {version}");
+            }
+            for (packet, _) in server_packets() {
+                let decoded = ServerPacket::decode(packet.encode(version), version);
+                assert_eq!(decoded.unwrap(), packet, "protocol {version}");
+            }
+        }
+    }
+
+    #[test]
+    fn fields_newer_than_the_client_are_left_out() {
+        let accepted = |session: &str| ServerPacket::ConnectionAccepted {
+            you: UserId::new(1),
+            users: Vec::new(),
+            session: session.to_string(),
+        };
+        for version in SUPPORTED_PROTOCOL_VERSIONS {
+            let encoded = accepted("abc").encode(version);
+            let decoded = ServerPacket::decode(encoded.clone(), version).unwrap();
+            if version >= SESSION_PROTOCOL_VERSION {
+                assert_eq!(decoded, accepted("abc"), "protocol {version}");
+            } else {
+                assert_eq!(bytes(&encoded), bytes(&accepted("").encode(version)));
+                assert_eq!(decoded, accepted(""), "protocol {version}");
+            }
+
+            let chat = |name: &str| ServerPacket::ChatUpdate {
+                messages: vec![ChatLine {
+                    user: UserId::new(1),
+                    text: "hi".to_string(),
+                    name: name.to_string(),
+                }],
+            };
+            let decoded = ServerPacket::decode(chat("Ann").encode(version), version).unwrap();
+            if version >= NAMES_PROTOCOL_VERSION {
+                assert_eq!(decoded, chat("Ann"), "protocol {version}");
+            } else {
+                assert_eq!(decoded, chat(""), "protocol {version}");
+            }
+        }
+    }
+
+    #[test]
+    fn a_connection_request_has_the_fields_of_the_version_it_asks_for() {
+        let request = |protocol_version: u16, name: &str| ClientPacket::ConnectionRequested {
+            protocol_version,
+            client_build: "build".to_string(),
+            name: name.to_string(),
+        };
+        for requested in SUPPORTED_PROTOCOL_VERSIONS {
+            let encoded = request(requested, "Ann").encode(HANDSHAKE_PROTOCOL_VERSION);
+            let decoded = ClientPacket::decode(encoded, HANDSHAKE_PROTOCOL_VERSION).unwrap();
+            if requested >= NAMES_PROTOCOL_VERSION {
+                assert_eq!(decoded, request(requested, "Ann"), "protocol {requested}");
+            } else {
+                assert_eq!(decoded, request(requested, ""), "protocol {requested}");
+            }
+        }
+        let frame = Cpp::default()
+            .string("Ann")
+            .string("build")
+            .u16(NAMES_PROTOCOL_VERSION)
+            .frame(MessageType::ConnectionRequested);
+        assert_eq!(
+            ClientPacket::decode(read_back(&frame), HANDSHAKE_PROTOCOL_VERSION).unwrap(),
+            request(NAMES_PROTOCOL_VERSION, "Ann")
+        );
+    }
+
+    /// Counts what is read from it, to show what was not.
+    struct Counted<'a> {
+        data: &'a [u8],
+        read: usize,
+    }
+
+    impl Read for Counted<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read = self.data.read(buf)?;
+            self.read += read;
+            Ok(read)
+        }
+    }
+
+    fn header(message_type: u32, length: u32) -> Vec<u8> {
+        let mut header = message_type.to_le_bytes().to_vec();
+        header.extend_from_slice(&length.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn a_length_above_the_limit_of_its_type_is_rejected_before_the_payload_is_read() {
+        let limits = FrameLimits::default();
+        let length = limits.limit_for(MessageType::Pong as u32) as u32 + 1;
+        let mut frame = header(MessageType::Pong as u32, length);
+        frame.resize(frame.len() + length as usize, 0);
+        let mut reader = Counted {
+            data: &frame,
+            read: 0,
+        };
+
+        let result = Message::iter(&mut reader, &limits).next().unwrap();
+        assert!(matches!(
+            result,
+            Err(DecodeError::Oversize { message_type, length: 17, limit: 16 })
+                if message_type == MessageType::Pong as u32
+        ));
+        assert_eq!(reader.read, 8);
+    }
+
+    #[test]
+    fn the_largest_length_is_rejected() {
+        for message_type in [MessageType::ChatUpdate as u32, 9999] {
+            let frame = header(message_type, u32::MAX);
+            let mut reader = &frame[..];
+            let result = Message::iter(&mut reader, &FrameLimits::default()).next();
+            assert!(matches!(
+                result,
+                Some(Err(DecodeError::Oversize { length, .. })) if length == u32::MAX as usize
+            ));
+            assert!(!result.unwrap().unwrap_err().is_recoverable());
+        }
+    }
+
+    #[test]
+    fn a_truncated_frame_is_an_error() {
+        let mut frame = Cpp::default()
+            .string("hello")
+            .frame(MessageType::ServerShutdown);
+        frame.truncate(frame.len() - 2);
+        let limits = FrameLimits::default();
+        for end in 1..frame.len() {
+            let mut reader = &frame[..end];
+            let mut messages = Message::iter(&mut reader, &limits);
+            assert!(
+                matches!(
+                    messages.next(),
+                    Some(Err(DecodeError::Io(e))) if e.kind() == ErrorKind::UnexpectedEof
+                ),
+                "cut after {end} bytes"
+            );
+            assert!(messages.next().is_none());
+        }
+    }
+
+    #[test]
+    fn a_truncated_payload_is_an_error() {
+        // The count says two strings, only one follows.
+        let frame = Cpp::default()
+            .string("a")
+            .u8(2)
+            .frame(MessageType::StubMessage);
+        let mut reader = &frame[..];
+        let message = Message::iter(&mut reader, &FrameLimits::default())
+            .next()
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            ClientPacket::decode(message, HANDSHAKE_PROTOCOL_VERSION),
+            Err(DecodeError::Truncated(MessageType::StubMessage))
+        ));
+    }
+
+    #[test]
+    fn unknown_types_are_skipped_and_the_stream_stays_aligned() {
+        let mut stream = header(9999, 3);
+        stream.extend_from_slice(b"abc");
+        stream.extend(Cpp::default().u32(5).frame(MessageType::Pong));
+        let mut reader = &stream[..];
+        let results: Vec<_> = Message::iter(&mut reader, &FrameLimits::default()).collect();
+        assert!(matches!(results[0], Err(DecodeError::UnknownType(9999))));
+        let pong = results[1].as_ref().unwrap().clone();
+        assert_eq!(
+            ClientPacket::decode(pong, HANDSHAKE_PROTOCOL_VERSION).unwrap(),
+            ClientPacket::Pong { nonce: 5 }
+        );
+        assert_eq!(results.len(), 2);
+    }
+
+    fn status_update(len: usize) -> ServerPacket {
+        ServerPacket::UserStatusUpdate {
+            users: (0..len)
+                .map(|i| UserStatusChange {
+                    status: UserStatus::Ready,
+                    user: UserId::new(i as i32 + 1),
+                    name: String::new(),
+                })
+                .collect(),
+        }
+    }
+
+    fn chat_update(len: usize) -> ServerPacket {
+        ServerPacket::ChatUpdate {
+            messages: (0..len)
+                .map(|i| ChatLine {
+                    user: UserId::new(1),
+                    text: i.to_string(),
+                    name: String::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn varint_counts_carry_more_than_255_entries() {
+        for len in [255, 256, 70_000] {
+            for packet in [status_update(len), chat_update(len)] {
+                for version in VARINT_COUNTS_PROTOCOL_VERSION..=*SUPPORTED_PROTOCOL_VERSIONS.end() {
+                    let decoded = ServerPacket::decode(packet.encode(version), version).unwrap();
+                    assert!(decoded == packet, "{len} entries on protocol {version}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn u8_counts_keep_the_last_255_entries() {
+        for version in HANDSHAKE_PROTOCOL_VERSION..VARINT_COUNTS_PROTOCOL_VERSION {
+            let decoded = ServerPacket::decode(chat_update(300).encode(version), version);
+            let ServerPacket::ChatUpdate { messages } = decoded.unwrap() else {
+                panic!("not a chat update");
+            };
+            assert_eq!(messages.len(), 255);
+            assert_eq!(messages[0].text, "45");
+            assert_eq!(messages[254].text, "299");
+        }
+    }
+
+    #[test]
+    fn varints_are_as_short_as_they_can_be() {
+        for (len, expected) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (300, &[0xac, 0x02]),
+            (70_000, &[0xf0, 0xa2, 0x04]),
+            (u32::MAX as usize, &[0xff, 0xff, 0xff, 0xff, 0x0f]),
+        ] {
+            let mut writer =
+                MessageWriter::new(MessageType::StubMessage, VARINT_COUNTS_PROTOCOL_VERSION);
+            writer.write_len(len);
+            let message = writer.finish();
+            assert_eq!(message.data, expected, "{len}");
+            let mut reader = MessageReader::new(&message, VARINT_COUNTS_PROTOCOL_VERSION);
+            assert_eq!(reader.read_len().unwrap(), len);
+        }
+    }
+
+    #[test]
+    fn over_long_varints_are_rejected() {
+        for count in [
+            // More than five bytes.
+            &[0x80, 0x80, 0x80, 0x80, 0x80, 0x00][..],
+            // Five bytes, but more than 32 bits.
+            &[0xff, 0xff, 0xff, 0xff, 0x1f],
+            // Cut short.
+            &[0x80, 0x80],
+        ] {
+            let mut message = Message::new(MessageType::UserStatusUpdate);
+            message.data.extend_from_slice(count);
+            let decoded = ServerPacket::decode(message, VARINT_COUNTS_PROTOCOL_VERSION);
+            assert!(
+                matches!(
+                    decoded,
+                    Err(DecodeError::InvalidValue(MessageType::UserStatusUpdate)
+                        | DecodeError::Truncated(MessageType::UserStatusUpdate))
+                ),
+                "{count:x?}"
+            );
         }
     }
 }
@@ -0,0 +1,345 @@
+//! Every message of the protocol and the fields it carries, in the order they
+//! are read. This is the one place the wire format is spelled out: the packet
+//! enums and their encoders are generated from it, and so is the client's
+//! `protocol.hpp` (`cargo run --bin protogen`).
+
+use super::{
+    wire::{EnumSchema, StructSchema},
+    NAMES_PROTOCOL_VERSION, SESSION_PROTOCOL_VERSION,
+};
+use crate::user_id::UserId;
+
+message_types! {
+    ConnectionRequested = 1,
+    ConnectionAccepted = 2,
+    ConnectionRejected = 3,
+    UserStatusUpdate = 4,
+    ReadyToStartChanged = 5,
+    ReadyToStart = 6,
+    StubMessage = 7,
+    GameAboutToStart = 8,
+    GameStarting = 9,
+    ChatUpdate = 10,
+    ProtocolError = 11,
+    Ping = 12,
+    Pong = 13,
+    ServerShutdown = 14,
+    Reconnect = 15,
+    Kicked = 16,
+    CreateRoom = 17,
+    ListRooms = 18,
+    JoinRoom = 19,
+    LeaveRoom = 20,
+    RoomList = 21,
+    RoomJoined = 22,
+    RoomLeft = 23,
+    RoomRequestFailed = 24,
+    FindMatch = 25,
+    CancelMatch = 26,
+    MatchmakingStatus = 27,
+    KickPlayer = 28,
+    LockLobby = 29,
+    TransferHost = 30,
+    ForceStart = 31,
+    ChangeLobbySettings = 32,
+    LobbyUpdate = 33,
+    LobbyRequestFailed = 34,
+}
+
+wire_enum! {
+    pub enum RejectionReason {
+        UnsupportedVersion = 1,
+        UnknownSession = 2,
+        GameInProgress = 3,
+        Banned = 4,
+        LobbyFull = 5,
+    }
+}
+
+wire_enum! {
+    pub enum UserStatus {
+        NotReady = 0,
+        Ready = 1,
+        Disconnected = 2,
+    }
+}
+
+wire_enum! {
+    pub enum ProtocolErrorCode {
+        Io = 0,
+        UnknownType = 1,
+        UnexpectedType = 2,
+        Truncated = 3,
+        BadUtf8 = 4,
+        InvalidValue = 5,
+        Oversize = 6,
+    }
+}
+
+wire_enum! {
+    pub enum KickReason {
+        RateLimited = 1,
+        HandshakeTimeout = 2,
+        ReadTimeout = 3,
+        LobbyIdle = 4,
+        ByHost = 5,
+        IdleTimeout = 6,
+    }
+}
+
+wire_enum! {
+    pub enum RoomVisibility {
+        Public = 0,
+        Private = 1,
+    }
+}
+
+wire_enum! {
+    pub enum RoomState {
+        Open = 0,
+        Starting = 1,
+        InProgress = 2,
+        Locked = 3,
+    }
+}
+
+wire_enum! {
+    pub enum RoomRequestError {
+        UnknownRoom = 1,
+        WrongPassword = 2,
+        GameInProgress = 3,
+        AlreadyInRoom = 4,
+        NotInRoom = 5,
+        InvalidMatch = 6,
+        NotQueued = 7,
+        RoomLocked = 8,
+        RoomFull = 9,
+        NoRoomLeft = 10,
+    }
+}
+
+wire_enum! {
+    pub enum LobbyRequestError {
+        NotHost = 1,
+        UnknownUser = 2,
+        InvalidSettings = 3,
+    }
+}
+
+wire_enum! {
+    pub enum MatchmakingState {
+        Searching = 0,
+        Cancelled = 1,
+    }
+}
+
+wire_struct! {
+    pub struct UserReady {
+        ready: bool,
+        user: UserId,
+        #[since(NAMES_PROTOCOL_VERSION)]
+        name: String,
+    }
+}
+
+wire_struct! {
+    pub struct UserStatusChange {
+        status: UserStatus,
+        user: UserId,
+        #[since(NAMES_PROTOCOL_VERSION)]
+        name: String,
+    }
+}
+
+wire_struct! {
+    /// Lines from [`UserId::SERVER`] are from the server itself.
+    pub struct ChatLine {
+        user: UserId,
+        text: String,
+        #[since(NAMES_PROTOCOL_VERSION)]
+        name: String,
+    }
+}
+
+wire_struct! {
+    /// A room as listed to users looking for one to join.
+    pub struct RoomInfo {
+        code: String,
+        players: u16,
+        state: RoomState,
+        has_password: bool,
+    }
+}
+
+packets! {
+    /// Messages sent by the client to the server.
+    ///
+    /// A client opens a connection with either `ConnectionRequested` or, to
+    /// come back as the user it was, `Reconnect` with the session it was given.
+    ///
+    /// Since protocol 7 a new user is in no room until it sends `CreateRoom` or
+    /// `JoinRoom`, or is put in one by `FindMatch`, see [`crate::room`]. An
+    /// empty `password` is no password, and `rating` is the client's own as
+    /// the server keeps none.
+    ///
+    /// Since protocol 8 the host of a lobby, whoever was accepted first, may
+    /// send `KickPlayer`, `LockLobby`, `TransferHost`, `ForceStart` and
+    /// `ChangeLobbySettings`.
+    pub enum ClientPacket {
+        /// `name` is the one the user would like to be shown by. See
+        /// [`crate::names`] for what they get. The fields are those of the
+        /// version it asks for, not of the one the connection has so far.
+        ConnectionRequested {
+            protocol_version: u16,
+            client_build: String,
+            #[since(NAMES_PROTOCOL_VERSION)]
+            name: String,
+        },
+        ReadyToStartChanged {
+            ready: bool,
+        },
+        StubMessage {
+            strings: Vec<String>,
+        },
+        ChatUpdate {
+            messages: Vec<String>,
+        },
+        Pong {
+            nonce: u32,
+        },
+        Reconnect {
+            protocol_version: u16,
+            session: String,
+        },
+        CreateRoom {
+            visibility: RoomVisibility,
+            password: String,
+        },
+        ListRooms,
+        JoinRoom {
+            code: String,
+            password: String,
+        },
+        LeaveRoom,
+        FindMatch {
+            players: u8,
+            rating: u16,
+        },
+        CancelMatch,
+        KickPlayer {
+            user: UserId,
+        },
+        LockLobby {
+            locked: bool,
+        },
+        TransferHost {
+            user: UserId,
+        },
+        ForceStart,
+        ChangeLobbySettings {
+            countdown: u8,
+        },
+    }
+}
+
+packets! {
+    /// Messages sent by the server to the client.
+    ///
+    /// `ChatUpdate` shares its `MessageType` with [`ClientPacket::ChatUpdate`],
+    /// but the server side also carries the author of every line.
+    ///
+    /// `ConnectionRejected`, `ServerShutdown` and `Kicked` are always the last
+    /// frame written to a connection.
+    pub enum ServerPacket {
+        /// The game leaves `session` empty, it is filled in by the network thread.
+        ConnectionAccepted {
+            you: UserId,
+            users: Vec<UserReady>,
+            #[since(SESSION_PROTOCOL_VERSION)]
+            session: String,
+        },
+        ConnectionRejected {
+            reason: RejectionReason,
+            min_version: u16,
+            max_version: u16,
+        },
+        UserStatusUpdate {
+            users: Vec<UserStatusChange>,
+        },
+        ReadyToStart,
+        StubMessage {
+            strings: Vec<String>,
+        },
+        GameAboutToStart {
+            seconds_left: u8,
+        },
+        GameStarting,
+        ChatUpdate {
+            messages: Vec<ChatLine>,
+        },
+        ProtocolError {
+            rejected_type: u32,
+            code: ProtocolErrorCode,
+            details: String,
+        },
+        Ping {
+            nonce: u32,
+        },
+        ServerShutdown {
+            reason: String,
+        },
+        /// `details` says what the user did or did not do, for people to read.
+        Kicked {
+            reason: KickReason,
+            details: String,
+        },
+        /// Only public rooms are listed.
+        RoomList {
+            rooms: Vec<RoomInfo>,
+        },
+        /// Followed by `ConnectionAccepted` from the room's game.
+        RoomJoined {
+            code: String,
+        },
+        RoomLeft,
+        RoomRequestFailed {
+            reason: RoomRequestError,
+        },
+        /// Sent now and then while searching. A match found is a `RoomJoined`.
+        MatchmakingStatus {
+            state: MatchmakingState,
+            waited_seconds: u16,
+            tolerance: u16,
+            queued: u16,
+        },
+        /// Sent to everybody in a lobby whenever any of it changes, and to
+        /// whoever is accepted into it.
+        LobbyUpdate {
+            host: UserId,
+            locked: bool,
+            countdown: u8,
+        },
+        LobbyRequestFailed {
+            reason: LobbyRequestError,
+        },
+    }
+}
+
+pub const ENUMS: &[EnumSchema] = &[
+    RejectionReason::SCHEMA,
+    UserStatus::SCHEMA,
+    ProtocolErrorCode::SCHEMA,
+    KickReason::SCHEMA,
+    RoomVisibility::SCHEMA,
+    RoomState::SCHEMA,
+    RoomRequestError::SCHEMA,
+    MatchmakingState::SCHEMA,
+    LobbyRequestError::SCHEMA,
+];
+
+pub const STRUCTS: &[StructSchema] = &[
+    UserReady::SCHEMA,
+    UserStatusChange::SCHEMA,
+    ChatLine::SCHEMA,
+    RoomInfo::SCHEMA,
+];
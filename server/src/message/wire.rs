@@ -0,0 +1,337 @@
+//! The building blocks [`super::schema`] is written in. Every type that can
+//! appear in a payload implements [`Wire`], and the macros below turn a
+//! declaration into the Rust type, its [`Wire`] impl and a description of its
+//! layout that `protogen` renders into the client header.
+//!
+//! A field marked `#[since(VERSION)]` is only in the payloads of that protocol
+//! version and later. Older payloads leave it out, and it reads back as its
+//! default.
+
+use super::{DecodeError, MessageType, PayloadReader, PayloadWriter};
+
+/// A value with a fixed way of being written to and read from a payload.
+pub trait Wire: Sized {
+    fn write_to<W: PayloadWriter>(&self, writer: &mut W);
+
+    fn read_from<R: PayloadReader>(reader: &mut R) -> Result<Self, DecodeError>;
+}
+
+macro_rules! wire_pod {
+    ($($ty:ty),*) => {
+        $(
+            impl Wire for $ty {
+                fn write_to<W: PayloadWriter>(&self, writer: &mut W) {
+                    writer.write(self);
+                }
+
+                fn read_from<R: PayloadReader>(reader: &mut R) -> Result<Self, DecodeError> {
+                    reader.read()
+                }
+            }
+        )*
+    };
+}
+
+wire_pod!(u8, u16, u32, i32);
+
+/// Written as a `u8`, anything but 0 reads back as `true`.
+impl Wire for bool {
+    fn write_to<W: PayloadWriter>(&self, writer: &mut W) {
+        writer.write(&(*self as u8));
+    }
+
+    fn read_from<R: PayloadReader>(reader: &mut R) -> Result<Self, DecodeError> {
+        Ok(reader.read::<u8>()? != 0)
+    }
+}
+
+impl Wire for String {
+    fn write_to<W: PayloadWriter>(&self, writer: &mut W) {
+        writer.write_string(self);
+    }
+
+    fn read_from<R: PayloadReader>(reader: &mut R) -> Result<Self, DecodeError> {
+        reader.read_string()
+    }
+}
+
+/// A count followed by the items, see [`PayloadWriter::write_collection`].
+impl<T: Wire> Wire for Vec<T> {
+    fn write_to<W: PayloadWriter>(&self, writer: &mut W) {
+        writer.write_collection(self, |writer, item| item.write_to(writer));
+    }
+
+    fn read_from<R: PayloadReader>(reader: &mut R) -> Result<Self, DecodeError> {
+        reader.read_collection(|reader| T::read_from(reader))
+    }
+}
+
+/// A field of a packet or struct as written in the schema.
+#[derive(Debug)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    /// The Rust type.
+    pub ty: &'static str,
+    /// The first protocol version that has the field, `None` if they all do.
+    pub since: Option<u16>,
+}
+
+#[derive(Debug)]
+pub struct PacketSchema {
+    pub message_type: MessageType,
+    pub fields: &'static [FieldSchema],
+}
+
+#[derive(Debug)]
+pub struct StructSchema {
+    pub name: &'static str,
+    pub fields: &'static [FieldSchema],
+}
+
+#[derive(Debug)]
+pub struct EnumSchema {
+    pub name: &'static str,
+    pub variants: &'static [(&'static str, u8)],
+}
+
+/// The `since` of a [`FieldSchema`], from the field's optional `#[since]`.
+macro_rules! since {
+    () => {
+        None
+    };
+    ($since:expr) => {
+        Some($since)
+    };
+}
+
+/// Declares `MessageType` and its conversion from the raw frame header.
+macro_rules! message_types {
+    ($($variant:ident = $value:literal),* $(,)?) => {
+        #[repr(u32)] // Ensure that the enum is represented as an u32
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+        pub enum MessageType {
+            $($variant = $value),*
+        }
+
+        impl MessageType {
+            pub const ALL: &'static [MessageType] = &[$(MessageType::$variant),*];
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(MessageType::$variant => stringify!($variant)),*
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<MessageType> {
+                match name {
+                    $(stringify!($variant) => Some(MessageType::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl TryFrom<u32> for MessageType {
+            type Error = $crate::message::DecodeError;
+
+            fn try_from(value: u32) -> Result<MessageType, $crate::message::DecodeError> {
+                match value {
+                    $($value => Ok(MessageType::$variant),)*
+                    _ => Err($crate::message::DecodeError::UnknownType(value)),
+                }
+            }
+        }
+    };
+}
+
+/// Declares a fieldless enum that goes over the wire as a `u8`. Unknown
+/// values are reported as [`DecodeError::InvalidValue`].
+macro_rules! wire_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($variant:ident = $value:literal),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[repr(u8)]
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+        pub enum $name {
+            $($variant = $value),*
+        }
+
+        impl $name {
+            pub const SCHEMA: $crate::message::wire::EnumSchema =
+                $crate::message::wire::EnumSchema {
+                    name: stringify!($name),
+                    variants: &[$((stringify!($variant), $value)),*],
+                };
+
+            pub fn from_u8(value: u8) -> Option<$name> {
+                match value {
+                    $($value => Some($name::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl $crate::message::wire::Wire for $name {
+            fn write_to<W: $crate::message::PayloadWriter>(&self, writer: &mut W) {
+                writer.write(&(*self as u8));
+            }
+
+            fn read_from<R: $crate::message::PayloadReader>(
+                reader: &mut R,
+            ) -> Result<Self, $crate::message::DecodeError> {
+                let value = reader.read()?;
+                $name::from_u8(value)
+                    .ok_or($crate::message::DecodeError::InvalidValue(reader.message_type()))
+            }
+        }
+    };
+}
+
+/// Declares a struct whose fields are written one after another, in the
+/// order they are declared.
+macro_rules! wire_struct {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $($(#[since($since:expr)])? $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        pub struct $name {
+            $(
+                $(
+                    #[doc = concat!("Only sent since protocol [`", stringify!($since), "`].")]
+                    #[serde(default)]
+                )?
+                pub $field: $ty
+            ),*
+        }
+
+        impl $name {
+            pub const SCHEMA: $crate::message::wire::StructSchema =
+                $crate::message::wire::StructSchema {
+                    name: stringify!($name),
+                    fields: &[$($crate::message::wire::FieldSchema {
+                        name: stringify!($field),
+                        ty: stringify!($ty),
+                        since: since!($($since)?),
+                    }),*],
+                };
+        }
+
+        impl $crate::message::wire::Wire for $name {
+            fn write_to<W: $crate::message::PayloadWriter>(&self, writer: &mut W) {
+                $(
+                    $(if writer.protocol_version() >= $since)? {
+                        $crate::message::wire::Wire::write_to(&self.$field, writer);
+                    }
+                )*
+            }
+
+            fn read_from<R: $crate::message::PayloadReader>(
+                reader: &mut R,
+            ) -> Result<Self, $crate::message::DecodeError> {
+                Ok($name {
+                    $(
+                        $field: $(if reader.protocol_version() < $since {
+                            Default::default()
+                        } else)? {
+                            $crate::message::wire::Wire::read_from(reader)?
+                        }
+                    ),*
+                })
+            }
+        }
+    };
+}
+
+/// Declares one direction's packet enum. Every variant is named after its
+/// `MessageType`, and its fields are written in the order they are declared,
+/// as in [`wire_struct`]. The JSON form is an object whose `type` is the
+/// variant name; fields newer than some clients may be left out of it.
+macro_rules! packets {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $(
+                $(#[$variant_meta:meta])*
+                $variant:ident $({ $($(#[since($since:expr)])? $field:ident: $ty:ty),* $(,)? })?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        #[serde(tag = "type")]
+        pub enum $name {
+            $(
+                $(#[$variant_meta])*
+                $variant $({
+                    $(
+                        $(
+                            #[doc = concat!("Only sent since protocol [`", stringify!($since), "`].")]
+                            #[serde(default)]
+                        )?
+                        $field: $ty
+                    ),*
+                })?
+            ),*
+        }
+
+        impl $name {
+            pub const SCHEMA: &'static [$crate::message::wire::PacketSchema] = &[$(
+                $crate::message::wire::PacketSchema {
+                    message_type: MessageType::$variant,
+                    fields: &[$($($crate::message::wire::FieldSchema {
+                        name: stringify!($field),
+                        ty: stringify!($ty),
+                        since: since!($($since)?),
+                    }),*)?],
+                }
+            ),*];
+
+            pub fn message_type(&self) -> MessageType {
+                match self {
+                    $($name::$variant { .. } => MessageType::$variant),*
+                }
+            }
+
+            pub(crate) fn write_payload<W: $crate::message::PayloadWriter>(&self, writer: &mut W) {
+                match self {
+                    $(
+                        $name::$variant $({ $($field),* })? => {
+                            $($(
+                                $(if writer.protocol_version() >= $since)? {
+                                    $crate::message::wire::Wire::write_to($field, writer);
+                                }
+                            )*)?
+                        }
+                    )*
+                }
+            }
+
+            pub(crate) fn read_payload<R: $crate::message::PayloadReader>(
+                message_type: MessageType,
+                reader: &mut R,
+            ) -> Result<$name, $crate::message::DecodeError> {
+                match message_type {
+                    $(
+                        MessageType::$variant => Ok($name::$variant $({
+                            $(
+                                $field: $(if reader.protocol_version() < $since {
+                                    Default::default()
+                                } else)? {
+                                    $crate::message::wire::Wire::read_from(reader)?
+                                }
+                            ),*
+                        })?),
+                    )*
+                    message_type => Err($crate::message::DecodeError::UnexpectedType(message_type)),
+                }
+            }
+        }
+    };
+}
@@ -0,0 +1,175 @@
+//! Display names, what users are shown to each other by instead of their ids.
+//!
+//! A user asks for a name when they connect. What they get is that name cut
+//! down to what may be shown: at most [`MAX_NAME_LENGTH`] letters, digits, `_`
+//! and `-`. It is also made unique among everybody on the server, ignoring
+//! case, by a number at its end, so asking for `ann` may well get `ann2`.
+//! Users that ask for nothing, like clients older than protocol 9, are called
+//! `player`.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::user_id::UserId;
+
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// What the server's own chat lines are signed with, never a user's name.
+pub const SERVER_NAME: &str = "server";
+
+const DEFAULT_NAME: &str = "player";
+
+#[derive(Debug)]
+pub struct Names {
+    user_to_name: HashMap<UserId, String>,
+    /// Every name given out, in lowercase.
+    taken: HashSet<String>,
+}
+
+impl Names {
+    pub fn new() -> Self {
+        Names {
+            user_to_name: HashMap::new(),
+            taken: HashSet::from([SERVER_NAME.to_string()]),
+        }
+    }
+
+    /// Gives the user the name closest to `requested` that nobody else has,
+    /// in place of the one they had.
+    pub fn claim(&mut self, user: UserId, requested: &str) -> &str {
+        self.release(&user);
+        let base = clean(requested);
+        let name = (1..)
+            .map(|n| numbered(&base, n))
+            .find(|name| !self.taken.contains(&name.to_lowercase()))
+            .unwrap();
+        self.taken.insert(name.to_lowercase());
+        self.user_to_name.entry(user).or_insert(name)
+    }
+
+    pub fn release(&mut self, user: &UserId) {
+        if let Some(name) = self.user_to_name.remove(user) {
+            self.taken.remove(&name.to_lowercase());
+        }
+    }
+
+    /// Lets the names of everybody else be taken again.
+    pub fn retain<F: FnMut(&UserId) -> bool>(&mut self, mut keep: F) {
+        let released: Vec<UserId> = self
+            .user_to_name
+            .keys()
+            .filter(|user| !keep(user))
+            .copied()
+            .collect();
+        for user in released {
+            self.release(&user);
+        }
+    }
+
+    /// Empty for users that have not connected or are gone.
+    pub fn of(&self, user: &UserId) -> String {
+        if *user == UserId::SERVER {
+            return SERVER_NAME.to_string();
+        }
+        self.user_to_name.get(user).cloned().unwrap_or_default()
+    }
+}
+
+impl Default for Names {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn clean(requested: &str) -> String {
+    let name: String = requested
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    if name.is_empty() {
+        DEFAULT_NAME.to_string()
+    } else {
+        name
+    }
+}
+
+/// The first is the name itself, then `name2`, `name3`... cut short to keep
+/// within [`MAX_NAME_LENGTH`].
+fn numbered(base: &str, n: usize) -> String {
+    if n == 1 {
+        return base.to_string();
+    }
+    let suffix = n.to_string();
+    let mut name: String = base.chars().take(MAX_NAME_LENGTH - suffix.len()).collect();
+    name.push_str(&suffix);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(names: &mut Names, user: i32, requested: &str) -> String {
+        names.claim(UserId::new(user), requested).to_string()
+    }
+
+    #[test]
+    fn whitespace_control_characters_and_symbols_are_left_out() {
+        let mut names = Names::new();
+        assert_eq!(claim(&mut names, 1, " ann  lee\t\n\u{7}!"), "annlee");
+        assert_eq!(claim(&mut names, 2, "björn_o-k"), "bjrn_o-k");
+    }
+
+    #[test]
+    fn long_names_are_cut_short() {
+        let mut names = Names::new();
+        let long = "a".repeat(MAX_NAME_LENGTH + 5);
+        assert_eq!(claim(&mut names, 1, &long), "a".repeat(MAX_NAME_LENGTH));
+        // The number of a duplicate still fits.
+        let second = claim(&mut names, 2, &long);
+        assert_eq!(second, format!("{}2", "a".repeat(MAX_NAME_LENGTH - 1)));
+    }
+
+    #[test]
+    fn users_that_ask_for_nothing_shown_are_called_player() {
+        let mut names = Names::new();
+        assert_eq!(claim(&mut names, 1, ""), "player");
+        assert_eq!(claim(&mut names, 2, " ?! "), "player2");
+    }
+
+    #[test]
+    fn duplicates_are_numbered_ignoring_case() {
+        let mut names = Names::new();
+        assert_eq!(claim(&mut names, 1, "ann"), "ann");
+        assert_eq!(claim(&mut names, 2, "ANN"), "ANN2");
+        assert_eq!(claim(&mut names, 3, "Ann"), "Ann3");
+        assert_eq!(names.of(&UserId::new(2)), "ANN2");
+    }
+
+    #[test]
+    fn nobody_is_called_server() {
+        let mut names = Names::new();
+        assert_eq!(claim(&mut names, 1, "Server"), "Server2");
+        assert_eq!(names.of(&UserId::SERVER), SERVER_NAME);
+    }
+
+    #[test]
+    fn names_of_users_not_kept_can_be_taken_again() {
+        let mut names = Names::new();
+        claim(&mut names, 1, "ann");
+        claim(&mut names, 2, "bob");
+        names.retain(|user| *user == UserId::new(2));
+        assert_eq!(names.of(&UserId::new(1)), "");
+        assert_eq!(claim(&mut names, 3, "ann"), "ann");
+        assert_eq!(claim(&mut names, 4, "bob"), "bob2");
+    }
+
+    #[test]
+    fn a_new_name_frees_the_old_one() {
+        let mut names = Names::new();
+        claim(&mut names, 1, "ann");
+        assert_eq!(claim(&mut names, 1, "ann"), "ann");
+        claim(&mut names, 1, "bob");
+        assert_eq!(claim(&mut names, 2, "ann"), "ann");
+    }
+}
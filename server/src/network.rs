@@ -0,0 +1,1356 @@
+//! All sockets live on one thread, driven by a `mio` poll.
+//!
+//! The game loop never sees a socket: every user still has a channel of
+//! decoded [`ClientPacket`]s and a channel of [`ServerPacket`]s in [`Users`].
+//! The network thread fills the former as frames arrive and drains the latter
+//! when woken by [`NetworkHandle::wake`].
+
+use std::{
+    collections::HashMap,
+    io::{Cursor, ErrorKind, Read, Write},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use mio::{
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Token, Waker,
+};
+
+use crate::{
+    heartbeat::Heartbeat,
+    json::{self, JsonPacketIterator},
+    message::{
+        ChatLine, ClientPacket, DecodeError, KickReason, Message, MessageType, RejectionReason,
+        ServerPacket, HANDSHAKE_PROTOCOL_VERSION, HEARTBEAT_PROTOCOL_VERSION,
+        SESSION_PROTOCOL_VERSION, SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    names::SERVER_NAME,
+    outbound::{outbound_queue, OutboundReceiver, OutboundSender},
+    rate_limit::{RateLimitAction, RateLimiter},
+    session::Sessions,
+    user_id::UserId,
+    Codec, Connection, MalformedFramePolicy, Protocol, Users,
+};
+
+const WAKER: Token = Token(0);
+
+/// How long the network thread sleeps when nothing happens, so that sessions
+/// expire on time.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+const READ_CHUNK: usize = 4096;
+
+/// How often what was recorded to the capture file is written out.
+const CAPTURE_FLUSH_EVERY: Duration = Duration::from_secs(1);
+
+/// Encoded bytes held for a peer before packets are left in its
+/// [`OutboundReceiver`], where they are subject to its overflow policy.
+const OUTBOUND_BUFFER_LIMIT: usize = 64 * 1024;
+
+enum NetworkCommand {
+    Disconnect(UserId),
+    /// Stop accepting, close every connection once what was queued for it is
+    /// written, then answer and exit.
+    Shutdown(mpsc::Sender<()>),
+}
+
+/// Lets other threads reach the network thread.
+#[derive(Clone)]
+pub struct NetworkHandle {
+    waker: Arc<Waker>,
+    commands: mpsc::Sender<NetworkCommand>,
+}
+
+impl NetworkHandle {
+    /// Asks the network thread to send whatever was queued for the users.
+    pub fn wake(&self) {
+        let _ = self.waker.wake();
+    }
+
+    /// Closes the user's connection. The user is removed from [`Users`] by the
+    /// network thread once it is closed.
+    pub fn disconnect(&self, user: UserId) {
+        let _ = self.commands.send(NetworkCommand::Disconnect(user));
+        self.wake();
+    }
+
+    /// Closes every connection after sending what was already queued for it.
+    /// The returned channel is answered once they are all closed.
+    pub fn shutdown(&self) -> mpsc::Receiver<()> {
+        let (done_sender, done) = mpsc::channel();
+        let _ = self.commands.send(NetworkCommand::Shutdown(done_sender));
+        self.wake();
+        done
+    }
+}
+
+/// Starts the thread that owns every socket, accepting on each listener with its codec.
+pub fn spawn_network_thread(
+    listeners: Vec<(std::net::TcpListener, Codec)>,
+    users: Arc<Mutex<Users>>,
+    protocol: Arc<Protocol>,
+) -> NetworkHandle {
+    let poll = Poll::new().unwrap();
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER).unwrap());
+    let (commands_sender, commands) = mpsc::channel();
+    let handle = NetworkHandle {
+        waker,
+        commands: commands_sender,
+    };
+
+    let listeners = listeners
+        .into_iter()
+        .enumerate()
+        .map(|(index, (listener, codec))| {
+            listener.set_nonblocking(true).unwrap();
+            let mut listener = TcpListener::from_std(listener);
+            poll.registry()
+                .register(&mut listener, Token(index + 1), Interest::READABLE)
+                .unwrap();
+            Some((listener, codec))
+        })
+        .collect::<Vec<_>>();
+
+    users.lock().unwrap().network = Some(handle.clone());
+
+    let mut network = Network {
+        poll,
+        next_token: listeners.len() + 1,
+        listeners,
+        peers: HashMap::new(),
+        user_to_token: HashMap::new(),
+        commands,
+        handle: handle.clone(),
+        users,
+        backlog: false,
+        sessions: Sessions::new(protocol.config.reconnect_grace),
+        bans: HashMap::new(),
+        protocol,
+        shutdown: None,
+        capture_flushed: Instant::now(),
+    };
+    thread::spawn(move || network.run());
+
+    handle
+}
+
+struct Network {
+    poll: Poll,
+    /// Indexed by token minus one, `None` once accepting has stopped.
+    listeners: Vec<Option<(TcpListener, Codec)>>,
+    next_token: usize,
+    peers: HashMap<Token, Peer>,
+    user_to_token: HashMap<UserId, Token>,
+    commands: mpsc::Receiver<NetworkCommand>,
+    handle: NetworkHandle,
+    users: Arc<Mutex<Users>>,
+    /// Set when a listener may have connections that were left waiting.
+    backlog: bool,
+    sessions: Sessions,
+    /// Addresses refused until the given time for flooding, see [`crate::rate_limit`].
+    bans: HashMap<IpAddr, Instant>,
+    protocol: Arc<Protocol>,
+    /// Who to tell once every connection is closed, set when shutting down.
+    shutdown: Option<mpsc::Sender<()>>,
+    capture_flushed: Instant,
+}
+
+/// One user's socket and what has been read from or is waiting to be written to it.
+struct Peer {
+    user_id: UserId,
+    stream: TcpStream,
+    address: IpAddr,
+    codec: Codec,
+    protocol_version: Arc<AtomicU16>,
+    read_sender: mpsc::Sender<ClientPacket>,
+    write_receiver: OutboundReceiver,
+    inbound: Vec<u8>,
+    outbound: Vec<u8>,
+    /// Set once nothing more is accepted from the peer. It is dropped as soon
+    /// as `outbound` is flushed.
+    closing: bool,
+    wants_writable: bool,
+    /// Set for connections accepted while new users are not: they may only
+    /// take over a user that is already in the game, anything else is
+    /// rejected for this reason.
+    refuse_new: Option<RejectionReason>,
+    limiter: RateLimiter,
+    connected: Instant,
+    /// Set once `ConnectionRequested` or `Reconnect` has been received.
+    handshaken: bool,
+    /// When the first bytes of a frame that is not complete yet arrived.
+    frame_started: Option<Instant>,
+    /// When anything was last read from the socket.
+    last_read: Instant,
+}
+
+impl Network {
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(IDLE_TIMEOUT)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                panic!("network poll failed: {e}");
+            }
+
+            for event in events.iter() {
+                let token = event.token();
+                if token == WAKER {
+                    continue;
+                }
+                if token.0 <= self.listeners.len() {
+                    self.backlog = true;
+                    continue;
+                }
+                if event.is_readable() || event.is_read_closed() || event.is_error() {
+                    self.read(token);
+                }
+            }
+
+            self.handle_commands();
+            self.accept();
+            self.expire_sessions();
+            self.enforce_timeouts();
+
+            // Outbound packets are only queued when the game loop wakes us,
+            // but draining every channel is cheap next to a syscall per user.
+            let tokens: Vec<Token> = self.peers.keys().copied().collect();
+            for token in tokens {
+                let peer = self.peers.get_mut(&token).unwrap();
+                peer.drain_outbound(&self.protocol, &mut self.sessions);
+                peer.flush();
+                if peer.closing && peer.outbound.is_empty() {
+                    self.close(token);
+                } else if peer.wants_writable == peer.outbound.is_empty() {
+                    peer.reregister(self.poll.registry(), token);
+                }
+            }
+
+            let stopping = self.peers.is_empty() && self.shutdown.is_some();
+            if let Some(capture) = &self.protocol.capture {
+                if stopping || self.capture_flushed.elapsed() >= CAPTURE_FLUSH_EVERY {
+                    capture.flush();
+                    self.capture_flushed = Instant::now();
+                }
+            }
+            if stopping {
+                let _ = self.shutdown.take().unwrap().send(());
+                return;
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        if self.shutdown.is_some() {
+            if self.listeners.iter().any(Option::is_some) {
+                println!("stopping accepting new users");
+                for listener in self.listeners.iter_mut() {
+                    if let Some((mut listener, _)) = listener.take() {
+                        let _ = self.poll.registry().deregister(&mut listener);
+                    }
+                }
+            }
+            return;
+        }
+        if !self.backlog {
+            return;
+        }
+        self.backlog = false;
+
+        for index in 0..self.listeners.len() {
+            while let Some((listener, codec)) = &self.listeners[index] {
+                let codec = *codec;
+                match listener.accept() {
+                    Ok((stream, address)) if self.is_banned(address.ip()) => {
+                        refuse(stream, codec, RejectionReason::Banned);
+                    }
+                    Ok((stream, address)) => {
+                        let refuse_new = self.refuse_new();
+                        self.add_peer(stream, address.ip(), codec, refuse_new);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        println!("failed to accept a connection: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Why a new connection could not be a new user, if it could not.
+    fn refuse_new(&self) -> Option<RejectionReason> {
+        // Users that are away keep their place for when they come back.
+        let players = self
+            .peers
+            .values()
+            .filter(|peer| peer.refuse_new.is_none())
+            .count()
+            + self.users.lock().unwrap().away.len();
+        (players >= self.protocol.config.max_players).then_some(RejectionReason::LobbyFull)
+    }
+
+    fn is_banned(&mut self, address: IpAddr) -> bool {
+        let now = Instant::now();
+        self.bans.retain(|_, until| *until > now);
+        self.bans.contains_key(&address)
+    }
+
+    fn add_peer(
+        &mut self,
+        mut stream: TcpStream,
+        address: IpAddr,
+        codec: Codec,
+        refuse_new: Option<RejectionReason>,
+    ) {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+
+        if let Err(e) = self
+            .poll
+            .registry()
+            .register(&mut stream, token, Interest::READABLE)
+        {
+            println!("failed to register a connection: {e}");
+            return;
+        }
+
+        let (write_sender, write_receiver) = outbound_queue(self.protocol.config.outbound_queue);
+        let (read_sender, read_receiver) = mpsc::channel();
+        let protocol_version = Arc::new(AtomicU16::new(HANDSHAKE_PROTOCOL_VERSION));
+
+        let user_id = add_user_to_users(&self.users, write_sender, read_receiver, |user| {
+            Connection {
+                user,
+                protocol_version: protocol_version.clone(),
+                heartbeat: Heartbeat::new(),
+                network: self.handle.clone(),
+            }
+        });
+        let Some(user_id) = user_id else {
+            println!("no user id left, closing the connection");
+            let _ = self.poll.registry().deregister(&mut stream);
+            return;
+        };
+
+        if let Some(capture) = &self.protocol.capture {
+            capture.connected(user_id);
+        }
+
+        self.user_to_token.insert(user_id, token);
+        self.peers.insert(
+            token,
+            Peer {
+                user_id,
+                stream,
+                address,
+                codec,
+                protocol_version,
+                read_sender,
+                write_receiver,
+                inbound: Vec::new(),
+                outbound: Vec::new(),
+                closing: false,
+                wants_writable: false,
+                refuse_new,
+                limiter: RateLimiter::new(&self.protocol.config.rate_limit, Instant::now()),
+                connected: Instant::now(),
+                handshaken: false,
+                frame_started: None,
+                last_read: Instant::now(),
+            },
+        );
+    }
+
+    fn handle_commands(&mut self) {
+        while let Ok(command) = self.commands.try_recv() {
+            match command {
+                NetworkCommand::Disconnect(user) => {
+                    if let Some(token) = self.user_to_token.get(&user).copied() {
+                        self.close(token);
+                    }
+                }
+                NetworkCommand::Shutdown(done) => {
+                    for peer in self.peers.values_mut() {
+                        peer.drain_outbound(&self.protocol, &mut self.sessions);
+                        peer.closing = true;
+                    }
+                    self.shutdown = Some(done);
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        let Some(mut peer) = self.peers.remove(&token) else {
+            return;
+        };
+        let _ = self.poll.registry().deregister(&mut peer.stream);
+        let _ = peer.stream.shutdown(std::net::Shutdown::Both);
+        self.user_to_token.remove(&peer.user_id);
+
+        let mut locked_users = self.users.lock().unwrap();
+        locked_users.user_to_write_sender.remove(&peer.user_id);
+        locked_users.user_to_read_receiver.remove(&peer.user_id);
+        locked_users.user_to_connection.remove(&peer.user_id);
+        locked_users.users.remove(&peer.user_id);
+        let away = self.shutdown.is_none() && self.sessions.leave(peer.user_id, Instant::now());
+        if away {
+            locked_users.away.insert(peer.user_id);
+        }
+        drop(locked_users);
+
+        if let Some(capture) = &self.protocol.capture {
+            capture.disconnected(peer.user_id);
+        }
+        if away {
+            println!(
+                "connection closed for {}, they may reconnect within {:?}",
+                peer.user_id,
+                self.sessions.grace()
+            );
+        } else {
+            println!("connection closed for {}", peer.user_id);
+        }
+    }
+
+    /// Reads what the peer sent and hands it to the game.
+    fn read(&mut self, token: Token) {
+        let Some(peer) = self.peers.get_mut(&token) else {
+            return;
+        };
+        for decoded in peer.receive(&self.protocol) {
+            let message_type = decoded.as_ref().ok().map(ClientPacket::message_type);
+            if !self.within_limits(token, message_type) {
+                let peer = self.peers.get_mut(&token).unwrap();
+                // Not answered, but a frame that misaligned the stream still ends it.
+                if decoded.is_err_and(|e| !e.is_recoverable()) {
+                    peer.closing = true;
+                }
+                if peer.closing {
+                    break;
+                }
+                continue;
+            }
+            match &decoded {
+                Ok(ClientPacket::Reconnect {
+                    protocol_version,
+                    session,
+                }) => {
+                    if !self.reattach(token, *protocol_version, session) {
+                        break;
+                    }
+                    self.peers.get_mut(&token).unwrap().handshaken = true;
+                }
+                Ok(ClientPacket::Pong { .. }) | Err(_) => {}
+                Ok(_) if self.peers[&token].refuse_new.is_some() => {
+                    let reason = self.peers[&token].refuse_new.unwrap();
+                    self.reject(token, reason);
+                    break;
+                }
+                Ok(ClientPacket::ConnectionRequested { .. }) => {
+                    self.peers.get_mut(&token).unwrap().handshaken = true;
+                }
+                Ok(_) => {}
+            }
+            let peer = self.peers.get_mut(&token).unwrap();
+            if !peer.handle(decoded, &self.users, &self.protocol) {
+                peer.closing = true;
+                break;
+            }
+        }
+    }
+
+    /// Returns whether a packet of `message_type` may be handed on, or a frame
+    /// that could not be decoded answered. A peer that sends too much is
+    /// warned, kicked or banned as configured.
+    fn within_limits(&mut self, token: Token, message_type: Option<MessageType>) -> bool {
+        let now = Instant::now();
+        let peer = self.peers.get_mut(&token).unwrap();
+        let Some((action, limit)) = peer.limiter.check(message_type, now) else {
+            return true;
+        };
+        match action {
+            RateLimitAction::Drop => {}
+            RateLimitAction::Warn => {
+                if peer.limiter.warning_due(now) {
+                    println!("warning {}: {limit}", peer.user_id);
+                    let warning = ChatLine {
+                        user: UserId::SERVER,
+                        text: format!("Slow down, you are sending {limit}."),
+                        name: SERVER_NAME.to_string(),
+                    };
+                    peer.queue(
+                        &ServerPacket::ChatUpdate {
+                            messages: vec![warning],
+                        },
+                        &self.protocol,
+                    );
+                }
+            }
+            RateLimitAction::Kick | RateLimitAction::Ban => {
+                let mut details = format!("sent {limit}");
+                if action == RateLimitAction::Ban {
+                    let ban = self.protocol.config.rate_limit.ban;
+                    self.bans.insert(peer.address, now + ban);
+                    details += &format!(", {} is banned for {ban:?}", peer.address);
+                }
+                self.kick(token, KickReason::RateLimited, details);
+            }
+        }
+        false
+    }
+
+    /// Tells the peer why it is dropped and closes it.
+    fn kick(&mut self, token: Token, reason: KickReason, details: String) {
+        let peer = self.peers.get_mut(&token).unwrap();
+        println!("kicking {}: {details}", peer.user_id);
+        self.sessions.revoke(peer.user_id);
+        peer.queue(&ServerPacket::Kicked { reason, details }, &self.protocol);
+        peer.closing = true;
+    }
+
+    /// Drops peers that take too long to say who they are or to finish a frame,
+    /// and peers too old to be pinged that have gone quiet: for them, silence
+    /// is the only sign of a connection that died without being closed.
+    fn enforce_timeouts(&mut self) {
+        let now = Instant::now();
+        let config = &self.protocol.config;
+        let mut expired = Vec::new();
+        for (token, peer) in self.peers.iter().filter(|(_, peer)| !peer.closing) {
+            if !peer.handshaken && now.duration_since(peer.connected) > config.handshake_timeout {
+                expired.push((
+                    *token,
+                    KickReason::HandshakeTimeout,
+                    format!(
+                        "did not send ConnectionRequested within {:?}",
+                        config.handshake_timeout
+                    ),
+                ));
+            } else if peer
+                .frame_started
+                .is_some_and(|started| now.duration_since(started) > config.read_timeout)
+            {
+                expired.push((
+                    *token,
+                    KickReason::ReadTimeout,
+                    format!("did not finish a frame within {:?}", config.read_timeout),
+                ));
+            } else if peer.protocol_version.load(Ordering::Acquire) < HEARTBEAT_PROTOCOL_VERSION
+                && now.duration_since(peer.last_read) > config.idle_timeout
+            {
+                expired.push((
+                    *token,
+                    KickReason::IdleTimeout,
+                    format!("sent nothing for {:?}", config.idle_timeout),
+                ));
+            }
+        }
+        for (token, reason, details) in expired {
+            self.kick(token, reason, details);
+        }
+    }
+
+    /// Moves the peer over to the user the session belongs to, so that the
+    /// game sees that user come back. Returns whether that worked; if not the
+    /// peer is told why and closed.
+    fn reattach(&mut self, token: Token, protocol_version: u16, session: &str) -> bool {
+        let rejection = if !SUPPORTED_PROTOCOL_VERSIONS.contains(&protocol_version) {
+            Some(RejectionReason::UnsupportedVersion)
+        } else if protocol_version < SESSION_PROTOCOL_VERSION {
+            Some(RejectionReason::UnknownSession)
+        } else {
+            None
+        };
+        let user = match (rejection, self.sessions.user(session)) {
+            (None, Some(user)) => user,
+            (rejection, _) => {
+                self.reject(token, rejection.unwrap_or(RejectionReason::UnknownSession));
+                return false;
+            }
+        };
+
+        // The old connection may not have noticed that it is gone yet.
+        if let Some(old) = self.user_to_token.get(&user).copied() {
+            if old == token {
+                return true;
+            }
+            self.close(old);
+        }
+        self.sessions.back(user);
+
+        let peer = self.peers.get_mut(&token).unwrap();
+        let previous = std::mem::replace(&mut peer.user_id, user);
+        let mut locked_users = self.users.lock().unwrap();
+        let Users {
+            user_to_write_sender,
+            user_to_read_receiver,
+            user_to_connection,
+            users,
+            away,
+            ..
+        } = &mut *locked_users;
+        if let Some(sender) = user_to_write_sender.remove(&previous) {
+            user_to_write_sender.insert(user, sender);
+        }
+        if let Some(receiver) = user_to_read_receiver.remove(&previous) {
+            user_to_read_receiver.insert(user, receiver);
+        }
+        if let Some(mut connection) = user_to_connection.remove(&previous) {
+            connection.user = user;
+            user_to_connection.insert(user, connection);
+        }
+        users.remove(&previous);
+        users.insert(user);
+        away.remove(&user);
+        drop(locked_users);
+
+        peer.refuse_new = None;
+        self.user_to_token.remove(&previous);
+        self.user_to_token.insert(user, token);
+        if let Some(capture) = &self.protocol.capture {
+            capture.disconnected(previous);
+            capture.connected(user);
+        }
+        println!("{previous} reconnected as {user}");
+        true
+    }
+
+    fn reject(&mut self, token: Token, reason: RejectionReason) {
+        let peer = self.peers.get_mut(&token).unwrap();
+        println!("rejecting {}: {reason:?}", peer.user_id);
+        peer.queue(
+            &ServerPacket::ConnectionRejected {
+                reason,
+                min_version: *SUPPORTED_PROTOCOL_VERSIONS.start(),
+                max_version: *SUPPORTED_PROTOCOL_VERSIONS.end(),
+            },
+            &self.protocol,
+        );
+        peer.closing = true;
+    }
+
+    fn expire_sessions(&mut self) {
+        let expired = self.sessions.expire(Instant::now());
+        if expired.is_empty() {
+            return;
+        }
+        let mut locked_users = self.users.lock().unwrap();
+        for user in expired {
+            locked_users.away.remove(&user);
+            println!(
+                "{user} did not reconnect within {:?}",
+                self.sessions.grace()
+            );
+        }
+    }
+}
+
+impl Peer {
+    /// Reads everything the socket has and returns the complete frames.
+    fn receive(&mut self, protocol: &Protocol) -> Vec<Result<ClientPacket, DecodeError>> {
+        if self.closing {
+            return Vec::new();
+        }
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closing = true;
+                    break;
+                }
+                Ok(read) => {
+                    self.inbound.extend_from_slice(&chunk[..read]);
+                    self.last_read = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.closing = true;
+                    self.outbound.clear();
+                    break;
+                }
+            }
+        }
+
+        let decoded = self.decode_inbound(protocol);
+        if self.inbound.is_empty() {
+            self.frame_started = None;
+        } else if self.frame_started.is_none() || !decoded.is_empty() {
+            self.frame_started = Some(Instant::now());
+        }
+        decoded
+    }
+
+    /// Takes every complete frame off `inbound`. An error that leaves the
+    /// stream misaligned is the last thing returned.
+    fn decode_inbound(&mut self, protocol: &Protocol) -> Vec<Result<ClientPacket, DecodeError>> {
+        let limits = &protocol.config.frame_limits;
+        let mut decoded = Vec::new();
+        match self.codec {
+            Codec::Binary => loop {
+                let mut cursor = Cursor::new(&self.inbound[..]);
+                let result = Message::next_message(&mut cursor, limits);
+                if let Err(DecodeError::Io(e)) = &result {
+                    if e.kind() == ErrorKind::UnexpectedEof {
+                        break;
+                    }
+                }
+                let consumed = cursor.position() as usize;
+                self.inbound.drain(..consumed);
+
+                let version = self.protocol_version.load(Ordering::Acquire);
+                let result = result.and_then(|message| {
+                    if let Some(capture) = &protocol.capture {
+                        capture.inbound(self.user_id, version, &message);
+                    }
+                    ClientPacket::decode(message, version)
+                });
+                let recoverable = result
+                    .as_ref()
+                    .map_or_else(|e| e.is_recoverable(), |_| true);
+                // The version a ConnectionRequested negotiates applies to the very next frame.
+                if let Ok(packet) = &result {
+                    negotiate(packet, &self.protocol_version);
+                }
+                decoded.push(result);
+                if !recoverable {
+                    break;
+                }
+            },
+            Codec::Json => {
+                let limit = limits.default_limit();
+                let complete = match self.inbound.iter().rposition(|byte| *byte == b'\n') {
+                    Some(newline) => newline + 1,
+                    None if self.inbound.len() > limit => self.inbound.len(),
+                    None => 0,
+                };
+                let lines: Vec<u8> = self.inbound.drain(..complete).collect();
+                for result in JsonPacketIterator::new(&lines[..], limit) {
+                    let version = self.protocol_version.load(Ordering::Acquire);
+                    if let (Ok(packet), Some(capture)) = (&result, &protocol.capture) {
+                        capture.inbound(self.user_id, version, &packet.encode(version));
+                    }
+                    if let Ok(packet) = &result {
+                        negotiate(packet, &self.protocol_version);
+                    }
+                    let recoverable = result
+                        .as_ref()
+                        .map_or_else(|e| e.is_recoverable(), |_| true);
+                    decoded.push(result);
+                    if !recoverable {
+                        break;
+                    }
+                }
+            }
+        }
+        decoded
+    }
+
+    /// Returns whether the connection should stay open.
+    fn handle(
+        &mut self,
+        decoded: Result<ClientPacket, DecodeError>,
+        users: &Arc<Mutex<Users>>,
+        protocol: &Protocol,
+    ) -> bool {
+        match decoded {
+            Ok(ClientPacket::Pong { nonce }) => {
+                if let Some(connection) = users
+                    .lock()
+                    .unwrap()
+                    .user_to_connection
+                    .get_mut(&self.user_id)
+                {
+                    connection.heartbeat.pong(nonce, Instant::now());
+                }
+                true
+            }
+            Ok(packet) => self.read_sender.send(packet).is_ok(),
+            Err(DecodeError::Io(_)) => false,
+            Err(e) => {
+                if let DecodeError::Oversize { message_type, .. } = e {
+                    protocol.frame_stats.record_oversize(message_type);
+                    println!(
+                        "oversize frames dropped so far: {} (by type: {:?})",
+                        protocol.frame_stats.oversize_total(),
+                        protocol.frame_stats.oversize_by_type()
+                    );
+                }
+                println!("rejecting frame from {}: {e}", self.user_id);
+                self.queue(&e.to_packet(), protocol);
+                e.is_recoverable()
+                    && !matches!(
+                        protocol.config.malformed_frame_policy,
+                        MalformedFramePolicy::Disconnect
+                    )
+            }
+        }
+    }
+
+    /// Queues what the game sent, giving the user a session when they are accepted.
+    fn drain_outbound(&mut self, protocol: &Protocol, sessions: &mut Sessions) {
+        if self.write_receiver.overflowed() && !self.closing {
+            println!(
+                "{} is not reading fast enough, disconnecting ({:?})",
+                self.user_id,
+                self.write_receiver.stats()
+            );
+            self.abandon();
+            return;
+        }
+        while !self.closing && self.outbound.len() < OUTBOUND_BUFFER_LIMIT {
+            let Some(mut packet) = self.write_receiver.try_recv() else {
+                break;
+            };
+            if let ServerPacket::ConnectionAccepted { session, .. } = &mut packet {
+                if self.protocol_version.load(Ordering::Acquire) >= SESSION_PROTOCOL_VERSION {
+                    *session = sessions.issue(self.user_id);
+                }
+            }
+            self.queue(&packet, protocol);
+            if let ServerPacket::Kicked { .. } = packet {
+                sessions.revoke(self.user_id);
+            }
+            if let ServerPacket::ConnectionRejected { .. } | ServerPacket::Kicked { .. } = packet {
+                self.closing = true;
+            }
+        }
+    }
+
+    /// Packets the client's version does not know are left out.
+    fn queue(&mut self, packet: &ServerPacket, protocol: &Protocol) {
+        let version = self.protocol_version.load(Ordering::Acquire);
+        if packet.first_version() > version {
+            return;
+        }
+        let message = packet.encode(version);
+        if let Some(capture) = &protocol.capture {
+            capture.outbound(self.user_id, version, &message);
+        }
+        match self.codec {
+            Codec::Binary => message.write_to(&mut self.outbound).unwrap(),
+            Codec::Json => json::write_packet(&mut self.outbound, packet).unwrap(),
+        }
+    }
+
+    fn flush(&mut self) {
+        while !self.outbound.is_empty() {
+            match self.stream.write(&self.outbound) {
+                Ok(0) => {
+                    self.abandon();
+                    return;
+                }
+                Ok(written) => {
+                    self.outbound.drain(..written);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.abandon();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The socket is gone, nothing queued for it will ever be written.
+    fn abandon(&mut self) {
+        self.closing = true;
+        self.outbound.clear();
+    }
+
+    fn reregister(&mut self, registry: &mio::Registry, token: Token) {
+        self.wants_writable = !self.outbound.is_empty();
+        let interest = if self.wants_writable {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+        let _ = registry.reregister(&mut self.stream, token, interest);
+    }
+}
+
+/// Rejects a connection before it is given a user. It has not said which
+/// protocol it speaks yet, so it is answered in the handshake's.
+fn refuse(mut stream: TcpStream, codec: Codec, reason: RejectionReason) {
+    let packet = ServerPacket::ConnectionRejected {
+        reason,
+        min_version: *SUPPORTED_PROTOCOL_VERSIONS.start(),
+        max_version: *SUPPORTED_PROTOCOL_VERSIONS.end(),
+    };
+    let mut frame = Vec::new();
+    match codec {
+        Codec::Binary => packet
+            .encode(HANDSHAKE_PROTOCOL_VERSION)
+            .write_to(&mut frame)
+            .unwrap(),
+        Codec::Json => json::write_packet(&mut frame, &packet).unwrap(),
+    }
+    // Fits in an empty socket buffer; if it does not, the client only misses the reason.
+    let _ = stream.write(&frame);
+    let _ = stream.shutdown(std::net::Shutdown::Both);
+}
+
+fn negotiate(packet: &ClientPacket, protocol_version: &AtomicU16) {
+    if let ClientPacket::ConnectionRequested {
+        protocol_version: requested,
+        ..
+    }
+    | ClientPacket::Reconnect {
+        protocol_version: requested,
+        ..
+    } = packet
+    {
+        if SUPPORTED_PROTOCOL_VERSIONS.contains(requested) {
+            protocol_version.store(*requested, Ordering::Release);
+        }
+    }
+}
+
+/// Returns `None` once every user id has been handed out.
+fn add_user_to_users<F: FnOnce(UserId) -> Connection>(
+    users: &Arc<Mutex<Users>>,
+    write_sender: OutboundSender,
+    read_receiver: mpsc::Receiver<ClientPacket>,
+    connection: F,
+) -> Option<UserId> {
+    let mut locked_users = users.lock().unwrap();
+    let user_id = locked_users.user_ids.allocate()?;
+
+    locked_users.users.insert(user_id);
+    locked_users
+        .user_to_connection
+        .insert(user_id, connection(user_id));
+    locked_users
+        .user_to_write_sender
+        .insert(user_id, write_sender);
+    locked_users
+        .user_to_read_receiver
+        .insert(user_id, read_receiver);
+
+    Some(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, net::SocketAddr};
+
+    use super::*;
+    use crate::{
+        message::{FrameLimits, ROOMS_PROTOCOL_VERSION},
+        rate_limit::{RateLimit, RateLimitConfig},
+        ProtocolConfig,
+    };
+
+    /// A network thread whose users may send `burst` frames and no more.
+    fn start(burst: f64, action: RateLimitAction) -> (SocketAddr, Arc<Mutex<Users>>) {
+        start_with(ProtocolConfig {
+            rate_limit: RateLimitConfig {
+                total: RateLimit {
+                    rate: 0.001,
+                    burst,
+                    action,
+                },
+                messages: BTreeMap::new(),
+                ..RateLimitConfig::default()
+            },
+            ..ProtocolConfig::default()
+        })
+    }
+
+    fn start_with(config: ProtocolConfig) -> (SocketAddr, Arc<Mutex<Users>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let protocol = Protocol {
+            config,
+            ..Protocol::default()
+        };
+        let users = Arc::new(Mutex::new(Users::new()));
+        spawn_network_thread(
+            vec![(listener, Codec::Binary)],
+            users.clone(),
+            Arc::new(protocol),
+        );
+        (address, users)
+    }
+
+    /// Connects and sends `frames` all at once.
+    fn flood(address: SocketAddr, frames: &[Message]) -> std::net::TcpStream {
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let mut bytes = Vec::new();
+        for frame in frames {
+            frame.write_to(&mut bytes).unwrap();
+        }
+        stream.write_all(&bytes).unwrap();
+        stream
+    }
+
+    fn pongs(count: u32, protocol_version: u16) -> Vec<Message> {
+        (0..count)
+            .map(|nonce| ClientPacket::Pong { nonce }.encode(protocol_version))
+            .collect()
+    }
+
+    /// What the server sent until it went quiet, and whether it then closed
+    /// the connection.
+    fn replies(
+        stream: &mut std::net::TcpStream,
+        protocol_version: u16,
+    ) -> (Vec<ServerPacket>, bool) {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let limits = FrameLimits::default();
+        let mut packets = Vec::new();
+        for message in Message::iter(stream, &limits) {
+            match message {
+                Ok(message) => {
+                    packets.push(ServerPacket::decode(message, protocol_version).unwrap())
+                }
+                Err(DecodeError::Io(e)) if e.kind() == ErrorKind::ConnectionReset => {
+                    return (packets, true)
+                }
+                Err(_) => return (packets, false),
+            }
+        }
+        (packets, true)
+    }
+
+    /// Waits for a connection to have negotiated `version`.
+    fn negotiated(users: &Mutex<Users>, version: u16) -> UserId {
+        loop {
+            let locked = users.lock().unwrap();
+            let negotiated = locked
+                .user_to_connection
+                .values()
+                .find(|connection| connection.protocol_version() == version);
+            if let Some(connection) = negotiated {
+                return connection.user;
+            }
+            drop(locked);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Connects at `version` and stands in for the game accepting the user,
+    /// returning who they are and the session they were given.
+    fn accepted(
+        address: SocketAddr,
+        users: &Mutex<Users>,
+        version: u16,
+    ) -> (std::net::TcpStream, UserId, String) {
+        let request = ClientPacket::ConnectionRequested {
+            protocol_version: version,
+            client_build: String::new(),
+            name: String::new(),
+        };
+        let mut stream = flood(address, &[request.encode(version)]);
+        let user = negotiated(users, version);
+        let locked = users.lock().unwrap();
+        locked.user_to_write_sender[&user].send(ServerPacket::ConnectionAccepted {
+            you: user,
+            users: Vec::new(),
+            session: String::new(),
+        });
+        locked.flush();
+        drop(locked);
+        let (packets, _) = replies(&mut stream, version);
+        let [ServerPacket::ConnectionAccepted { session, .. }] = &packets[..] else {
+            panic!("not accepted: {packets:?}");
+        };
+        assert!(!session.is_empty());
+        let session = session.clone();
+        (stream, user, session)
+    }
+
+    fn wait_until<F: FnMut(&Users) -> bool>(users: &Mutex<Users>, mut done: F) {
+        while !done(&users.lock().unwrap()) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn frames_over_a_drop_limit_are_dropped_quietly() {
+        let (address, _) = start(3.0, RateLimitAction::Drop);
+        let mut stream = flood(address, &pongs(10, HANDSHAKE_PROTOCOL_VERSION));
+        assert_eq!(
+            replies(&mut stream, HANDSHAKE_PROTOCOL_VERSION),
+            (Vec::new(), false)
+        );
+    }
+
+    #[test]
+    fn malformed_frames_take_from_the_limit_and_are_not_answered_over_it() {
+        let (address, _) = start(3.0, RateLimitAction::Drop);
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let mut unknown = Vec::new();
+        for _ in 0..10 {
+            unknown.extend_from_slice(&9999u32.to_le_bytes());
+            unknown.extend_from_slice(&0u32.to_le_bytes());
+        }
+        stream.write_all(&unknown).unwrap();
+
+        let (packets, closed) = replies(&mut stream, HANDSHAKE_PROTOCOL_VERSION);
+        assert!(!closed);
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|packet| matches!(
+            packet,
+            ServerPacket::ProtocolError {
+                rejected_type: 9999,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn a_user_over_a_warn_limit_is_told_to_slow_down_once() {
+        let (address, _) = start(3.0, RateLimitAction::Warn);
+        let mut stream = flood(address, &pongs(10, HANDSHAKE_PROTOCOL_VERSION));
+        let (packets, closed) = replies(&mut stream, HANDSHAKE_PROTOCOL_VERSION);
+        assert!(!closed);
+        let [ServerPacket::ChatUpdate { messages }] = &packets[..] else {
+            panic!("not one warning: {packets:?}");
+        };
+        assert_eq!(messages[0].user, UserId::SERVER);
+        assert!(messages[0].text.starts_with("Slow down"));
+    }
+
+    #[test]
+    fn a_user_over_a_kick_limit_is_kicked() {
+        let (address, _) = start(3.0, RateLimitAction::Kick);
+        let mut stream = flood(address, &pongs(10, HANDSHAKE_PROTOCOL_VERSION));
+        let (packets, closed) = replies(&mut stream, HANDSHAKE_PROTOCOL_VERSION);
+        assert!(closed);
+        assert!(matches!(
+            packets[..],
+            [ServerPacket::Kicked {
+                reason: KickReason::RateLimited,
+                ..
+            }]
+        ));
+
+        // Only the user is kicked, others may still connect.
+        let mut stream = flood(address, &pongs(1, HANDSHAKE_PROTOCOL_VERSION));
+        assert_eq!(
+            replies(&mut stream, HANDSHAKE_PROTOCOL_VERSION),
+            (Vec::new(), false)
+        );
+    }
+
+    #[test]
+    fn a_user_over_a_ban_limit_is_kicked_and_their_address_refused() {
+        let (address, _) = start(3.0, RateLimitAction::Ban);
+        let mut stream = flood(address, &pongs(10, HANDSHAKE_PROTOCOL_VERSION));
+        let (packets, closed) = replies(&mut stream, HANDSHAKE_PROTOCOL_VERSION);
+        assert!(closed);
+        assert!(matches!(
+            packets[..],
+            [ServerPacket::Kicked {
+                reason: KickReason::RateLimited,
+                ..
+            }]
+        ));
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let (packets, closed) = replies(&mut stream, HANDSHAKE_PROTOCOL_VERSION);
+        assert!(closed);
+        assert!(matches!(
+            packets[..],
+            [ServerPacket::ConnectionRejected {
+                reason: RejectionReason::Banned,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn a_user_kicked_for_flooding_cannot_come_back_with_their_session() {
+        let (address, users) = start(3.0, RateLimitAction::Kick);
+        let version = SESSION_PROTOCOL_VERSION;
+        let (mut stream, _, session) = accepted(address, &users, version);
+
+        for pong in pongs(10, version) {
+            pong.write_to(&mut stream).unwrap();
+        }
+        let (packets, closed) = replies(&mut stream, version);
+        assert!(closed);
+        assert!(matches!(
+            packets[..],
+            [ServerPacket::Kicked {
+                reason: KickReason::RateLimited,
+                ..
+            }]
+        ));
+
+        let reconnect = ClientPacket::Reconnect {
+            protocol_version: version,
+            session,
+        };
+        let mut stream = flood(address, &[reconnect.encode(version)]);
+        let (packets, closed) = replies(&mut stream, version);
+        assert!(closed);
+        assert!(matches!(
+            packets[..],
+            [ServerPacket::ConnectionRejected {
+                reason: RejectionReason::UnknownSession,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn a_user_that_reconnects_in_time_is_the_same_user() {
+        let (address, users) = start_with(ProtocolConfig::default());
+        let version = SESSION_PROTOCOL_VERSION;
+        let (stream, user, session) = accepted(address, &users, version);
+        drop(stream);
+        wait_until(&users, |users| users.is_away(&user));
+
+        let reconnect = ClientPacket::Reconnect {
+            protocol_version: version,
+            session,
+        };
+        let mut stream = flood(address, &[reconnect.encode(version)]);
+        wait_until(&users, |users| users.users.contains(&user));
+        let mut locked = users.lock().unwrap();
+        assert!(!locked.is_away(&user));
+        // The id the new connection was given first is gone.
+        assert_eq!(locked.users.len(), 1);
+        assert_eq!(locked.user_to_connection[&user].protocol_version(), version);
+        let receiver = locked.user_to_read_receiver.remove(&user).unwrap();
+        locked.user_to_write_sender[&user].send(ServerPacket::ReadyToStart);
+        locked.flush();
+        drop(locked);
+
+        // The game is told, so that it lets the user back in.
+        assert!(matches!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Ok(ClientPacket::Reconnect { .. })
+        ));
+        assert_eq!(
+            replies(&mut stream, version),
+            (vec![ServerPacket::ReadyToStart], false)
+        );
+    }
+
+    #[test]
+    fn a_session_is_forgotten_after_the_grace_window() {
+        let (address, users) = start_with(ProtocolConfig {
+            reconnect_grace: Duration::from_millis(100),
+            ..ProtocolConfig::default()
+        });
+        let version = SESSION_PROTOCOL_VERSION;
+        let (stream, user, session) = accepted(address, &users, version);
+        drop(stream);
+        wait_until(&users, |users| users.is_away(&user));
+        wait_until(&users, |users| !users.is_away(&user));
+
+        let reconnect = ClientPacket::Reconnect {
+            protocol_version: version,
+            session,
+        };
+        let mut stream = flood(address, &[reconnect.encode(version)]);
+        let (packets, closed) = replies(&mut stream, version);
+        assert!(closed);
+        assert!(matches!(
+            packets[..],
+            [ServerPacket::ConnectionRejected {
+                reason: RejectionReason::UnknownSession,
+                ..
+            }]
+        ));
+        assert!(!users.lock().unwrap().users.contains(&user));
+    }
+
+    #[test]
+    fn clients_too_old_for_pings_are_dropped_once_quiet_for_too_long() {
+        let (address, _) = start_with(ProtocolConfig {
+            idle_timeout: Duration::from_millis(200),
+            ..ProtocolConfig::default()
+        });
+        let request = |protocol_version| {
+            ClientPacket::ConnectionRequested {
+                protocol_version,
+                client_build: String::new(),
+                name: String::new(),
+            }
+            .encode(protocol_version)
+        };
+
+        let mut old = flood(address, &[request(HEARTBEAT_PROTOCOL_VERSION - 1)]);
+        let mut pinged = flood(address, &[request(HEARTBEAT_PROTOCOL_VERSION)]);
+        let (packets, closed) = replies(&mut old, HEARTBEAT_PROTOCOL_VERSION - 1);
+        assert!(closed);
+        assert!(matches!(
+            packets[..],
+            [ServerPacket::Kicked {
+                reason: KickReason::IdleTimeout,
+                ..
+            }]
+        ));
+        // Pings, sent by the game, are what tells whether a newer client is there.
+        assert_eq!(
+            replies(&mut pinged, HEARTBEAT_PROTOCOL_VERSION),
+            (Vec::new(), false)
+        );
+    }
+
+    #[test]
+    fn the_id_of_a_user_that_left_is_not_handed_out_again() {
+        let (address, users) = start_with(ProtocolConfig::default());
+        let request = [ClientPacket::ConnectionRequested {
+            protocol_version: HANDSHAKE_PROTOCOL_VERSION,
+            client_build: String::new(),
+            name: String::new(),
+        }
+        .encode(HANDSHAKE_PROTOCOL_VERSION)];
+
+        let stream = flood(address, &request);
+        let first = negotiated(&users, HANDSHAKE_PROTOCOL_VERSION);
+        drop(stream);
+        wait_until(&users, |users| users.users.is_empty());
+
+        let _stream = flood(address, &request);
+        let second = negotiated(&users, HANDSHAKE_PROTOCOL_VERSION);
+        assert!(second > first);
+    }
+
+    #[test]
+    fn packets_newer_than_the_client_are_not_sent() {
+        let (address, users) = start_with(ProtocolConfig::default());
+        for version in [HANDSHAKE_PROTOCOL_VERSION, ROOMS_PROTOCOL_VERSION] {
+            let request = ClientPacket::ConnectionRequested {
+                protocol_version: version,
+                client_build: String::new(),
+                name: String::new(),
+            };
+            let mut stream = flood(address, &[request.encode(version)]);
+            let user = negotiated(&users, version);
+            let locked = users.lock().unwrap();
+            let sender = &locked.user_to_write_sender[&user];
+            sender.send(ServerPacket::RoomLeft);
+            sender.send(ServerPacket::ReadyToStart);
+            locked.flush();
+            drop(locked);
+
+            let (packets, _) = replies(&mut stream, version);
+            if version >= ROOMS_PROTOCOL_VERSION {
+                assert_eq!(
+                    packets,
+                    [ServerPacket::RoomLeft, ServerPacket::ReadyToStart]
+                );
+            } else {
+                assert_eq!(packets, [ServerPacket::ReadyToStart]);
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Bounded queues of packets waiting for the network thread to write them to a
+//! user. A user that stops reading fills their queue, and what happens to the
+//! next packet depends on its [`OverflowPolicy`] instead of the server
+//! buffering for them forever.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::message::ServerPacket;
+
+/// What to do with a packet that does not fit in a full queue.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Only the latest one matters: the oldest queued packet of the same type
+    /// is dropped to make room.
+    DropOldest,
+    /// Merged into the newest queued packet of the same type.
+    Coalesce,
+    /// Must arrive: a user too slow to take it is disconnected.
+    Disconnect,
+}
+
+impl OverflowPolicy {
+    pub fn of(packet: &ServerPacket) -> OverflowPolicy {
+        match packet {
+            ServerPacket::GameAboutToStart { .. }
+            | ServerPacket::StubMessage { .. }
+            | ServerPacket::Ping { .. } => OverflowPolicy::DropOldest,
+            ServerPacket::ChatUpdate { .. } | ServerPacket::UserStatusUpdate { .. } => {
+                OverflowPolicy::Coalesce
+            }
+            _ => OverflowPolicy::Disconnect,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OutboundStats {
+    pub depth: usize,
+    pub max_depth: usize,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+struct Queue {
+    packets: VecDeque<ServerPacket>,
+    stats: OutboundStats,
+    /// Set when a packet had to be given up on, the user is disconnected.
+    overflowed: bool,
+    /// Set once the receiving side is gone, nothing is queued anymore.
+    closed: bool,
+}
+
+struct Shared {
+    capacity: usize,
+    queue: Mutex<Queue>,
+}
+
+/// The game's end of a user's queue. Sending never fails nor blocks.
+#[derive(Clone)]
+pub struct OutboundSender {
+    shared: Arc<Shared>,
+}
+
+/// The network thread's end of a user's queue.
+pub struct OutboundReceiver {
+    shared: Arc<Shared>,
+}
+
+pub fn outbound_queue(capacity: usize) -> (OutboundSender, OutboundReceiver) {
+    let shared = Arc::new(Shared {
+        capacity,
+        queue: Mutex::new(Queue {
+            packets: VecDeque::new(),
+            stats: OutboundStats::default(),
+            overflowed: false,
+            closed: false,
+        }),
+    });
+    (
+        OutboundSender {
+            shared: shared.clone(),
+        },
+        OutboundReceiver { shared },
+    )
+}
+
+impl OutboundSender {
+    pub fn send(&self, packet: ServerPacket) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed || queue.overflowed {
+            return;
+        }
+        let packet = if queue.packets.len() >= self.shared.capacity {
+            let Some(packet) = queue.make_room(packet) else {
+                return;
+            };
+            packet
+        } else {
+            packet
+        };
+        queue.packets.push_back(packet);
+        queue.stats.depth = queue.packets.len();
+        queue.stats.max_depth = queue.stats.max_depth.max(queue.stats.depth);
+    }
+
+    pub fn stats(&self) -> OutboundStats {
+        self.shared.queue.lock().unwrap().stats.clone()
+    }
+}
+
+impl Queue {
+    /// Returns `packet` if it still has to be queued.
+    fn make_room(&mut self, packet: ServerPacket) -> Option<ServerPacket> {
+        let message_type = packet.message_type();
+        match OverflowPolicy::of(&packet) {
+            OverflowPolicy::DropOldest => {
+                let oldest = self
+                    .packets
+                    .iter()
+                    .position(|queued| queued.message_type() == message_type);
+                if let Some(oldest) = oldest {
+                    self.packets.remove(oldest);
+                    self.stats.dropped += 1;
+                    return Some(packet);
+                }
+            }
+            OverflowPolicy::Coalesce => {
+                let newest = self
+                    .packets
+                    .iter_mut()
+                    .rev()
+                    .find(|queued| queued.message_type() == message_type);
+                if let Some(newest) = newest {
+                    coalesce(newest, packet);
+                    self.stats.coalesced += 1;
+                    return None;
+                }
+            }
+            OverflowPolicy::Disconnect => {}
+        }
+        self.overflowed = true;
+        self.packets.clear();
+        self.stats.dropped += 1;
+        self.stats.depth = 0;
+        None
+    }
+}
+
+/// Only called with two packets of the same type.
+fn coalesce(into: &mut ServerPacket, packet: ServerPacket) {
+    match (into, packet) {
+        (
+            ServerPacket::ChatUpdate { messages },
+            ServerPacket::ChatUpdate {
+                messages: more_messages,
+            },
+        ) => messages.extend(more_messages),
+        (
+            ServerPacket::UserStatusUpdate { users },
+            ServerPacket::UserStatusUpdate { users: more_users },
+        ) => users.extend(more_users),
+        (into, packet) => unreachable!("cannot coalesce {packet:?} into {into:?}"),
+    }
+}
+
+impl OutboundReceiver {
+    pub fn try_recv(&self) -> Option<ServerPacket> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        let packet = queue.packets.pop_front();
+        queue.stats.depth = queue.packets.len();
+        packet
+    }
+
+    pub fn stats(&self) -> OutboundStats {
+        self.shared.queue.lock().unwrap().stats.clone()
+    }
+
+    /// Whether a packet that had to arrive was given up on.
+    pub fn overflowed(&self) -> bool {
+        self.shared.queue.lock().unwrap().overflowed
+    }
+}
+
+impl Drop for OutboundReceiver {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.closed = true;
+        queue.packets.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{UserStatus, UserStatusChange},
+        user_id::UserId,
+    };
+
+    fn ping(nonce: u32) -> ServerPacket {
+        ServerPacket::Ping { nonce }
+    }
+
+    fn joined(user: i32) -> ServerPacket {
+        ServerPacket::UserStatusUpdate {
+            users: vec![UserStatusChange {
+                status: UserStatus::NotReady,
+                user: UserId::new(user),
+                name: String::new(),
+            }],
+        }
+    }
+
+    fn drain(receiver: &OutboundReceiver) -> Vec<ServerPacket> {
+        std::iter::from_fn(|| receiver.try_recv()).collect()
+    }
+
+    #[test]
+    fn the_oldest_packet_of_the_kind_is_dropped_for_a_new_one() {
+        let (sender, receiver) = outbound_queue(3);
+        sender.send(ping(1));
+        sender.send(ServerPacket::ReadyToStart);
+        sender.send(ping(2));
+        sender.send(ping(3));
+
+        assert_eq!(
+            drain(&receiver),
+            [ServerPacket::ReadyToStart, ping(2), ping(3)]
+        );
+        assert!(!receiver.overflowed());
+        let stats = sender.stats();
+        assert_eq!((stats.dropped, stats.coalesced), (1, 0));
+    }
+
+    #[test]
+    fn a_packet_is_merged_into_the_newest_queued_one_of_its_kind() {
+        let (sender, receiver) = outbound_queue(2);
+        sender.send(joined(1));
+        sender.send(ServerPacket::ReadyToStart);
+        sender.send(joined(2));
+
+        let ServerPacket::UserStatusUpdate { users } = &drain(&receiver)[0] else {
+            panic!("the update is not first");
+        };
+        let users: Vec<UserId> = users.iter().map(|change| change.user).collect();
+        assert_eq!(users, [UserId::new(1), UserId::new(2)]);
+        let stats = receiver.stats();
+        assert_eq!((stats.dropped, stats.coalesced), (0, 1));
+    }
+
+    #[test]
+    fn a_packet_that_must_arrive_overflows_the_queue() {
+        let (sender, receiver) = outbound_queue(1);
+        sender.send(ServerPacket::ReadyToStart);
+        sender.send(ServerPacket::RoomLeft);
+
+        assert!(receiver.overflowed());
+        assert!(drain(&receiver).is_empty());
+        // Nothing is queued for a user that is being disconnected.
+        sender.send(ping(1));
+        assert!(drain(&receiver).is_empty());
+        assert_eq!(sender.stats().dropped, 1);
+    }
+
+    #[test]
+    fn a_packet_with_nothing_to_replace_overflows_the_queue_too() {
+        let (sender, receiver) = outbound_queue(1);
+        sender.send(ServerPacket::ReadyToStart);
+        sender.send(ping(1));
+        assert!(receiver.overflowed());
+    }
+
+    #[test]
+    fn the_depth_is_counted_as_packets_come_and_go() {
+        let (sender, receiver) = outbound_queue(4);
+        for nonce in 0..3 {
+            sender.send(ping(nonce));
+        }
+        assert_eq!(sender.stats().depth, 3);
+        receiver.try_recv();
+        receiver.try_recv();
+        sender.send(ping(3));
+        let stats = sender.stats();
+        assert_eq!((stats.depth, stats.max_depth), (2, 3));
+    }
+}
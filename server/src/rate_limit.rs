@@ -0,0 +1,354 @@
+//! Token buckets on what each user sends, so that a client spamming frames
+//! cannot make the game process them all.
+//!
+//! Every user has one bucket for all their frames and one per limited message
+//! type. A frame takes a token from each bucket that applies; a frame that
+//! finds one empty is not handed to the game, and the limit's
+//! [`RateLimitAction`] says what else happens to the user. Frames over a
+//! message type's limit still take from the total, so the total limit is
+//! what stops a client that keeps flooding after being dropped or warned.
+//! So do frames that cannot be decoded, which are not answered once the
+//! total is used up.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::message::MessageType;
+
+/// What happens to a user that goes over a limit, on top of the frame being dropped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitAction {
+    Drop,
+    /// The user is told to slow down in the chat.
+    Warn,
+    Kick,
+    /// Kicked, and new connections from the same address are refused for a while.
+    Ban,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Frames per second, on average.
+    pub rate: f64,
+    /// Frames that may be sent at once after a quiet period.
+    pub burst: f64,
+    pub action: RateLimitAction,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Applies to every frame.
+    pub total: RateLimit,
+    /// By message type name, e.g. `ChatUpdate`.
+    pub messages: BTreeMap<String, RateLimit>,
+    /// How long an address is refused after [`RateLimitAction::Ban`].
+    #[serde(deserialize_with = "crate::config::seconds")]
+    pub ban: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            total: RateLimit {
+                rate: 60.0,
+                burst: 120.0,
+                action: RateLimitAction::Kick,
+            },
+            messages: BTreeMap::from([
+                (
+                    MessageType::ChatUpdate.name().to_string(),
+                    RateLimit {
+                        rate: 2.0,
+                        burst: 5.0,
+                        action: RateLimitAction::Warn,
+                    },
+                ),
+                (
+                    MessageType::ReadyToStartChanged.name().to_string(),
+                    RateLimit {
+                        rate: 2.0,
+                        burst: 4.0,
+                        action: RateLimitAction::Drop,
+                    },
+                ),
+                (
+                    MessageType::ListRooms.name().to_string(),
+                    RateLimit {
+                        rate: 1.0,
+                        burst: 3.0,
+                        action: RateLimitAction::Drop,
+                    },
+                ),
+            ]),
+            ban: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, limit) in [("total", &self.total)].into_iter().chain(
+            self.messages
+                .iter()
+                .map(|(name, limit)| (name.as_str(), limit)),
+        ) {
+            if name != "total" && MessageType::from_name(name).is_none() {
+                return Err(format!("no message type is called {name}"));
+            }
+            // Written so that NaN, which compares false to anything, is refused.
+            if !(limit.rate > 0.0 && limit.burst >= 1.0) {
+                return Err(format!(
+                    "{name} needs a positive rate and a burst of at least 1"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.refilled = now;
+    }
+}
+
+/// The buckets of one user.
+pub struct RateLimiter {
+    total: TokenBucket,
+    by_type: HashMap<MessageType, TokenBucket>,
+    last_warning: Option<Instant>,
+}
+
+/// How often a user is warned at most.
+const WARN_EVERY: Duration = Duration::from_secs(1);
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        RateLimiter {
+            total: TokenBucket::new(config.total, now),
+            by_type: config
+                .messages
+                .iter()
+                .filter_map(|(name, limit)| {
+                    Some((MessageType::from_name(name)?, TokenBucket::new(*limit, now)))
+                })
+                .collect(),
+            last_warning: None,
+        }
+    }
+
+    /// Takes a token for a frame of `message_type`, `None` for a frame that
+    /// could not be decoded. Returns the action of the limit it went over, if
+    /// any, and which limit that was.
+    pub fn check(
+        &mut self,
+        message_type: Option<MessageType>,
+        now: Instant,
+    ) -> Option<(RateLimitAction, String)> {
+        self.total.refill(now);
+        if self.total.tokens < 1.0 {
+            return Some((
+                self.total.limit.action,
+                format!("more than {} frames per second", self.total.limit.rate),
+            ));
+        }
+        self.total.tokens -= 1.0;
+
+        let message_type = message_type?;
+        let bucket = self.by_type.get_mut(&message_type)?;
+        bucket.refill(now);
+        if bucket.tokens < 1.0 {
+            return Some((
+                bucket.limit.action,
+                format!(
+                    "more than {} {} per second",
+                    bucket.limit.rate,
+                    message_type.name()
+                ),
+            ));
+        }
+        bucket.tokens -= 1.0;
+        None
+    }
+
+    /// Whether the user has not been warned for a while.
+    pub fn warning_due(&mut self, now: Instant) -> bool {
+        if self
+            .last_warning
+            .is_some_and(|warned| now.duration_since(warned) < WARN_EVERY)
+        {
+            return false;
+        }
+        self.last_warning = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(rate: f64, burst: f64, action: RateLimitAction) -> RateLimit {
+        RateLimit {
+            rate,
+            burst,
+            action,
+        }
+    }
+
+    fn config(total: RateLimit, messages: &[(MessageType, RateLimit)]) -> RateLimitConfig {
+        RateLimitConfig {
+            total,
+            messages: messages
+                .iter()
+                .map(|(message_type, limit)| (message_type.name().to_string(), *limit))
+                .collect(),
+            ..RateLimitConfig::default()
+        }
+    }
+
+    #[test]
+    fn a_bucket_allows_its_burst_then_refills_at_its_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(limit(2.0, 3.0, RateLimitAction::Drop), start);
+        assert_eq!(bucket.tokens, 3.0);
+
+        bucket.tokens = 0.0;
+        bucket.refill(start + Duration::from_millis(500));
+        assert_eq!(bucket.tokens, 1.0);
+        bucket.refill(start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn the_burst_is_let_through_and_the_next_frame_is_not() {
+        let start = Instant::now();
+        let mut limiter =
+            RateLimiter::new(&config(limit(2.0, 3.0, RateLimitAction::Drop), &[]), start);
+        for _ in 0..3 {
+            assert!(limiter.check(Some(MessageType::Pong), start).is_none());
+        }
+        assert!(limiter.check(Some(MessageType::Pong), start).is_some());
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check(Some(MessageType::Pong), later).is_none());
+        assert!(limiter.check(Some(MessageType::Pong), later).is_some());
+    }
+
+    #[test]
+    fn every_action_is_that_of_the_limit_gone_over() {
+        let start = Instant::now();
+        for action in [
+            RateLimitAction::Drop,
+            RateLimitAction::Warn,
+            RateLimitAction::Kick,
+            RateLimitAction::Ban,
+        ] {
+            let mut limiter = RateLimiter::new(&config(limit(1.0, 1.0, action), &[]), start);
+            assert!(limiter.check(None, start).is_none());
+            let (taken, exceeded) = limiter.check(None, start).unwrap();
+            assert_eq!(taken, action);
+            assert_eq!(exceeded, "more than 1 frames per second");
+
+            let lenient = limit(1.0, 100.0, RateLimitAction::Drop);
+            let mut limiter = RateLimiter::new(
+                &config(
+                    lenient,
+                    &[(MessageType::ChatUpdate, limit(1.0, 1.0, action))],
+                ),
+                start,
+            );
+            assert!(limiter
+                .check(Some(MessageType::ChatUpdate), start)
+                .is_none());
+            let (taken, exceeded) = limiter.check(Some(MessageType::ChatUpdate), start).unwrap();
+            assert_eq!(taken, action);
+            assert_eq!(exceeded, "more than 1 ChatUpdate per second");
+        }
+    }
+
+    #[test]
+    fn frames_over_a_type_limit_and_undecodable_ones_take_from_the_total() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(
+            &config(
+                limit(1.0, 4.0, RateLimitAction::Kick),
+                &[(
+                    MessageType::ChatUpdate,
+                    limit(1.0, 1.0, RateLimitAction::Drop),
+                )],
+            ),
+            start,
+        );
+        let chat = Some(MessageType::ChatUpdate);
+        assert!(limiter.check(chat, start).is_none());
+        assert_eq!(limiter.check(chat, start).unwrap().0, RateLimitAction::Drop);
+        assert!(limiter.check(None, start).is_none());
+        assert!(limiter.check(Some(MessageType::Pong), start).is_none());
+        assert_eq!(limiter.check(None, start).unwrap().0, RateLimitAction::Kick);
+    }
+
+    #[test]
+    fn a_user_is_warned_at_most_once_a_second() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(&RateLimitConfig::default(), start);
+        assert!(limiter.warning_due(start));
+        assert!(!limiter.warning_due(start + Duration::from_millis(999)));
+        assert!(limiter.warning_due(start + WARN_EVERY));
+    }
+
+    #[test]
+    fn limits_that_let_nothing_through_or_are_not_numbers_are_refused() {
+        let drop = RateLimitAction::Drop;
+        for bad in [
+            limit(0.0, 3.0, drop),
+            limit(-1.0, 3.0, drop),
+            limit(1.0, 0.5, drop),
+            limit(f64::NAN, 3.0, drop),
+            limit(1.0, f64::NAN, drop),
+        ] {
+            assert!(config(bad, &[]).validate().is_err(), "total {bad:?}");
+            let per_type = config(limit(1.0, 1.0, drop), &[(MessageType::Pong, bad)]);
+            assert!(per_type.validate().is_err(), "Pong {bad:?}");
+        }
+        assert!(config(limit(1.0, 1.0, drop), &[]).validate().is_ok());
+    }
+
+    #[test]
+    fn a_nan_rate_in_a_config_file_is_refused() {
+        let config: RateLimitConfig =
+            toml::from_str("[total]\nrate = nan\nburst = 3\naction = \"drop\"\n").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn limits_of_unknown_message_types_are_refused() {
+        let mut config = RateLimitConfig::default();
+        config
+            .messages
+            .insert("Dance".to_string(), limit(1.0, 1.0, RateLimitAction::Drop));
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,966 @@
+//! Hosts any number of games at once, each in a room with a short code that
+//! users share to play together.
+//!
+//! Since protocol 7 a user that connects is in no room until they create one,
+//! join one by its code or are matched with others by the [`Matchmaker`], who
+//! puts each match in a private room of its own. Public rooms can be listed.
+//! Older clients cannot
+//! pick, so they are put in the public lobby, and once its game starts or it
+//! is full a new lobby is opened for whoever comes next. No room takes more
+//! than [`ServerConfig::room_size`] users, and there are no more than
+//! [`ServerConfig::max_rooms`] rooms; when no new lobby can be opened, older
+//! clients are told the game is in progress or the lobby is full.
+//!
+//! Every packet a user sends goes through here first. Room requests are
+//! handled here and anything else is passed on to the game of the user's
+//! room, which never sees users that are not in it.
+//!
+//! Everything that is about users rather than games, heartbeats and shutting
+//! down, is done here once for the whole server.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{
+    config::ServerConfig,
+    game::{
+        state::{just_created::JustCreatedGame, Admission, Snapshot},
+        Game, GameConfig,
+    },
+    heartbeat::{HeartbeatAction, HeartbeatConfig},
+    matchmaker::{Matchmaker, QueueStatus},
+    message::{
+        ClientPacket, MatchmakingState, RejectionReason, RoomInfo, RoomRequestError, RoomState,
+        RoomVisibility, ServerPacket, HEARTBEAT_PROTOCOL_VERSION, ROOMS_PROTOCOL_VERSION,
+        SUPPORTED_PROTOCOL_VERSIONS,
+    },
+    names::Names,
+    user_id::UserId,
+    Users,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+pub struct RoomId(u32);
+
+impl Display for RoomId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// How often users that are behind in reading are reported.
+const QUEUE_REPORT_EVERY: Duration = Duration::from_secs(5);
+
+/// Room codes leave out letters and digits that are easily confused.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 6;
+
+struct Room {
+    code: String,
+    visibility: RoomVisibility,
+    /// Empty when the code is enough to join.
+    password: String,
+    game: Game,
+}
+
+impl Room {
+    fn info(&self) -> RoomInfo {
+        RoomInfo {
+            code: self.code.clone(),
+            players: u16::try_from(self.game.users().len()).unwrap_or(u16::MAX),
+            state: match self.game.admission() {
+                Admission::Open => RoomState::Open,
+                Admission::Paused => RoomState::Starting,
+                Admission::Closed => RoomState::InProgress,
+                Admission::Locked => RoomState::Locked,
+            },
+            has_password: !self.password.is_empty(),
+        }
+    }
+}
+
+/// A game in progress as written out on shutdown.
+#[derive(Serialize)]
+struct RoomSnapshot {
+    room: RoomId,
+    code: String,
+    #[serde(flatten)]
+    game: Snapshot,
+}
+
+pub struct RoomManager {
+    users: Arc<Mutex<Users>>,
+    rooms: BTreeMap<RoomId, Room>,
+    codes: HashMap<String, RoomId>,
+    user_to_room: HashMap<UserId, RoomId>,
+    /// The `ConnectionRequested` or `Reconnect` each user opened with, handed
+    /// to the game of every room they enter so that it accepts them.
+    greetings: HashMap<UserId, ClientPacket>,
+    /// Given out on `ConnectionRequested`, kept for as long as the greeting.
+    names: Names,
+    /// Users that cannot pick a room, waiting for the lobby to be open.
+    quick_play: HashSet<UserId>,
+    /// What is passed on to the game of each user's room, and what that game
+    /// reads it from.
+    user_to_inbox: HashMap<UserId, mpsc::Sender<ClientPacket>>,
+    user_to_read_receiver: HashMap<UserId, mpsc::Receiver<ClientPacket>>,
+    matchmaker: Matchmaker,
+    /// The public room users that cannot pick one are put in.
+    lobby: RoomId,
+    next_room: u32,
+    room_size: usize,
+    max_rooms: usize,
+    game_config: GameConfig,
+    heartbeat_config: HeartbeatConfig,
+    last_queue_report: Instant,
+    shutdown_grace: Duration,
+    snapshot: Option<PathBuf>,
+}
+
+impl RoomManager {
+    pub fn new(users: Arc<Mutex<Users>>, config: &ServerConfig) -> Self {
+        let mut rooms = RoomManager {
+            users,
+            rooms: BTreeMap::new(),
+            codes: HashMap::new(),
+            user_to_room: HashMap::new(),
+            greetings: HashMap::new(),
+            names: Names::new(),
+            quick_play: HashSet::new(),
+            user_to_inbox: HashMap::new(),
+            user_to_read_receiver: HashMap::new(),
+            matchmaker: Matchmaker::new(config.matchmaking.clone()),
+            lobby: RoomId(0),
+            next_room: 1,
+            room_size: config.room_size,
+            max_rooms: config.max_rooms,
+            game_config: config.game.clone(),
+            heartbeat_config: config.heartbeat.clone(),
+            last_queue_report: Instant::now(),
+            shutdown_grace: config.shutdown_grace,
+            snapshot: config.snapshot.clone(),
+        };
+        rooms.open_lobby();
+        rooms
+    }
+
+    pub fn elapsed(&mut self, elapsed: Duration) {
+        for room in self.rooms.values_mut() {
+            room.game.elapsed(elapsed);
+        }
+    }
+
+    pub fn io_updates(&mut self) {
+        let users = self.users.clone();
+        let mut locked_users = users.lock().unwrap();
+        self.heartbeats(&mut locked_users);
+        self.forget_gone_users(&locked_users);
+        if let Admission::Closed | Admission::Locked = self.rooms[&self.lobby].game.admission() {
+            self.open_lobby();
+        }
+        self.dispatch(&locked_users);
+        self.matchmake(&locked_users);
+        self.quick_play(&locked_users);
+        for room in self.rooms.values_mut() {
+            room.game
+                .io_updates(&locked_users, &self.user_to_read_receiver, &self.names);
+        }
+        // Only now, so that the games could still say who left.
+        let greetings = &self.greetings;
+        self.names.retain(|user| greetings.contains_key(user));
+        self.close_empty_rooms();
+        locked_users.flush();
+
+        if self.last_queue_report.elapsed() >= QUEUE_REPORT_EVERY {
+            self.last_queue_report = Instant::now();
+            report_queues(&locked_users);
+        }
+    }
+
+    /// Tells every user why the server is going away and waits, at most
+    /// `shutdown_grace`, for that to be written before their sockets are closed.
+    pub fn shutdown(&mut self, reason: &str) {
+        if let Some(path) = &self.snapshot {
+            let rooms: Vec<RoomSnapshot> = self
+                .rooms
+                .iter()
+                .map(|(id, room)| RoomSnapshot {
+                    room: *id,
+                    code: room.code.clone(),
+                    game: room.game.snapshot(),
+                })
+                .collect();
+            let written = serde_json::to_vec_pretty(&rooms)
+                .map_err(std::io::Error::from)
+                .and_then(|snapshot| std::fs::write(path, snapshot));
+            match written {
+                Ok(()) => println!("games written to {}", path.display()),
+                Err(e) => println!("failed to write the games to {}: {e}", path.display()),
+            }
+        }
+
+        let locked_users = self.users.lock().unwrap();
+        for sender in locked_users.user_to_write_sender.values() {
+            sender.send(ServerPacket::ServerShutdown {
+                reason: reason.to_string(),
+            });
+        }
+        let closed = locked_users
+            .network
+            .as_ref()
+            .map(|network| network.shutdown());
+        let connected = locked_users.users.len();
+        drop(locked_users);
+
+        if let Some(closed) = closed {
+            if closed.recv_timeout(self.shutdown_grace).is_err() {
+                println!("gave up waiting for {connected} connections to close");
+            }
+        }
+    }
+
+    /// Returns whether there was room for a new lobby; if not, the old one stays.
+    fn open_lobby(&mut self) -> bool {
+        let Some(lobby) = self.open_room(RoomVisibility::Public, String::new()) else {
+            return false;
+        };
+        self.lobby = lobby;
+        true
+    }
+
+    /// Returns `None` if there are `max_rooms` rooms already.
+    fn open_room(&mut self, visibility: RoomVisibility, password: String) -> Option<RoomId> {
+        if self.rooms.len() >= self.max_rooms {
+            return None;
+        }
+        let id = RoomId(self.next_room);
+        self.next_room += 1;
+        let code = loop {
+            let code = new_code();
+            if !self.codes.contains_key(&code) {
+                break code;
+            }
+        };
+        println!("room {id} is open as {code} ({visibility:?})");
+        self.codes.insert(code.clone(), id);
+        self.rooms.insert(
+            id,
+            Room {
+                code,
+                visibility,
+                password,
+                game: Game::new(Box::new(JustCreatedGame::new(self.game_config.clone()))),
+            },
+        );
+        Some(id)
+    }
+
+    /// Handles room requests and passes everything else on to the game of the
+    /// user's room.
+    fn dispatch(&mut self, users: &Users) {
+        for user in users.users.iter() {
+            let Some(receiver) = users.user_to_read_receiver.get(user) else {
+                continue;
+            };
+            for packet in receiver.try_iter() {
+                let reply = match packet {
+                    ClientPacket::ListRooms => Some(ServerPacket::RoomList { rooms: self.list() }),
+                    // Nobody enters a room before saying what protocol they speak.
+                    packet if !self.greetings.contains_key(user) => {
+                        self.pass_on(*user, packet);
+                        None
+                    }
+                    ClientPacket::CreateRoom {
+                        visibility,
+                        password,
+                    } => Some(self.create(*user, visibility, password)),
+                    ClientPacket::JoinRoom { code, password } => {
+                        Some(self.join(*user, &code, &password))
+                    }
+                    ClientPacket::LeaveRoom => Some(self.leave(*user)),
+                    ClientPacket::FindMatch { players, rating } => {
+                        Some(self.find_match(*user, players, rating))
+                    }
+                    ClientPacket::CancelMatch => Some(self.cancel_match(*user)),
+                    packet => {
+                        self.pass_on(*user, packet);
+                        None
+                    }
+                };
+                if let Some(reply) = reply {
+                    send(users, user, reply);
+                }
+            }
+        }
+    }
+
+    fn pass_on(&mut self, user: UserId, packet: ClientPacket) {
+        if let Some(inbox) = self.user_to_inbox.get(&user) {
+            let _ = inbox.send(packet);
+            return;
+        }
+        // Anything else from a user in no room has nobody to go to.
+        if let ClientPacket::ConnectionRequested {
+            protocol_version, ..
+        }
+        | ClientPacket::Reconnect {
+            protocol_version, ..
+        } = &packet
+        {
+            // Unsupported versions are rejected by the lobby's game.
+            if *protocol_version < ROOMS_PROTOCOL_VERSION
+                || !SUPPORTED_PROTOCOL_VERSIONS.contains(protocol_version)
+            {
+                self.quick_play.insert(user);
+            }
+            if let ClientPacket::ConnectionRequested { name, .. } = &packet {
+                let given = self.names.claim(user, name);
+                println!("{user} is called {given}");
+            }
+            self.greetings.insert(user, packet);
+        }
+    }
+
+    fn list(&self) -> Vec<RoomInfo> {
+        self.rooms
+            .values()
+            .filter(|room| room.visibility == RoomVisibility::Public)
+            .map(Room::info)
+            .collect()
+    }
+
+    fn create(
+        &mut self,
+        user: UserId,
+        visibility: RoomVisibility,
+        password: String,
+    ) -> ServerPacket {
+        if self.user_to_room.contains_key(&user) {
+            return failed(RoomRequestError::AlreadyInRoom);
+        }
+        let Some(room) = self.open_room(visibility, password) else {
+            return failed(RoomRequestError::NoRoomLeft);
+        };
+        self.enter(user, room)
+    }
+
+    fn join(&mut self, user: UserId, code: &str, password: &str) -> ServerPacket {
+        if self.user_to_room.contains_key(&user) {
+            return failed(RoomRequestError::AlreadyInRoom);
+        }
+        let Some(id) = self.codes.get(&code.trim().to_ascii_uppercase()).copied() else {
+            return failed(RoomRequestError::UnknownRoom);
+        };
+        let room = &self.rooms[&id];
+        if !room.password.is_empty() && room.password != password {
+            return failed(RoomRequestError::WrongPassword);
+        }
+        if room.game.users().len() >= self.room_size {
+            return failed(RoomRequestError::RoomFull);
+        }
+        match room.game.admission() {
+            Admission::Open => {}
+            Admission::Locked => return failed(RoomRequestError::RoomLocked),
+            Admission::Paused | Admission::Closed => {
+                return failed(RoomRequestError::GameInProgress)
+            }
+        }
+        self.enter(user, id)
+    }
+
+    fn leave(&mut self, user: UserId) -> ServerPacket {
+        let Some(id) = self.user_to_room.get(&user).copied() else {
+            return failed(RoomRequestError::NotInRoom);
+        };
+        if self.rooms[&id].game.admission() == Admission::Closed {
+            return failed(RoomRequestError::GameInProgress);
+        }
+        self.exit(user);
+        ServerPacket::RoomLeft
+    }
+
+    fn find_match(&mut self, user: UserId, players: u8, rating: u16) -> ServerPacket {
+        if self.user_to_room.contains_key(&user) {
+            return failed(RoomRequestError::AlreadyInRoom);
+        }
+        if players < 2 || usize::from(players) > self.room_size {
+            return failed(RoomRequestError::InvalidMatch);
+        }
+        let now = Instant::now();
+        self.matchmaker.enqueue(user, players, rating, now);
+        println!("{user} is looking for a match of {players} around {rating}");
+        searching(self.matchmaker.status(&user, now).unwrap())
+    }
+
+    fn cancel_match(&mut self, user: UserId) -> ServerPacket {
+        if !self.matchmaker.cancel(&user) {
+            return failed(RoomRequestError::NotQueued);
+        }
+        ServerPacket::MatchmakingStatus {
+            state: MatchmakingState::Cancelled,
+            waited_seconds: 0,
+            tolerance: 0,
+            queued: 0,
+        }
+    }
+
+    /// Puts every match found in a room of its own, and tells whoever is still
+    /// searching how it is going. A match there is no room for is called off.
+    fn matchmake(&mut self, users: &Users) {
+        let now = Instant::now();
+        for players in self.matchmaker.find_matches(now) {
+            let Some(room) = self.open_room(RoomVisibility::Private, String::new()) else {
+                for user in players {
+                    send(users, &user, failed(RoomRequestError::NoRoomLeft));
+                }
+                continue;
+            };
+            let names: Vec<String> = players.iter().map(UserId::to_string).collect();
+            println!("matched {} in room {room}", names.join(", "));
+            for user in players {
+                let joined = self.enter(user, room);
+                send(users, &user, joined);
+            }
+        }
+        for (user, status) in self.matchmaker.statuses_due(now) {
+            send(users, &user, searching(status));
+        }
+    }
+
+    /// Also takes the user out of the matchmaking queue.
+    fn enter(&mut self, user: UserId, id: RoomId) -> ServerPacket {
+        self.matchmaker.cancel(&user);
+        let (inbox, receiver) = mpsc::channel();
+        let _ = inbox.send(self.greetings[&user].clone());
+        self.user_to_inbox.insert(user, inbox);
+        self.user_to_read_receiver.insert(user, receiver);
+        self.user_to_room.insert(user, id);
+        let room = self.rooms.get_mut(&id).unwrap();
+        room.game.join(user);
+        println!("{user} entered room {}", room.code);
+        ServerPacket::RoomJoined {
+            code: room.code.clone(),
+        }
+    }
+
+    /// To the game of the room the user was in, they are gone.
+    fn exit(&mut self, user: UserId) {
+        self.user_to_inbox.remove(&user);
+        self.user_to_read_receiver.remove(&user);
+        if let Some(room) = self
+            .user_to_room
+            .remove(&user)
+            .and_then(|id| self.rooms.get_mut(&id))
+        {
+            room.game.leave(&user);
+            println!("{user} left room {}", room.code);
+        }
+    }
+
+    /// Puts users that cannot pick a room in the lobby, once it is open, and
+    /// in a new one whenever it is full. If there is no room for a new lobby
+    /// they are rejected, as they cannot be asked to go elsewhere.
+    fn quick_play(&mut self, users: &Users) {
+        let rejection = match self.rooms[&self.lobby].game.admission() {
+            Admission::Open => None,
+            // Whoever connects now waits to see whether the lobby starts.
+            Admission::Paused => return,
+            // There was no room for a new lobby in `io_updates`.
+            Admission::Closed => Some(RejectionReason::GameInProgress),
+            Admission::Locked => Some(RejectionReason::LobbyFull),
+        };
+        // They would not know what `RoomJoined` is.
+        for user in std::mem::take(&mut self.quick_play) {
+            let full = self.rooms[&self.lobby].game.users().len() >= self.room_size;
+            let rejection = match rejection {
+                None if full && !self.open_lobby() => Some(RejectionReason::LobbyFull),
+                rejection => rejection,
+            };
+            if let Some(reason) = rejection {
+                println!("{user} cannot be put in a lobby: {reason:?}");
+                send(
+                    users,
+                    &user,
+                    ServerPacket::ConnectionRejected {
+                        reason,
+                        min_version: *SUPPORTED_PROTOCOL_VERSIONS.start(),
+                        max_version: *SUPPORTED_PROTOCOL_VERSIONS.end(),
+                    },
+                );
+                continue;
+            }
+            self.enter(user, self.lobby);
+        }
+    }
+
+    fn forget_gone_users(&mut self, users: &Users) {
+        let gone: Vec<UserId> = self
+            .greetings
+            .keys()
+            .filter(|user| !users.users.contains(user) && !users.is_away(user))
+            .copied()
+            .collect();
+        for user in gone {
+            self.exit(user);
+            self.greetings.remove(&user);
+            self.quick_play.remove(&user);
+        }
+        // Nobody is matched while they are away.
+        let disconnected: Vec<UserId> = self
+            .matchmaker
+            .queued()
+            .filter(|user| !users.users.contains(user))
+            .collect();
+        for user in disconnected {
+            self.matchmaker.cancel(&user);
+        }
+    }
+
+    fn close_empty_rooms(&mut self) {
+        let lobby = self.lobby;
+        let codes = &mut self.codes;
+        self.rooms.retain(|id, room| {
+            let keep = *id == lobby || !room.game.users().is_empty();
+            if !keep {
+                println!("room {id} ({}) is empty, closing it", room.code);
+                codes.remove(&room.code);
+            }
+            keep
+        });
+    }
+
+    fn heartbeats(&self, users: &mut Users) {
+        let now = Instant::now();
+        let Users {
+            user_to_write_sender,
+            user_to_connection,
+            ..
+        } = users;
+        for (user, connection) in user_to_connection.iter_mut() {
+            // Older clients do not answer, the network thread times them out instead.
+            if connection.protocol_version() < HEARTBEAT_PROTOCOL_VERSION {
+                continue;
+            }
+            match connection.heartbeat.poll(now, &self.heartbeat_config) {
+                HeartbeatAction::Wait => {}
+                HeartbeatAction::Ping(nonce) => {
+                    if let Some(sender) = user_to_write_sender.get(user) {
+                        sender.send(ServerPacket::Ping { nonce });
+                    }
+                }
+                HeartbeatAction::Expired => {
+                    println!(
+                        "{user} missed {} heartbeats (last rtt {:?}), disconnecting",
+                        connection.heartbeat.missed(),
+                        connection.heartbeat.rtt()
+                    );
+                    connection.disconnect();
+                }
+            }
+        }
+    }
+}
+
+fn send(users: &Users, user: &UserId, packet: ServerPacket) {
+    if let Some(sender) = users.user_to_write_sender.get(user) {
+        sender.send(packet);
+    }
+}
+
+fn searching(status: QueueStatus) -> ServerPacket {
+    ServerPacket::MatchmakingStatus {
+        state: MatchmakingState::Searching,
+        waited_seconds: u16::try_from(status.waited.as_secs()).unwrap_or(u16::MAX),
+        tolerance: status.tolerance,
+        queued: u16::try_from(status.queued).unwrap_or(u16::MAX),
+    }
+}
+
+fn failed(reason: RoomRequestError) -> ServerPacket {
+    ServerPacket::RoomRequestFailed { reason }
+}
+
+fn new_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+fn report_queues(users: &Users) {
+    let mut behind: Vec<_> = users
+        .outbound_stats()
+        .into_iter()
+        .filter(|(_, stats)| stats.depth > 0)
+        .collect();
+    if behind.is_empty() {
+        return;
+    }
+    behind.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.depth));
+    println!("{} users are behind in reading:", behind.len());
+    for (user, stats) in behind {
+        println!(
+            "  {user}: {} queued (at most {}), {} dropped, {} merged",
+            stats.depth, stats.max_depth, stats.dropped, stats.coalesced
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{SocketAddr, TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        message::{FrameLimits, Message, HANDSHAKE_PROTOCOL_VERSION},
+        network::spawn_network_thread,
+        outbound::{outbound_queue, OutboundReceiver},
+        Codec, Protocol, ProtocolConfig,
+    };
+
+    /// A server with room for `max_rooms` rooms, driven by the returned manager.
+    fn start(max_rooms: usize) -> (SocketAddr, RoomManager) {
+        let config = ServerConfig {
+            max_rooms,
+            ..ServerConfig::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let users = Arc::new(Mutex::new(Users::new()));
+        let protocol = Protocol {
+            config: ProtocolConfig::from(&config),
+            ..Protocol::default()
+        };
+        spawn_network_thread(
+            vec![(listener, Codec::Binary)],
+            users.clone(),
+            Arc::new(protocol),
+        );
+        (address, RoomManager::new(users, &config))
+    }
+
+    /// Connects as a client that predates rooms.
+    fn connect(address: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        send_packet(
+            &mut stream,
+            ClientPacket::ConnectionRequested {
+                protocol_version: HANDSHAKE_PROTOCOL_VERSION,
+                client_build: String::new(),
+                name: String::new(),
+            },
+        );
+        stream
+    }
+
+    fn send_packet(stream: &mut TcpStream, packet: ClientPacket) {
+        let mut frame = Vec::new();
+        packet
+            .encode(HANDSHAKE_PROTOCOL_VERSION)
+            .write_to(&mut frame)
+            .unwrap();
+        stream.write_all(&frame).unwrap();
+    }
+
+    fn next_packet(stream: &mut TcpStream) -> ServerPacket {
+        let message = Message::next_message(stream, &FrameLimits::default()).unwrap();
+        ServerPacket::decode(message, HANDSHAKE_PROTOCOL_VERSION).unwrap()
+    }
+
+    /// Runs the rooms until `client` is done.
+    fn run<T>(rooms: &mut RoomManager, client: thread::JoinHandle<T>) -> T {
+        let tick = Duration::from_millis(10);
+        while !client.is_finished() {
+            rooms.elapsed(tick);
+            rooms.io_updates();
+            thread::sleep(tick);
+        }
+        client.join().unwrap()
+    }
+
+    /// A client that gets the lobby's game started on its own.
+    fn start_game(address: SocketAddr) -> thread::JoinHandle<TcpStream> {
+        thread::spawn(move || {
+            let mut stream = connect(address);
+            while !matches!(
+                next_packet(&mut stream),
+                ServerPacket::ConnectionAccepted { .. }
+            ) {}
+            send_packet(
+                &mut stream,
+                ClientPacket::ReadyToStartChanged { ready: true },
+            );
+            while !matches!(
+                next_packet(&mut stream),
+                ServerPacket::GameAboutToStart { .. }
+            ) {}
+            stream
+        })
+    }
+
+    #[test]
+    fn older_clients_are_rejected_while_the_game_runs_and_no_lobby_can_be_opened() {
+        let (address, mut rooms) = start(1);
+        let _playing = run(&mut rooms, start_game(address));
+
+        let late = thread::spawn(move || next_packet(&mut connect(address)));
+        assert!(matches!(
+            run(&mut rooms, late),
+            ServerPacket::ConnectionRejected {
+                reason: RejectionReason::GameInProgress,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn older_clients_get_a_new_lobby_while_the_game_runs() {
+        let (address, mut rooms) = start(2);
+        let _playing = run(&mut rooms, start_game(address));
+
+        let late = thread::spawn(move || next_packet(&mut connect(address)));
+        assert!(matches!(
+            run(&mut rooms, late),
+            ServerPacket::ConnectionAccepted { .. }
+        ));
+    }
+
+    /// Rooms for users the tests speak for themselves, without sockets.
+    fn in_memory(config: ServerConfig) -> (Arc<Mutex<Users>>, RoomManager) {
+        let users = Arc::new(Mutex::new(Users::new()));
+        let rooms = RoomManager::new(users.clone(), &config);
+        (users, rooms)
+    }
+
+    struct Client {
+        packets: mpsc::Sender<ClientPacket>,
+        outbound: OutboundReceiver,
+    }
+
+    /// Connects a user as a client that picks its own room.
+    fn client(users: &Mutex<Users>, id: i32) -> Client {
+        let user = UserId::new(id);
+        let (packets, receiver) = mpsc::channel();
+        let (sender, outbound) = outbound_queue(64);
+        let mut users = users.lock().unwrap();
+        users.users.insert(user);
+        users.user_to_write_sender.insert(user, sender);
+        users.user_to_read_receiver.insert(user, receiver);
+        let client = Client { packets, outbound };
+        client.send(ClientPacket::ConnectionRequested {
+            protocol_version: *SUPPORTED_PROTOCOL_VERSIONS.end(),
+            client_build: String::new(),
+            name: String::new(),
+        });
+        client
+    }
+
+    impl Client {
+        fn send(&self, packet: ClientPacket) {
+            self.packets.send(packet).unwrap();
+        }
+
+        /// The answers to room requests sent since the last call, leaving out
+        /// what the game of the room sent.
+        fn replies(&self) -> Vec<ServerPacket> {
+            std::iter::from_fn(|| self.outbound.try_recv())
+                .filter(|packet| {
+                    matches!(
+                        packet,
+                        ServerPacket::RoomList { .. }
+                            | ServerPacket::RoomJoined { .. }
+                            | ServerPacket::RoomLeft
+                            | ServerPacket::RoomRequestFailed { .. }
+                    )
+                })
+                .collect()
+        }
+
+        /// Sends `request` and returns the single answer to it.
+        fn request(&self, rooms: &mut RoomManager, request: ClientPacket) -> ServerPacket {
+            self.send(request);
+            tick(rooms);
+            let mut replies = self.replies();
+            assert_eq!(replies.len(), 1, "{replies:?}");
+            replies.remove(0)
+        }
+
+        fn create(&self, rooms: &mut RoomManager, visibility: RoomVisibility) -> String {
+            self.create_with_password(rooms, visibility, "")
+        }
+
+        fn create_with_password(
+            &self,
+            rooms: &mut RoomManager,
+            visibility: RoomVisibility,
+            password: &str,
+        ) -> String {
+            let created = ClientPacket::CreateRoom {
+                visibility,
+                password: password.to_string(),
+            };
+            match self.request(rooms, created) {
+                ServerPacket::RoomJoined { code } => code,
+                reply => panic!("the room was not created: {reply:?}"),
+            }
+        }
+
+        fn join(&self, rooms: &mut RoomManager, code: &str, password: &str) -> ServerPacket {
+            let join = ClientPacket::JoinRoom {
+                code: code.to_string(),
+                password: password.to_string(),
+            };
+            self.request(rooms, join)
+        }
+    }
+
+    fn tick(rooms: &mut RoomManager) {
+        rooms.elapsed(Duration::from_millis(10));
+        rooms.io_updates();
+    }
+
+    #[test]
+    fn private_rooms_are_not_listed() {
+        let (users, mut rooms) = in_memory(ServerConfig::default());
+        let (public, private, looking) = (client(&users, 1), client(&users, 2), client(&users, 3));
+        let public = public.create(&mut rooms, RoomVisibility::Public);
+        private.create(&mut rooms, RoomVisibility::Private);
+
+        let ServerPacket::RoomList { rooms: listed } =
+            looking.request(&mut rooms, ClientPacket::ListRooms)
+        else {
+            panic!("no room list");
+        };
+        let lobby = rooms.rooms[&rooms.lobby].code.clone();
+        let codes: HashSet<String> = listed.iter().map(|room| room.code.clone()).collect();
+        assert_eq!(codes, HashSet::from([lobby, public.clone()]));
+        let listed = listed.iter().find(|room| room.code == public).unwrap();
+        assert_eq!(listed.players, 1);
+        assert_eq!(listed.state, RoomState::Open);
+        assert!(!listed.has_password);
+    }
+
+    #[test]
+    fn a_room_is_only_joined_with_its_code_and_password_while_there_is_space() {
+        let config = ServerConfig {
+            room_size: 2,
+            ..ServerConfig::default()
+        };
+        let (users, mut rooms) = in_memory(config);
+        let (host, guest, late) = (client(&users, 1), client(&users, 2), client(&users, 3));
+        let code = host.create_with_password(&mut rooms, RoomVisibility::Private, "secret");
+
+        assert_eq!(
+            guest.join(&mut rooms, "nope", "secret"),
+            failed(RoomRequestError::UnknownRoom)
+        );
+        assert_eq!(
+            guest.join(&mut rooms, &code, "wrong"),
+            failed(RoomRequestError::WrongPassword)
+        );
+        // Codes are not case sensitive.
+        assert_eq!(
+            guest.join(&mut rooms, &code.to_lowercase(), "secret"),
+            ServerPacket::RoomJoined { code: code.clone() }
+        );
+        assert_eq!(
+            late.join(&mut rooms, &code, "secret"),
+            failed(RoomRequestError::RoomFull)
+        );
+    }
+
+    #[test]
+    fn a_locked_room_is_not_joined() {
+        let (users, mut rooms) = in_memory(ServerConfig::default());
+        let (host, guest) = (client(&users, 1), client(&users, 2));
+        let code = host.create(&mut rooms, RoomVisibility::Public);
+        host.send(ClientPacket::LockLobby { locked: true });
+        tick(&mut rooms);
+
+        assert_eq!(
+            guest.join(&mut rooms, &code, ""),
+            failed(RoomRequestError::RoomLocked)
+        );
+    }
+
+    #[test]
+    fn a_room_whose_game_started_is_not_joined() {
+        let (users, mut rooms) = in_memory(ServerConfig::default());
+        let (host, guest) = (client(&users, 1), client(&users, 2));
+        let code = host.create(&mut rooms, RoomVisibility::Public);
+        host.send(ClientPacket::ReadyToStartChanged { ready: true });
+        let id = rooms.codes[&code];
+        for _ in 0..5 {
+            tick(&mut rooms);
+        }
+        assert_eq!(rooms.rooms[&id].game.admission(), Admission::Closed);
+
+        assert_eq!(
+            guest.join(&mut rooms, &code, ""),
+            failed(RoomRequestError::GameInProgress)
+        );
+    }
+
+    #[test]
+    fn a_room_is_closed_once_its_last_user_leaves() {
+        let (users, mut rooms) = in_memory(ServerConfig::default());
+        let (host, guest) = (client(&users, 1), client(&users, 2));
+        assert_eq!(
+            host.request(&mut rooms, ClientPacket::LeaveRoom),
+            failed(RoomRequestError::NotInRoom)
+        );
+
+        let code = host.create(&mut rooms, RoomVisibility::Public);
+        assert_eq!(
+            host.request(&mut rooms, ClientPacket::LeaveRoom),
+            ServerPacket::RoomLeft
+        );
+        assert!(!rooms.user_to_room.contains_key(&UserId::new(1)));
+        assert!(!rooms.codes.contains_key(&code));
+        assert_eq!(
+            guest.join(&mut rooms, &code, ""),
+            failed(RoomRequestError::UnknownRoom)
+        );
+        // The lobby stays open for whoever comes next.
+        assert!(rooms.rooms.contains_key(&rooms.lobby));
+        assert!(rooms.rooms[&rooms.lobby].game.users().is_empty());
+    }
+
+    #[test]
+    fn no_room_is_opened_past_max_rooms() {
+        // The lobby and one more.
+        let config = ServerConfig {
+            max_rooms: 2,
+            ..ServerConfig::default()
+        };
+        let (users, mut rooms) = in_memory(config);
+        let (first, second) = (client(&users, 1), client(&users, 2));
+        first.create(&mut rooms, RoomVisibility::Public);
+
+        let create = ClientPacket::CreateRoom {
+            visibility: RoomVisibility::Public,
+            password: String::new(),
+        };
+        assert_eq!(
+            second.request(&mut rooms, create),
+            failed(RoomRequestError::NoRoomLeft)
+        );
+        assert_eq!(rooms.rooms.len(), 2);
+    }
+}
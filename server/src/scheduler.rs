@@ -0,0 +1,234 @@
+//! Drives the games of a [`RoomManager`] at a fixed simulation rate and a
+//! separate, usually lower, network send rate. Between deadlines the game thread sleeps; sockets
+//! are waited on by the network thread.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::room::RoomManager;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    /// Simulation ticks per second. Every tick advances the game by exactly
+    /// `1 / tick_rate`.
+    pub tick_rate: u32,
+    /// How many times per second packets are exchanged with the users.
+    pub send_rate: u32,
+    /// Ticks run back to back to catch up after a stall before the rest are
+    /// dropped.
+    pub max_catch_up: u32,
+    /// How often overruns are reported, at most.
+    #[serde(deserialize_with = "crate::config::seconds")]
+    pub report_every: Duration,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            tick_rate: 60,
+            send_rate: 30,
+            max_catch_up: 5,
+            report_every: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Counts deadlines that were missed since the last report.
+#[derive(Debug, Default)]
+struct Overruns {
+    late_ticks: u64,
+    dropped_ticks: u64,
+    late_sends: u64,
+    worst: Duration,
+}
+
+impl Overruns {
+    fn is_empty(&self) -> bool {
+        self.late_ticks == 0 && self.late_sends == 0
+    }
+
+    fn late(&mut self, by: Duration) {
+        self.worst = self.worst.max(by);
+    }
+}
+
+pub struct Scheduler {
+    config: SchedulerConfig,
+    tick: Duration,
+    send_interval: Duration,
+    next_tick: Instant,
+    next_send: Instant,
+    overruns: Overruns,
+    last_report: Instant,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        let now = Instant::now();
+        Scheduler {
+            tick: Duration::from_secs(1) / config.tick_rate.max(1),
+            send_interval: Duration::from_secs(1) / config.send_rate.max(1),
+            config,
+            next_tick: now,
+            next_send: now,
+            overruns: Overruns::default(),
+            last_report: now,
+        }
+    }
+
+    /// Runs the game until `stop` is set, checked at least once a tick.
+    pub fn run(mut self, rooms: &mut RoomManager, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            self.run_once(rooms);
+            let deadline = self.next_tick.min(self.next_send);
+            if let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+    }
+
+    /// Runs every tick and send whose deadline has passed.
+    fn run_once(&mut self, rooms: &mut RoomManager) {
+        let now = Instant::now();
+        for _ in 0..self.due_ticks(now) {
+            rooms.elapsed(self.tick);
+        }
+        if self.send_due(now) {
+            rooms.io_updates();
+        }
+        self.report(now);
+    }
+
+    /// How many ticks to run back to back at `now`, at most `max_catch_up`.
+    fn due_ticks(&mut self, now: Instant) -> u32 {
+        if now < self.next_tick {
+            return 0;
+        }
+        let behind = now - self.next_tick;
+        if behind >= self.tick {
+            self.overruns.late_ticks += 1;
+            self.overruns.late(behind);
+        }
+        let mut steps = 0;
+        while self.next_tick <= now && steps < self.config.max_catch_up {
+            self.next_tick += self.tick;
+            steps += 1;
+        }
+        // Too far behind to catch up: the skipped time is not simulated.
+        if self.next_tick <= now {
+            let dropped = ((now - self.next_tick).as_nanos() / self.tick.as_nanos()) as u32 + 1;
+            self.next_tick += self.tick * dropped;
+            self.overruns.dropped_ticks += dropped as u64;
+        }
+        steps
+    }
+
+    /// Whether packets are to be exchanged at `now`. A send that is more than
+    /// an interval late is not made up for.
+    fn send_due(&mut self, now: Instant) -> bool {
+        if now < self.next_send {
+            return false;
+        }
+        let behind = now - self.next_send;
+        self.next_send += self.send_interval;
+        if self.next_send <= now {
+            self.overruns.late_sends += 1;
+            self.overruns.late(behind);
+            self.next_send = now + self.send_interval;
+        }
+        true
+    }
+
+    fn report(&mut self, now: Instant) {
+        if now.duration_since(self.last_report) < self.config.report_every {
+            return;
+        }
+        if !self.overruns.is_empty() {
+            let overruns = std::mem::take(&mut self.overruns);
+            println!(
+                "tick overrun in the last {:?}: {} late ticks, {} dropped, {} late sends, worst {:?} behind",
+                now.duration_since(self.last_report),
+                overruns.late_ticks,
+                overruns.dropped_ticks,
+                overruns.late_sends,
+                overruns.worst
+            );
+        }
+        self.last_report = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// At the tick rate of `scheduler`.
+    const TICK: Duration = Duration::from_millis(20);
+
+    fn scheduler(max_catch_up: u32) -> (Scheduler, Instant) {
+        let scheduler = Scheduler::new(SchedulerConfig {
+            tick_rate: 50,
+            send_rate: 10,
+            max_catch_up,
+            ..SchedulerConfig::default()
+        });
+        let start = scheduler.next_tick;
+        (scheduler, start)
+    }
+
+    #[test]
+    fn ticks_are_run_at_a_fixed_rate() {
+        let (mut scheduler, start) = scheduler(5);
+        assert_eq!(scheduler.due_ticks(start), 1);
+        assert_eq!(scheduler.due_ticks(start + TICK / 2), 0);
+        assert_eq!(scheduler.due_ticks(start + TICK), 1);
+        // A little late is not an overrun, the next tick is still on time.
+        assert_eq!(scheduler.due_ticks(start + TICK * 2 + TICK / 2), 1);
+        assert_eq!(scheduler.due_ticks(start + TICK * 3), 1);
+        assert!(scheduler.overruns.is_empty());
+    }
+
+    #[test]
+    fn missed_ticks_are_caught_up_back_to_back() {
+        let (mut scheduler, start) = scheduler(5);
+        assert_eq!(scheduler.due_ticks(start + TICK * 3), 4);
+        assert_eq!(scheduler.due_ticks(start + TICK * 3), 0);
+        assert_eq!(scheduler.due_ticks(start + TICK * 4), 1);
+        assert_eq!(scheduler.overruns.late_ticks, 1);
+        assert_eq!(scheduler.overruns.worst, TICK * 3);
+        assert_eq!(scheduler.overruns.dropped_ticks, 0);
+    }
+
+    #[test]
+    fn ticks_past_max_catch_up_are_dropped() {
+        let (mut scheduler, start) = scheduler(5);
+        assert_eq!(scheduler.due_ticks(start + TICK * 20), 5);
+        // Ticks 5 to 20 are not simulated, 21 is the next one.
+        assert_eq!(scheduler.overruns.dropped_ticks, 16);
+        assert_eq!(scheduler.due_ticks(start + TICK * 20), 0);
+        assert_eq!(scheduler.due_ticks(start + TICK * 21), 1);
+    }
+
+    #[test]
+    fn a_late_send_is_not_made_up_for() {
+        let (mut scheduler, start) = scheduler(5);
+        let interval = Duration::from_millis(100);
+        assert!(scheduler.send_due(start));
+        assert!(!scheduler.send_due(start + interval / 2));
+        assert!(scheduler.send_due(start + interval));
+        assert!(scheduler.overruns.is_empty());
+
+        let stalled = start + interval * 5;
+        assert!(scheduler.send_due(stalled));
+        assert!(!scheduler.send_due(stalled + interval / 2));
+        assert!(scheduler.send_due(stalled + interval));
+        assert_eq!(scheduler.overruns.late_sends, 1);
+        assert_eq!(scheduler.overruns.worst, interval * 3);
+    }
+}
@@ -0,0 +1,89 @@
+//! Lets a user whose connection dropped come back as the same user.
+//!
+//! Every accepted user is given an opaque token in `ConnectionAccepted`. A new
+//! connection that sends it in `Reconnect` within the grace window takes over
+//! the user's id, and the game sees the user come back instead of a stranger.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::user_id::UserId;
+
+pub struct Sessions {
+    grace: Duration,
+    token_to_user: HashMap<String, UserId>,
+    user_to_token: HashMap<UserId, String>,
+    /// Users whose connection is gone, and since when.
+    away: HashMap<UserId, Instant>,
+}
+
+impl Sessions {
+    pub fn new(grace: Duration) -> Self {
+        Sessions {
+            grace,
+            token_to_user: HashMap::new(),
+            user_to_token: HashMap::new(),
+            away: HashMap::new(),
+        }
+    }
+
+    /// The user's token, issued the first time it is asked for.
+    pub fn issue(&mut self, user: UserId) -> String {
+        if let Some(token) = self.user_to_token.get(&user) {
+            return token.clone();
+        }
+        let token = format!("{:032x}", rand::random::<u128>());
+        self.token_to_user.insert(token.clone(), user);
+        self.user_to_token.insert(user, token.clone());
+        token
+    }
+
+    pub fn user(&self, token: &str) -> Option<UserId> {
+        self.token_to_user.get(token).copied()
+    }
+
+    /// The user is connected again.
+    pub fn back(&mut self, user: UserId) {
+        self.away.remove(&user);
+    }
+
+    /// The user may not come back, e.g. because they were kicked.
+    pub fn revoke(&mut self, user: UserId) {
+        if let Some(token) = self.user_to_token.remove(&user) {
+            self.token_to_user.remove(&token);
+        }
+    }
+
+    /// Returns whether the user holds a session they can come back to.
+    pub fn leave(&mut self, user: UserId, now: Instant) -> bool {
+        if !self.user_to_token.contains_key(&user) {
+            return false;
+        }
+        self.away.insert(user, now);
+        true
+    }
+
+    /// Forgets the users that have been away for longer than the grace
+    /// window and returns them.
+    pub fn expire(&mut self, now: Instant) -> Vec<UserId> {
+        let expired: Vec<UserId> = self
+            .away
+            .iter()
+            .filter(|(_, since)| now.duration_since(**since) > self.grace)
+            .map(|(user, _)| *user)
+            .collect();
+        for user in expired.iter() {
+            self.away.remove(user);
+            if let Some(token) = self.user_to_token.remove(user) {
+                self.token_to_user.remove(&token);
+            }
+        }
+        expired
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+}
@@ -0,0 +1,102 @@
+//! Users are told apart by a [`UserId`] for as long as the server runs.
+//!
+//! Ids are handed out in order and never reused, so a message about a user who
+//! left can never be mistaken for one about whoever came next. They are not
+//! secret: every user sees the ids of everybody in the lobby, and taking a
+//! user over takes their session token, see [`crate::session`].
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::message::{wire::Wire, DecodeError, PayloadReader, PayloadWriter};
+
+/// Goes over the wire as an `i32`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UserId(i32);
+
+impl UserId {
+    /// The author of chat lines written by the server itself, never a user's id.
+    pub const SERVER: UserId = UserId(0);
+
+    pub const fn new(id: i32) -> UserId {
+        UserId(id)
+    }
+
+    pub fn get(self) -> i32 {
+        self.0
+    }
+}
+
+impl Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Wire for UserId {
+    fn write_to<W: PayloadWriter>(&self, writer: &mut W) {
+        self.0.write_to(writer);
+    }
+
+    fn read_from<R: PayloadReader>(reader: &mut R) -> Result<Self, DecodeError> {
+        i32::read_from(reader).map(UserId)
+    }
+}
+
+/// Hands out every id from the first one up, once. Ids below 1 are never handed
+/// out, so that nobody is [`UserId::SERVER`].
+#[derive(Debug)]
+pub struct UserIdAllocator {
+    /// `None` once every id has been handed out.
+    next: Option<i32>,
+}
+
+impl UserIdAllocator {
+    pub fn starting_at(first: UserId) -> Self {
+        UserIdAllocator {
+            next: Some(first.0.max(1)),
+        }
+    }
+
+    pub fn allocate(&mut self) -> Option<UserId> {
+        let id = self.next?;
+        self.next = id.checked_add(1);
+        Some(UserId(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocate(allocator: &mut UserIdAllocator, count: usize) -> Vec<Option<i32>> {
+        (0..count)
+            .map(|_| allocator.allocate().map(UserId::get))
+            .collect()
+    }
+
+    #[test]
+    fn ids_are_handed_out_in_order() {
+        let mut allocator = UserIdAllocator::starting_at(UserId::new(5));
+        assert_eq!(allocate(&mut allocator, 3), [Some(5), Some(6), Some(7)]);
+    }
+
+    #[test]
+    fn ids_are_never_handed_out_twice() {
+        let mut allocator = UserIdAllocator::starting_at(UserId::new(i32::MAX - 1));
+        assert_eq!(
+            allocate(&mut allocator, 4),
+            [Some(i32::MAX - 1), Some(i32::MAX), None, None]
+        );
+    }
+
+    #[test]
+    fn the_server_id_is_never_handed_out() {
+        for first in [UserId::SERVER, UserId::new(-3)] {
+            let mut allocator = UserIdAllocator::starting_at(first);
+            assert_eq!(allocator.allocate(), Some(UserId::new(1)));
+        }
+    }
+}